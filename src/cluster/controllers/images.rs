@@ -5,6 +5,9 @@ use std::sync::Arc;
 use tokio::time::Duration;
 use tracing::{info, instrument};
 
+use crate::cluster::controllers::protection::{
+    PROTECTION_FINALIZER, PROTECTION_RECHECK, image_users, release_protection,
+};
 use crate::crd::ceph::Image;
 use crate::errors::Error;
 use crate::shared::ceph::lowlevel;
@@ -72,6 +75,9 @@ async fn update_fn(image: Arc<Image>, ctx: Arc<DefaultState>) -> Result<Action,
     image
         .ensure_finalizer("ceph", ctx.client.clone(), &FIELD_MANAGER)
         .await?;
+    image
+        .ensure_finalizer(PROTECTION_FINALIZER, ctx.client.clone(), &FIELD_MANAGER)
+        .await?;
     ceph_ensure_image_exists(&name, &source)?;
     info!("ceph: Image {name} update success");
     Ok(Action::requeue(Duration::from_secs(600)))
@@ -84,6 +90,11 @@ async fn remove_fn(image: Arc<Image>, ctx: Arc<DefaultState>) -> Result<Action,
 
     let name = image.name_prefixed_with_namespace();
 
+    let users = image_users(&image, ctx.client.clone()).await?;
+    if !release_protection(&mut image, users, ctx.client.clone(), &FIELD_MANAGER).await? {
+        return Ok(Action::requeue(Duration::from_secs(PROTECTION_RECHECK)));
+    }
+
     info!("ceph: Image {name} waiting for deletion");
     ceph_ensure_image_removed(&name)?;
 
@@ -1,53 +1,70 @@
 use crate::crd;
 use crate::errors::Error;
-use futures::try_join;
+use crate::utils::supervisor::{Supervisor, SupervisorHealth};
 use kube::Client;
 use log::info;
 use ovn_services::{ovn_central, ovn_controller};
+use virtualmachine::cache::SchedulingCache;
 
 mod images;
 mod network;
 pub mod node;
 mod ovn_services;
+mod protection;
 mod router;
 mod virtualmachine;
+mod virtualmachinepool;
 mod volumes;
 
-pub async fn run(client: Client) -> Result<(), Error> {
+pub use virtualmachine::explain::explain_schedule;
+
+pub async fn run(client: Client, health: SupervisorHealth) -> Result<(), Error> {
     info!("Creating CRDs");
     crd::libvirtnode::create(client.clone()).await?;
     crd::virtualmachine::create(client.clone()).await?;
+    crd::instancetype::create(client.clone()).await?;
+    crd::virtualmachinetemplate::create(client.clone()).await?;
+    crd::virtualmachinepool::create(client.clone()).await?;
     crd::ceph::create(client.clone()).await?;
     crd::network::create(client.clone()).await?;
     crd::router::create(client.clone()).await?;
 
     info!("Creating tasks");
-    let volumes_task = tokio::spawn(volumes::create(client.clone()));
-    let images_task = tokio::spawn(images::create(client.clone()));
-    let ovn_controller_task = tokio::spawn(ovn_controller::create(client.clone()));
-    let ovn_central_task = tokio::spawn(ovn_central::create(client.clone()));
-
-    let network_task = tokio::spawn(network::create(client.clone()));
-    let router_task = tokio::spawn(router::create(client.clone()));
+    let (scheduling_cache, scheduling_cache_watches) = SchedulingCache::new(client.clone());
 
-    let vm_task1 = tokio::spawn(virtualmachine::ovn::create(client.clone()));
-    let vm_task2 = tokio::spawn(virtualmachine::vm::create(client.clone()));
+    let supervisor = Supervisor::default()
+        .with_health(health)
+        .with_task("volumes", with_client(&client, volumes::create))
+        .with_task("images", with_client(&client, images::create))
+        .with_task(
+            "ovn-controller",
+            with_client(&client, ovn_controller::create),
+        )
+        .with_task("ovn-central", with_client(&client, ovn_central::create))
+        .with_task("network", with_client(&client, network::create))
+        .with_task("router", with_client(&client, router::create))
+        // The stores of the cache are filled by this one future
+        .with_task_once("scheduling-cache", scheduling_cache_watches)
+        .with_task("vm-ovn", with_client(&client, virtualmachine::ovn::create))
+        .with_task("vm", {
+            let (client, cache) = (client.clone(), scheduling_cache.clone());
+            move || virtualmachine::vm::create(client.clone(), cache.clone())
+        })
+        .with_task("pool", with_client(&client, virtualmachinepool::create))
+        .with_task("rebalancer", {
+            let (client, cache) = (client.clone(), scheduling_cache);
+            move || virtualmachine::rebalancer::run(client.clone(), cache.clone())
+        })
+        .with_task("node", with_client(&client, node::create));
 
-    let node_task = tokio::spawn(node::create(client.clone()));
-
-    try_join!(
-        async { volumes_task.await.unwrap() },
-        async { images_task.await.unwrap() },
-        async { ovn_controller_task.await.unwrap() },
-        async { ovn_central_task.await.unwrap() },
-        async { network_task.await.unwrap() },
-        async { router_task.await.unwrap() },
-        async { vm_task1.await.unwrap() },
-        async { vm_task2.await.unwrap() },
-        async { node_task.await.unwrap() },
-    )?;
+    Err(supervisor.run().await)
+}
 
-    Err(Error::UnexpectedExit(
-        "Cluster controllers should not exit".into(),
-    ))
+/// Task factory calling a controller with its own client
+fn with_client<F, Fut>(client: &Client, controller: F) -> impl Fn() -> Fut + Send + Sync + 'static
+where
+    F: Fn(Client) -> Fut + Send + Sync + 'static,
+{
+    let client = client.clone();
+    move || controller(client.clone())
 }
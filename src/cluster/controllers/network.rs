@@ -9,6 +9,9 @@ use std::sync::Arc;
 use tokio::time::Duration;
 use tracing::{info, instrument};
 
+use crate::cluster::controllers::protection::{
+    PROTECTION_FINALIZER, PROTECTION_RECHECK, network_users, release_protection,
+};
 use crate::crd::network::{
     DhcpOptions as DhcpOptionsCrd, Network, NetworkStatus, NetworkType, RouterAttachment,
 };
@@ -58,17 +61,7 @@ fn ensure_router_attachment(
     ovn: Arc<Ovn>,
 ) -> Result<(), Error> {
     let network_ns = ResourceExt::namespace(network).expect("Get network ns");
-
-    let split: Vec<String> = router_attachment
-        .name
-        .split('/')
-        .map(String::from)
-        .collect();
-    let (namespace, name) = match split.len() {
-        1 => (&network_ns, split.first().unwrap()),
-        2 => (split.first().unwrap(), split.get(1).unwrap()),
-        _ => panic!("Malformed router name (todo: error)"),
-    };
+    let (namespace, name) = router_attachment.router(&network_ns)?;
 
     let lr_name = format!("{}-{}", &namespace, &name);
     let mut lr = LogicalRouter::get_by_name(ovn.clone(), &lr_name)?;
@@ -85,6 +78,9 @@ fn ensure_router_attachment(
 #[instrument(skip(ctx))]
 async fn update_network(network: Arc<Network>, ctx: Arc<DefaultState>) -> Result<Action, Error> {
     let mut network = (*network).clone();
+    network
+        .ensure_finalizer(PROTECTION_FINALIZER, ctx.client.clone(), &FIELD_MANAGER)
+        .await?;
 
     // We are only interested in OVN networks. Ignore other types
     if network.spec.network_type.clone().unwrap_or_default() != NetworkType::Ovn {
@@ -123,6 +119,10 @@ async fn update_network(network: Arc<Network>, ctx: Arc<DefaultState>) -> Result
 #[instrument(skip(ctx))]
 async fn remove_network(network: Arc<Network>, ctx: Arc<DefaultState>) -> Result<Action, Error> {
     let mut network = (*network).clone();
+    let users = network_users(&network, ctx.client.clone()).await?;
+    if !release_protection(&mut network, users, ctx.client.clone(), &FIELD_MANAGER).await? {
+        return ok_and_requeue!(PROTECTION_RECHECK);
+    }
 
     // We are only interested in OVN networks. Ignore other types
     if network.spec.network_type.clone().unwrap_or_default() != NetworkType::Ovn {
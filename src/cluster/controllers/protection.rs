@@ -0,0 +1,126 @@
+use kube::{Api, Client, ResourceExt};
+use tracing::{info, instrument};
+
+use crate::crd::ceph::{Image, Volume};
+use crate::crd::network::Network;
+use crate::crd::router::Router;
+use crate::crd::virtualmachine::VirtualMachine;
+use crate::crd::virtualmachinepool::VirtualMachinePool;
+use crate::errors::Error;
+use crate::utils::libvirt_storage::{StorageType, parse_storage_location};
+use crate::utils::traits::kube::{ApiExt, ExtendResource};
+
+/// Finalizer keeping a resource from being deleted while other resources still reference it
+pub const PROTECTION_FINALIZER: &str = "in-use-protection";
+
+/// Seconds to wait before checking again whether a protected resource is still in use
+pub const PROTECTION_RECHECK: u64 = 30;
+
+/// VMs attaching the Volume
+#[instrument(skip(client))]
+pub async fn volume_users(volume: &Volume, client: Client) -> Result<Vec<String>, Error> {
+    let vms: Api<VirtualMachine> = Api::namespaced(client, &volume.namespace_unchecked());
+    let mut users = vec![];
+    for vm in vms.list_default().await? {
+        for attachment in &vm.spec.volumes {
+            let location = parse_storage_location(&attachment.name).ok();
+            if location == Some((StorageType::Ceph, volume.name_any())) {
+                users.push(format!("VirtualMachine {}", vm.name_any()));
+            }
+        }
+    }
+    Ok(users)
+}
+
+/// Volumes cloned from the Image, in any namespace, and pools creating volumes from it
+#[instrument(skip(client))]
+pub async fn image_users(image: &Image, client: Client) -> Result<Vec<String>, Error> {
+    let volumes: Api<Volume> = Api::all(client.clone());
+    let pools: Api<VirtualMachinePool> = Api::namespaced(client, &image.namespace_unchecked());
+    let template = image.name_prefixed_with_namespace();
+
+    let volume_users = volumes
+        .list_default()
+        .await?
+        .into_iter()
+        .filter(|volume| volume.spec.template.as_ref() == Some(&template))
+        .map(|volume| format!("Volume {}", volume.name_prefixed_with_namespace()));
+    let pool_users = pools
+        .list_default()
+        .await?
+        .into_iter()
+        .filter(|pool| {
+            pool.spec
+                .volume_templates
+                .iter()
+                .any(|template| template.image.as_ref() == Some(&image.name_any()))
+        })
+        .map(|pool| format!("VirtualMachinePool {}", pool.name_any()));
+    Ok(volume_users.chain(pool_users).collect())
+}
+
+/// VMs with an interface in the Network
+#[instrument(skip(client))]
+pub async fn network_users(network: &Network, client: Client) -> Result<Vec<String>, Error> {
+    let vms: Api<VirtualMachine> = Api::namespaced(client, &network.namespace_unchecked());
+    Ok(vms
+        .list_default()
+        .await?
+        .into_iter()
+        .filter(|vm| {
+            vm.spec
+                .networks
+                .iter()
+                .any(|nic| nic.name.as_ref() == Some(&network.name_any()))
+        })
+        .map(|vm| format!("VirtualMachine {}", vm.name_any()))
+        .collect())
+}
+
+/// Networks, in any namespace, attached to the Router
+#[instrument(skip(client))]
+pub async fn router_users(router: &Router, client: Client) -> Result<Vec<String>, Error> {
+    let networks: Api<Network> = Api::all(client);
+    let router_namespace = router.namespace_unchecked();
+    let router_name = router.name_any();
+
+    Ok(networks
+        .list_default()
+        .await?
+        .into_iter()
+        .filter(|network| {
+            let network_namespace = network.namespace_unchecked();
+            network.spec.routers.iter().flatten().any(|attachment| {
+                attachment.router(&network_namespace).ok()
+                    == Some((router_namespace.clone(), router_name.clone()))
+            })
+        })
+        .map(|network| format!("Network {}", network.name_prefixed_with_namespace()))
+        .collect())
+}
+
+/// Remove the protection finalizer of a resource being deleted once nothing uses it anymore.
+/// Returns whether the resource is free to be removed.
+#[instrument(skip(resource, client))]
+pub async fn release_protection<T>(
+    resource: &mut T,
+    users: Vec<String>,
+    client: Client,
+    field_manager: &str,
+) -> Result<bool, Error>
+where
+    T: ExtendResource + Send,
+{
+    if !users.is_empty() {
+        info!(
+            "{} is still in use by {}, waiting",
+            resource.name_prefixed_with_namespace(),
+            users.join(", ")
+        );
+        return Ok(false);
+    }
+    resource
+        .remove_finalizer(PROTECTION_FINALIZER, client, field_manager)
+        .await?;
+    Ok(true)
+}
@@ -9,6 +9,9 @@ use std::sync::Arc;
 use tokio::time::Duration;
 use tracing::{info, instrument};
 
+use crate::cluster::controllers::protection::{
+    PROTECTION_FINALIZER, PROTECTION_RECHECK, release_protection, router_users,
+};
 use crate::crd::router::{Router, RouterStatus};
 use crate::errors::Error;
 use crate::interfaces::ovn::types::logicalswitch::LogicalSwitch;
@@ -64,6 +67,9 @@ async fn update_router(router: Arc<Router>, ctx: Arc<DefaultState>) -> Result<Ac
     router
         .ensure_finalizer("ovn", client.clone(), &FIELD_MANAGER)
         .await?;
+    router
+        .ensure_finalizer(PROTECTION_FINALIZER, client.clone(), &FIELD_MANAGER)
+        .await?;
 
     let mut lr = LogicalRouter::create_if_missing(ovn.clone(), &name)?;
     if let Some(routes) = &router.spec.routes {
@@ -95,11 +101,16 @@ async fn update_router(router: Arc<Router>, ctx: Arc<DefaultState>) -> Result<Ac
 /// Handle updates to routers in the cluster
 #[instrument(skip(ctx))]
 async fn remove_router(router: Arc<Router>, ctx: Arc<DefaultState>) -> Result<Action, Error> {
-    let ovn = Arc::new(Ovn::try_from_annotations(ctx.client.clone()).await?);
     let mut router = (*router).clone();
     let client = ctx.client.clone();
     let name = router.name_prefixed_with_namespace();
 
+    let users = router_users(&router, client.clone()).await?;
+    if !release_protection(&mut router, users, client.clone(), &FIELD_MANAGER).await? {
+        return ok_and_requeue!(PROTECTION_RECHECK);
+    }
+    let ovn = Arc::new(Ovn::try_from_annotations(ctx.client.clone()).await?);
+
     info!("ovn: Router {} waiting for deletion", name);
     LogicalRouter::get_by_name(ovn, &name)?.delete()?;
     router
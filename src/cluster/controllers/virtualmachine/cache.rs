@@ -0,0 +1,296 @@
+use futures::{Future, StreamExt, future::join4};
+use k8s_openapi::api::core::v1::Node;
+use kube::runtime::reflector::{self, ObjectRef, Store, store::Writer};
+use kube::runtime::watcher;
+use kube::{Api, Client, Resource, ResourceExt};
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant, sleep};
+use tracing::{info, warn};
+
+use crate::cluster::controllers::virtualmachine::scheduling::{
+    ClusterSnapshot, CpuUsage, PciInventory, get_vm_node,
+};
+use crate::crd::cluster::Cluster;
+use crate::crd::libvirtnode::LibvirtNode;
+use crate::crd::virtualmachine::VirtualMachine;
+use crate::errors::Error;
+use crate::utils::libvirt_capabilities::HostCapabilities;
+use crate::utils::traits::kube::ExtendResource;
+
+/// Drop reservations the VM watch has not caught up with in this time, e.g. because the VM was
+/// deleted right after being scheduled
+const RESERVATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A placement decided by the scheduler which may not be visible in the VM watch yet
+struct Reservation {
+    vm: VirtualMachine,
+    reserved_at: Instant,
+}
+
+impl Reservation {
+    /// The watch has caught up once it shows the VM at the reserved node
+    fn is_observed(&self, watched: Option<&VirtualMachine>) -> bool {
+        let Some(watched) = watched else {
+            return false;
+        };
+        let scheduled = |vm: &VirtualMachine| vm.status.as_ref().is_some_and(|s| s.scheduled);
+        get_vm_node(watched) == get_vm_node(&self.vm) && scheduled(watched) == scheduled(&self.vm)
+    }
+}
+
+/// In-memory view of the nodes and VM placements the scheduler works on, kept up to date by
+/// watches. Placement decisions are made against it one at a time and reserved right away, so
+/// concurrent reconciles see each other's decisions without waiting for the VM status updates to
+/// round trip through the apiserver.
+pub(crate) struct SchedulingCache {
+    nodes: Store<Node>,
+    vms: Store<VirtualMachine>,
+    libvirt_nodes: Store<LibvirtNode>,
+    clusters: Store<Cluster>,
+    ready: [Arc<AtomicBool>; 4],
+    /// Reservations by namespaced VM name
+    reservations: Mutex<BTreeMap<String, Reservation>>,
+    /// Parsed host capabilities by node name, with the resource version they were parsed from.
+    /// None if they could not be parsed.
+    parsed_capabilities: Mutex<BTreeMap<String, (Option<String>, Option<HostCapabilities>)>>,
+}
+
+/// Keep a store up to date from a watch, flagging it ready after the initial listing
+async fn run_reflector<K>(api: Api<K>, writer: Writer<K>, ready: Arc<AtomicBool>)
+where
+    K: Resource + Clone + Debug + DeserializeOwned + Send + Sync + 'static,
+    K::DynamicType: Clone + Default + Eq + std::hash::Hash,
+{
+    reflector::reflector(writer, watcher(api, watcher::Config::default()))
+        .for_each(|event| {
+            let ready = ready.clone();
+            async move {
+                match event {
+                    Ok(watcher::Event::Restarted(_)) => ready.store(true, Ordering::Relaxed),
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("scheduling cache: watch failed: {e}");
+                        sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        })
+        .await;
+}
+
+impl SchedulingCache {
+    /// Create the cache, together with the future which runs the watches filling it
+    pub fn new(
+        client: Client,
+    ) -> (
+        Arc<SchedulingCache>,
+        impl Future<Output = Result<(), Error>> + Send + 'static,
+    ) {
+        let (nodes, node_writer) = reflector::store();
+        let (vms, vm_writer) = reflector::store();
+        let (libvirt_nodes, libvirt_node_writer) = reflector::store();
+        let (clusters, cluster_writer) = reflector::store();
+        let ready: [Arc<AtomicBool>; 4] = Default::default();
+
+        let watches = join4(
+            run_reflector(
+                Api::<Node>::all(client.clone()),
+                node_writer,
+                ready[0].clone(),
+            ),
+            run_reflector(
+                Api::<VirtualMachine>::all(client.clone()),
+                vm_writer,
+                ready[1].clone(),
+            ),
+            run_reflector(
+                Api::<LibvirtNode>::all(client.clone()),
+                libvirt_node_writer,
+                ready[2].clone(),
+            ),
+            run_reflector(
+                Api::<Cluster>::all(client.clone()),
+                cluster_writer,
+                ready[3].clone(),
+            ),
+        );
+        let run = async move {
+            watches.await;
+            Err(Error::UnexpectedExit(
+                "Scheduling cache watches should not exit".into(),
+            ))
+        };
+
+        let cache = Arc::new(SchedulingCache {
+            nodes,
+            vms,
+            libvirt_nodes,
+            clusters,
+            ready,
+            reservations: Mutex::new(BTreeMap::new()),
+            parsed_capabilities: Mutex::new(BTreeMap::new()),
+        });
+        (cache, run)
+    }
+
+    /// Wait until all the watches have done their initial listing
+    pub async fn wait_ready(&self) {
+        while !self.ready.iter().all(|ready| ready.load(Ordering::Relaxed)) {
+            sleep(Duration::from_millis(100)).await;
+        }
+        info!("scheduling cache: ready");
+    }
+
+    pub fn get_vm(&self, namespace: &str, name: &str) -> Option<Arc<VirtualMachine>> {
+        self.vms.get(&ObjectRef::new(name).within(namespace))
+    }
+
+    /// Build a snapshot from the watched objects with the outstanding reservations applied on top
+    fn build_snapshot(
+        &self,
+        reservations: &mut BTreeMap<String, Reservation>,
+    ) -> Result<ClusterSnapshot, Error> {
+        let nodes = self
+            .nodes
+            .state()
+            .into_iter()
+            .map(|node| (node.name_unchecked(), node.as_ref().clone()))
+            .collect();
+
+        let mut vms: BTreeMap<String, VirtualMachine> = self
+            .vms
+            .state()
+            .into_iter()
+            .map(|vm| (vm.name_prefixed_with_namespace(), vm.as_ref().clone()))
+            .collect();
+        reservations.retain(|name, reservation| {
+            !reservation.is_observed(vms.get(name))
+                && reservation.reserved_at.elapsed() < RESERVATION_TIMEOUT
+        });
+        for (name, reservation) in reservations.iter() {
+            vms.insert(name.clone(), reservation.vm.clone());
+        }
+
+        let mut capabilities = BTreeMap::new();
+        let mut invalid_capabilities = BTreeSet::new();
+        let mut cpu_usage = BTreeMap::new();
+        let mut hugepages = BTreeMap::new();
+        let mut pci_devices = BTreeMap::new();
+        let mut parsed_capabilities = self
+            .parsed_capabilities
+            .lock()
+            .expect("capabilities lock poisoned");
+        for libvirt_node in self.libvirt_nodes.state() {
+            let name = libvirt_node.name_unchecked();
+            let version = libvirt_node.resource_version();
+            if let Some(status) = libvirt_node.status.as_ref() {
+                hugepages.insert(name.clone(), status.hugepages.clone());
+                pci_devices.insert(
+                    name.clone(),
+                    PciInventory {
+                        devices: status.pci_devices.clone(),
+                        allocations: status.pci_allocations.clone(),
+                    },
+                );
+            }
+            cpu_usage.insert(
+                name.clone(),
+                CpuUsage {
+                    reserved: libvirt_node.spec.reserved_cpus.iter().copied().collect(),
+                    allocations: libvirt_node
+                        .status
+                        .as_ref()
+                        .map(|status| status.cpu_allocations.clone())
+                        .unwrap_or_default(),
+                },
+            );
+            // Capabilities which can not be parsed only keep their own node from being scheduled to
+            let parsed = match parsed_capabilities.get(&name) {
+                Some((parsed_version, parsed)) if *parsed_version == version => {
+                    Some(parsed.clone())
+                }
+                _ => match HostCapabilities::from_libvirtnode(&libvirt_node) {
+                    Ok(Some(parsed)) => Some(Some(parsed)),
+                    Ok(None) => None,
+                    Err(error) => {
+                        warn!("Ignoring the capabilities reported for node {name}: {error}");
+                        Some(None)
+                    }
+                },
+            };
+            match parsed {
+                Some(Some(parsed)) => {
+                    parsed_capabilities.insert(name.clone(), (version, Some(parsed.clone())));
+                    capabilities.insert(name, parsed);
+                }
+                Some(None) => {
+                    parsed_capabilities.insert(name.clone(), (version, None));
+                    invalid_capabilities.insert(name);
+                }
+                None => {}
+            }
+        }
+
+        let cluster = self
+            .clusters
+            .get(&ObjectRef::new("default"))
+            .map(|cluster| cluster.as_ref().clone());
+
+        Ok(ClusterSnapshot {
+            nodes,
+            vms: vms.into_values().collect(),
+            capabilities,
+            invalid_capabilities,
+            cpu_usage,
+            hugepages,
+            pci_devices,
+            cluster,
+        })
+    }
+
+    /// Snapshot of the current cluster state, for decisions that do not place VMs themselves
+    pub fn snapshot(&self) -> Result<ClusterSnapshot, Error> {
+        let mut reservations = self
+            .reservations
+            .lock()
+            .expect("reservations lock poisoned");
+        self.build_snapshot(&mut reservations)
+    }
+
+    /// Make a placement decision against the current state and reserve the VMs it returns with
+    /// their new status, before any other decision can be made. The VM status must then be
+    /// written to the apiserver, or the reservation released if that fails.
+    pub fn place<T>(
+        &self,
+        decide: impl FnOnce(&ClusterSnapshot) -> Result<(T, Vec<VirtualMachine>), Error>,
+    ) -> Result<T, Error> {
+        let mut reservations = self
+            .reservations
+            .lock()
+            .expect("reservations lock poisoned");
+        let snapshot = self.build_snapshot(&mut reservations)?;
+        let (decision, placed) = decide(&snapshot)?;
+        for vm in placed {
+            reservations.insert(
+                vm.name_prefixed_with_namespace(),
+                Reservation {
+                    vm,
+                    reserved_at: Instant::now(),
+                },
+            );
+        }
+        Ok(decision)
+    }
+
+    /// Forget the reservation of a VM whose status could not be written
+    pub fn release(&self, vm: &VirtualMachine) {
+        self.reservations
+            .lock()
+            .expect("reservations lock poisoned")
+            .remove(&vm.name_prefixed_with_namespace());
+    }
+}
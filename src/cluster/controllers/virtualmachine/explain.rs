@@ -0,0 +1,63 @@
+use kube::Client;
+use tracing::info;
+
+use crate::cluster::controllers::virtualmachine::cache::SchedulingCache;
+use crate::cluster::controllers::virtualmachine::scheduling::{explain, migration_requested};
+use crate::errors::Error;
+
+const USAGE: &str = "--explain-schedule <namespace>/<vm>";
+
+/// Namespace and name of the VM given after the mode
+fn parse_target(args: &[String]) -> Result<(&str, &str), Error> {
+    args.iter()
+        .skip_while(|arg| *arg != "--explain-schedule")
+        .nth(1)
+        .and_then(|target| target.split_once('/'))
+        .filter(|(namespace, name)| !namespace.is_empty() && !name.is_empty())
+        .ok_or_else(|| Error::InvalidArguments(format!("usage: {USAGE}")))
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_target() {
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        parse_target(&args(&["virt", "--explain-schedule", "default/web"])).unwrap(),
+        ("default", "web")
+    );
+    assert!(parse_target(&args(&["virt", "--explain-schedule"])).is_err());
+    assert!(parse_target(&args(&["virt", "--explain-schedule", "web"])).is_err());
+    assert!(parse_target(&args(&["virt", "--explain-schedule", "/web"])).is_err());
+    assert!(parse_target(&args(&["virt"])).is_err());
+}
+
+/// Run the scheduler for a single VM against the current cluster state and print how every node
+/// fared, without committing anything. The target is given as <namespace>/<vm> after the mode.
+pub async fn explain_schedule(args: Vec<String>, client: Client) -> Result<(), Error> {
+    let (namespace, name) = parse_target(&args)?;
+    let target = format!("{namespace}/{name}");
+
+    let (cache, watches) = SchedulingCache::new(client);
+    let watches_task = tokio::spawn(watches);
+    cache.wait_ready().await;
+    info!("Explaining scheduling of {namespace}/{name}");
+
+    let vm = cache
+        .get_vm(namespace, name)
+        .ok_or_else(|| Error::ScheduleFailed(format!("VM {target} not found")))?;
+    let snapshot = cache.snapshot()?;
+
+    let trace = explain(&vm, &snapshot, false)?;
+    println!("{target}:");
+    print!("{trace}");
+
+    // Scheduling for a requested migration falls back to ignoring affinity rules
+    if trace.selected.is_none() && migration_requested(&vm) {
+        let trace = explain(&vm, &snapshot, true)?;
+        println!("{target}, ignoring affinity for the requested migration:");
+        print!("{trace}");
+    }
+
+    watches_task.abort();
+    Ok(())
+}
@@ -1,4 +1,9 @@
+pub mod cache;
+pub mod explain;
 pub mod ovn;
+pub mod preemption;
+pub mod rebalancer;
 pub mod scheduling;
+pub mod templates;
 pub mod utils;
 pub mod vm;
@@ -25,13 +25,14 @@ lazy_static! {
     static ref FIELD_MANAGER: String = field_manager("vm.ovn");
 }
 
+/// Returns whether the logical switch port was created
 #[instrument(skip(client))]
 async fn connect_vm_nic(
     client: Client,
     vm: &VirtualMachine,
     nic: &NetworkAttachment,
     ovn: Arc<Ovn>,
-) -> Result<(), Error> {
+) -> Result<bool, Error> {
     let namespace = ResourceExt::namespace(vm).expect("Failed to get VM namespace");
     let network_name = nic.name.as_ref().expect("No network name set");
     let mac_address = nic.mac_address.as_ref().expect("MAC address missing");
@@ -40,6 +41,10 @@ async fn connect_vm_nic(
     let mut ls = LogicalSwitch::get_by_name(ovn.clone(), &ls_name)?;
 
     let lsp_id = nic.ovn_id.as_ref().unwrap();
+    let created = matches!(
+        LogicalSwitchPort::get_by_name(ovn.clone(), lsp_id),
+        Err(Error::OvnNotFound(_, _))
+    );
     let mut lsp = ls.lsp().create_if_missing(lsp_id, None)?;
 
     let ip = if ls.get_cidr().is_some() {
@@ -54,7 +59,7 @@ async fn connect_vm_nic(
     if let Some(dhcp) = network.spec.dhcp {
         lsp.set_dhcp_options(&dhcp.cidr)?;
     }
-    Ok(())
+    Ok(created)
 }
 
 #[instrument]
@@ -103,7 +108,20 @@ async fn update_vm(vm: Arc<VirtualMachine>, ctx: Arc<DefaultState>) -> Result<Ac
     let mut ip_addresses: Vec<String> = Vec::new();
     for (index, nic) in get_vm_ovn_nics(&vm).iter().enumerate() {
         info!("ovn: connecting NIC {index} for VM {name}");
-        connect_vm_nic(client.clone(), &vm, nic, ovn.clone()).await?;
+        if connect_vm_nic(client.clone(), &vm, nic, ovn.clone()).await? {
+            ctx.recorder
+                .normal(
+                    &vm,
+                    "PortCreated",
+                    "ConnectNetwork",
+                    format!(
+                        "Created OVN port {} on network {}",
+                        nic.ovn_id.as_deref().unwrap_or_default(),
+                        nic.name.as_deref().unwrap_or_default()
+                    ),
+                )
+                .await;
+        }
         if let Some(ovn_id) = nic.ovn_id.as_ref() {
             let lsp = LogicalSwitchPort::get_by_name(ovn.clone(), ovn_id)?;
             if let Some(ip) = lsp.dynamic_ip() {
@@ -113,12 +131,11 @@ async fn update_vm(vm: Arc<VirtualMachine>, ctx: Arc<DefaultState>) -> Result<Ac
     }
 
     if let Some(status) = vm.status.clone() {
-        let ip_addresses_string = ip_addresses.join(",");
-        let new_status = VirtualMachineStatus {
-            ip_addresses: Some(ip_addresses),
-            ip_addresses_string: Some(ip_addresses_string),
+        let mut new_status = VirtualMachineStatus {
+            ovn_ip_addresses: ip_addresses,
             ..status
         };
+        new_status.refresh_ip_addresses();
         set_vm_status(&vm, new_status, client).await?;
     }
 
@@ -0,0 +1,307 @@
+use k8s_openapi::api::core::v1::Node;
+use kube::runtime::events::EventType;
+use kube::{Client, ResourceExt};
+use std::cmp::Reverse;
+use tracing::{info, instrument};
+
+use crate::cluster::controllers::virtualmachine::cache::SchedulingCache;
+use crate::cluster::controllers::virtualmachine::scheduling::{
+    CAPACITY_FILTERS, ClusterSnapshot, SchedulingContext, vm_memory,
+};
+use crate::crd::virtualmachine::{VirtualMachine, VirtualMachineStatus, set_vm_status};
+use crate::errors::Error;
+use crate::utils::events::EventRecorder;
+use crate::utils::traits::kube::{ExtendResource, TryStatus};
+
+/// Lower priority VMs to evict from a node to make room for a VM
+struct PreemptionPlan<'a> {
+    node: &'a Node,
+    victims: Vec<&'a VirtualMachine>,
+}
+
+/// Memory the VMs on a node may be given when preempting for a cluster without a memory limit,
+/// in percent of the allocatable memory, so that evictions do not leave the node overcommitted
+const PREEMPTION_MEMORY_LIMIT_PERCENT: u64 = 100;
+
+/// Find the node where the VM fits by evicting the least important and fewest lower priority
+/// VMs. Evicting frees the capacity of the node, e.g. memory, hugepages, dedicated CPUs and host
+/// devices, all the other filters must already pass on the node.
+fn plan_preemption<'a>(
+    vm: &'a VirtualMachine,
+    snapshot: &'a ClusterSnapshot,
+) -> Result<Option<PreemptionPlan<'a>>, Error> {
+    let ctx = SchedulingContext::new(vm, snapshot, false)?;
+    let priority = vm.spec.get_priority();
+    let mut best: Option<((i32, usize), PreemptionPlan)> = None;
+
+    for node in ctx
+        .nodes
+        .values()
+        .filter(|node| ctx.is_feasible_without(node, CAPACITY_FILTERS))
+    {
+        let mut candidates: Vec<&VirtualMachine> = ctx
+            .vms_on_node(node)
+            .filter(|other| other.spec.get_priority() < priority)
+            .collect();
+        // Evict the least important VMs first, and among those the largest ones
+        candidates.sort_by_key(|other| (other.spec.get_priority(), Reverse(vm_memory(other))));
+
+        let mut victims = vec![];
+        let mut fits = false;
+        for candidate in candidates {
+            victims.push(candidate);
+            if fits_after_eviction(vm, snapshot, node, &victims)? {
+                fits = true;
+                break;
+            }
+        }
+        if !fits {
+            continue;
+        }
+        // Spare the victims that turned out not to be needed to make room
+        let mut index = 0;
+        while index < victims.len() {
+            let mut fewer = victims.clone();
+            fewer.remove(index);
+            if fits_after_eviction(vm, snapshot, node, &fewer)? {
+                victims = fewer;
+            } else {
+                index += 1;
+            }
+        }
+
+        let highest_victim_priority = victims
+            .iter()
+            .map(|victim| victim.spec.get_priority())
+            .max()
+            .unwrap_or(i32::MIN);
+        let cost = (highest_victim_priority, victims.len());
+        if best
+            .as_ref()
+            .is_none_or(|(best_cost, _plan)| cost < *best_cost)
+        {
+            best = Some((cost, PreemptionPlan { node, victims }));
+        }
+    }
+
+    Ok(best.map(|(_cost, plan)| plan))
+}
+
+/// Whether the VM passes all the filters on the node once the victims are gone
+fn fits_after_eviction(
+    vm: &VirtualMachine,
+    snapshot: &ClusterSnapshot,
+    node: &Node,
+    victims: &[&VirtualMachine],
+) -> Result<bool, Error> {
+    let snapshot = snapshot.without(victims);
+    let ctx = SchedulingContext::new(vm, &snapshot, false)?;
+    let limit_percent = ctx
+        .memory_limit_percent
+        .unwrap_or(PREEMPTION_MEMORY_LIMIT_PERCENT);
+    Ok(ctx.is_feasible_without(node, &["memory_capacity"])
+        && ctx.missing_memory_within(node, limit_percent) == 0)
+}
+
+#[cfg(test)]
+#[test]
+fn test_plan_preemption() {
+    use crate::cluster::controllers::virtualmachine::scheduling::{
+        test_node, test_snapshot, test_vm,
+    };
+    use crate::crd::virtualmachine::v1beta3::HugepageSize;
+    use std::collections::BTreeMap;
+
+    let hugepages_vm = |name: &str, memory: &str, priority: i32, node: Option<&str>| {
+        let mut vm = test_vm(name, &[], node);
+        vm.spec.memory = String::from(memory);
+        vm.spec.hugepages = Some(HugepageSize::Size1G);
+        vm.spec.priority = Some(priority);
+        vm
+    };
+    // The only node has all its hugepages taken, with the default cluster configuration
+    let mut snapshot = test_snapshot(
+        vec![test_node("a", &[])],
+        vec![
+            hugepages_vm("low", "2 GiB", 0, Some("a")),
+            hugepages_vm("lowest", "1 GiB", -1, Some("a")),
+        ],
+    );
+    snapshot.hugepages =
+        BTreeMap::from([(String::from("a"), BTreeMap::from([(String::from("1G"), 3)]))]);
+
+    let vm = hugepages_vm("high", "2 GiB", 10, None);
+    let ctx = SchedulingContext::new(&vm, &snapshot, false).unwrap();
+    assert!(!ctx.is_feasible(&snapshot.nodes["a"]));
+
+    let plan = plan_preemption(&vm, &snapshot).unwrap().unwrap();
+    assert_eq!(plan.node.name_unchecked(), "a");
+    let victims: Vec<String> = plan.victims.iter().map(|v| v.name_any()).collect();
+    // Evicting the lowest priority VM does not make enough room, and then it is not needed
+    assert_eq!(victims, vec!["low"]);
+
+    // VMs of the same or higher priority are never evicted
+    let vm = hugepages_vm("peer", "2 GiB", 0, None);
+    assert!(plan_preemption(&vm, &snapshot).unwrap().is_none());
+}
+
+/// A lower priority VM evicted from the node, with the status it gets
+struct Eviction {
+    victim: VirtualMachine,
+    status: VirtualMachineStatus,
+    outcome: String,
+}
+
+/// Decide where the victims go. Evicted VMs are moved to the best other node they fit on, or
+/// unscheduled if there is none or they can not be live migrated. Returns the evicted VMs with
+/// their new status.
+fn plan_evictions(
+    snapshot: &ClusterSnapshot,
+    node_name: &str,
+    victims: Vec<VirtualMachine>,
+) -> Result<Vec<Eviction>, Error> {
+    let mut snapshot = snapshot.clone();
+    let mut evictions = vec![];
+
+    for victim in victims {
+        // A victim which can not be scheduled, e.g. with an invalid CPU definition, is shut down
+        let destination = match SchedulingContext::new(&victim, &snapshot, false) {
+            Ok(ctx) if !victim.spec.has_host_devices() => ctx
+                .nodes
+                .values()
+                .filter(|candidate| candidate.name_unchecked() != node_name)
+                .filter(|candidate| ctx.is_feasible(candidate))
+                .max_by_key(|candidate| ctx.score(candidate))
+                .map(|candidate| candidate.name_unchecked()),
+            _ => None,
+        };
+
+        let mut status = victim.try_status()?.clone();
+        let outcome = match destination {
+            Some(destination) => {
+                let outcome = format!("migrated to {destination}");
+                status.node = Some(destination);
+                status.migration_pending = true;
+                outcome
+            }
+            None => {
+                status.node = None;
+                status.scheduled = false;
+                status.migration_pending = false;
+                String::from("shut down")
+            }
+        };
+
+        // Later victims must see where this one went
+        let victim_name = victim.name_prefixed_with_namespace();
+        if let Some(entry) = snapshot
+            .vms
+            .iter_mut()
+            .find(|other| other.name_prefixed_with_namespace() == victim_name)
+        {
+            entry.status = Some(status.clone());
+        }
+
+        evictions.push(Eviction {
+            victim,
+            status,
+            outcome,
+        });
+    }
+
+    Ok(evictions)
+}
+
+/// Make room for a VM which does not fit anywhere by evicting lower priority VMs from a node.
+/// Evicted VMs are live migrated if they fit on some other node, otherwise they are shut down and
+/// wait to be scheduled again. Returns the node that was freed for the VM, or None if no
+/// preemption would help. The VM is reserved to the node in the cache, its status must be written
+/// by the caller.
+#[instrument(skip(cache, client, recorder))]
+pub(crate) async fn preempt(
+    vm: &VirtualMachine,
+    cache: &SchedulingCache,
+    client: Client,
+    recorder: &EventRecorder,
+) -> Result<Option<Node>, Error> {
+    let decision = cache.place(|snapshot| {
+        let Some(plan) = plan_preemption(vm, snapshot)? else {
+            return Ok((None, vec![]));
+        };
+        let node = plan.node.clone();
+        let victims = plan.victims.into_iter().cloned().collect();
+        let evictions = plan_evictions(snapshot, &node.name_unchecked(), victims)?;
+
+        let mut placed: Vec<VirtualMachine> = evictions
+            .iter()
+            .map(|eviction| {
+                let mut victim = eviction.victim.clone();
+                victim.status = Some(eviction.status.clone());
+                victim
+            })
+            .collect();
+        let mut placed_vm = vm.clone();
+        if let Some(status) = placed_vm.status.as_mut() {
+            status.node = node.metadata.name.clone();
+            status.scheduled = true;
+        }
+        placed.push(placed_vm);
+
+        Ok((Some((node, evictions)), placed))
+    })?;
+    let Some((node, evictions)) = decision else {
+        return Ok(None);
+    };
+
+    let node_name = node.name_unchecked();
+    let vm_name = vm.name_prefixed_with_namespace();
+
+    for Eviction {
+        victim,
+        status,
+        outcome,
+    } in evictions
+    {
+        let victim_name = victim.name_prefixed_with_namespace();
+        info!("preemption: {victim_name} on {node_name} {outcome} to make room for {vm_name}");
+
+        // Write against the latest version of the VM to avoid conflicts with the snapshot copy
+        let latest = cache
+            .get_vm(&victim.namespace_unchecked(), &victim.name_unchecked())
+            .map(|latest| latest.as_ref().clone())
+            .unwrap_or_else(|| victim.clone());
+        if let Err(e) = set_vm_status(&latest, status, client.clone()).await {
+            cache.release(&victim);
+            return Err(e);
+        }
+
+        recorder
+            .publish_related(
+                &victim,
+                EventType::Warning,
+                "Preempted",
+                "Preempt",
+                format!(
+                    "Evicted from {node_name} and {outcome} to make room for {vm_name} with priority {}",
+                    vm.spec.get_priority()
+                ),
+                vm,
+            )
+            .await;
+        recorder
+            .publish_related(
+                vm,
+                EventType::Normal,
+                "Preempting",
+                "Preempt",
+                format!(
+                    "Evicted {victim_name} with priority {} from {node_name}, it was {outcome}",
+                    victim.spec.get_priority()
+                ),
+                &victim,
+            )
+            .await;
+    }
+
+    Ok(Some(node))
+}
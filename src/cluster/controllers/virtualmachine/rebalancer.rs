@@ -0,0 +1,301 @@
+use k8s_openapi::api::core::v1::Node;
+use kube::{Client, ResourceExt};
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::time::{Duration, sleep};
+use tracing::{debug, error, info, instrument};
+
+use crate::cluster::controllers::virtualmachine::cache::SchedulingCache;
+use crate::cluster::controllers::virtualmachine::scheduling::{
+    ClusterSnapshot, NodeLoad, SchedulingContext, get_vm_node,
+};
+use crate::crd::cluster::RebalancerSpec;
+use crate::crd::virtualmachine::VirtualMachine;
+use crate::errors::Error;
+use crate::utils::strings::field_manager;
+use crate::utils::traits::kube::ExtendResource;
+use crate::utils::traits::node::NodeExt;
+use crate::utils::traits::virtualmachine::VirtualMachineExt;
+
+const DEFAULT_INTERVAL_SECONDS: u64 = 300;
+const DEFAULT_MAX_CONCURRENT_MIGRATIONS: usize = 1;
+const DEFAULT_IMBALANCE_THRESHOLD_PERCENT: u64 = 25;
+
+lazy_static! {
+    static ref FIELD_MANAGER: String = field_manager("rebalancer");
+}
+
+/// A VM the rebalancer wants to move away from its current node
+#[derive(Debug)]
+struct PlannedMigration<'a> {
+    vm: &'a VirtualMachine,
+    source: &'a Node,
+    reason: String,
+}
+
+/// Only move VMs which the scheduler placed, which are not already being moved and which can be
+/// live migrated at all
+fn is_movable(vm: &VirtualMachine) -> bool {
+    let settled = vm
+        .status
+        .as_ref()
+        .is_some_and(|status| status.scheduled && !status.migration_pending);
+    settled
+        && vm.spec.node.is_none()
+        && vm.migration_requested_from().is_none()
+        && !vm.spec.has_host_devices()
+}
+
+fn migrations_in_progress(snapshot: &ClusterSnapshot) -> usize {
+    snapshot
+        .vms
+        .iter()
+        .filter(|vm| {
+            vm.migration_requested_from().is_some()
+                || vm
+                    .status
+                    .as_ref()
+                    .is_some_and(|status| status.migration_pending)
+        })
+        .count()
+}
+
+/// Difference between the most and least loaded node in percent of the average load, for
+/// whichever of vCPUs or memory is more unevenly spread
+fn imbalance_percent(loads: &BTreeMap<String, NodeLoad>) -> u64 {
+    let spread = |values: Vec<u64>| {
+        let (Some(max), Some(min)) = (values.iter().max(), values.iter().min()) else {
+            return 0;
+        };
+        let total: u64 = values.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        (max - min) * 100 * values.len() as u64 / total
+    };
+
+    let cpus = spread(loads.values().map(|load| load.cpus).collect());
+    let memory = spread(loads.values().map(|load| load.memory).collect());
+    cpus.max(memory)
+}
+
+#[cfg(test)]
+#[test]
+fn test_imbalance_percent() {
+    let loads = BTreeMap::from([
+        (String::from("a"), NodeLoad { cpus: 6, memory: 4 }),
+        (String::from("b"), NodeLoad { cpus: 2, memory: 4 }),
+    ]);
+    assert_eq!(imbalance_percent(&loads), 100);
+
+    let loads = BTreeMap::from([
+        (String::from("a"), NodeLoad { cpus: 4, memory: 0 }),
+        (String::from("b"), NodeLoad { cpus: 4, memory: 0 }),
+    ]);
+    assert_eq!(imbalance_percent(&loads), 0);
+}
+
+/// Share of the cluster wide vCPUs and memory allocated on a node, used to find the busiest and
+/// the least busy node
+fn load_share(load: &NodeLoad, total: &NodeLoad) -> f64 {
+    let share = |value: u64, total: u64| match total {
+        0 => 0.0,
+        total => value as f64 / total as f64,
+    };
+    share(load.cpus, total.cpus) + share(load.memory, total.memory)
+}
+
+/// Find VMs whose current node violates their placement rules, or which would score better on
+/// some other node, e.g. after a node has returned from maintenance. Violations come first,
+/// followed by the largest score improvements.
+fn plan_affinity_migrations(
+    snapshot: &ClusterSnapshot,
+) -> Result<Vec<PlannedMigration<'_>>, Error> {
+    let mut planned = vec![];
+
+    for vm in snapshot.vms.iter().filter(|vm| is_movable(vm)) {
+        let Some(current) = get_vm_node(vm).and_then(|name| snapshot.nodes.get(&name)) else {
+            continue;
+        };
+        // A VM which can not be scheduled, e.g. with an invalid CPU definition, is left in place
+        let Ok(ctx) = SchedulingContext::new(vm, snapshot, false) else {
+            continue;
+        };
+
+        let Some(best_elsewhere) = ctx
+            .nodes
+            .values()
+            .filter(|node| node.name_unchecked() != current.name_unchecked())
+            .filter(|node| ctx.is_feasible(node))
+            .map(|node| ctx.score(node))
+            .max()
+        else {
+            continue;
+        };
+
+        let current_score = ctx.score(current);
+        let (gain, reason) = if ctx.violates_placement_rules(current) {
+            (i64::MAX, String::from("placement rules are violated"))
+        } else if best_elsewhere > current_score {
+            (
+                best_elsewhere - current_score,
+                format!("placement score improves from {current_score} to {best_elsewhere}"),
+            )
+        } else {
+            continue;
+        };
+
+        planned.push((
+            gain,
+            PlannedMigration {
+                vm,
+                source: current,
+                reason,
+            },
+        ));
+    }
+
+    planned.sort_by(|(a, _), (b, _)| b.cmp(a));
+    Ok(planned
+        .into_iter()
+        .map(|(_gain, migration)| migration)
+        .collect())
+}
+
+/// If the load is spread too unevenly, find the VM on the busiest node whose move to the least
+/// busy node evens out the load the most. The move must not make the VM placement worse.
+fn plan_load_migration(
+    snapshot: &ClusterSnapshot,
+    threshold_percent: u64,
+) -> Result<Option<PlannedMigration<'_>>, Error> {
+    let mut loads = snapshot.node_loads();
+    loads.retain(|name, _load| {
+        let hypervisor =
+            snapshot.capabilities.is_empty() || snapshot.capabilities.contains_key(name);
+        let schedulable = snapshot
+            .nodes
+            .get(name)
+            .is_some_and(|node| !node.in_maintenance_mode() && node.allows_scheduling());
+        hypervisor && schedulable
+    });
+
+    let current_imbalance = imbalance_percent(&loads);
+    if current_imbalance <= threshold_percent {
+        return Ok(None);
+    }
+
+    let total = loads.values().fold(NodeLoad::default(), |mut total, load| {
+        total.cpus += load.cpus;
+        total.memory += load.memory;
+        total
+    });
+    let by_share = |(_a, a): &(&String, &NodeLoad), (_b, b): &(&String, &NodeLoad)| {
+        load_share(a, &total).total_cmp(&load_share(b, &total))
+    };
+    let (Some((busiest, _)), Some((least_busy, _))) =
+        (loads.iter().max_by(by_share), loads.iter().min_by(by_share))
+    else {
+        return Ok(None);
+    };
+    let (source, target) = (&snapshot.nodes[busiest], &snapshot.nodes[least_busy]);
+
+    let mut best: Option<(u64, &VirtualMachine)> = None;
+    for vm in snapshot
+        .vms
+        .iter()
+        .filter(|vm| is_movable(vm) && get_vm_node(vm).as_ref() == Some(busiest))
+    {
+        let Ok(ctx) = SchedulingContext::new(vm, snapshot, false) else {
+            continue;
+        };
+        if !ctx.is_feasible(target) || ctx.score(target) < ctx.score(source) {
+            continue;
+        }
+
+        let mut moved = loads.clone();
+        moved.entry(busiest.clone()).or_default().remove(vm);
+        moved.entry(least_busy.clone()).or_default().add(vm);
+        let imbalance = imbalance_percent(&moved);
+
+        if imbalance < current_imbalance && best.is_none_or(|(lowest, _vm)| imbalance < lowest) {
+            best = Some((imbalance, vm));
+        }
+    }
+
+    Ok(best.map(|(imbalance, vm)| PlannedMigration {
+        vm,
+        source,
+        reason: format!("load imbalance goes from {current_imbalance}% to {imbalance}%"),
+    }))
+}
+
+/// Run a single rebalancing round. Returns the number of seconds until the next round.
+#[instrument(skip(client, cache))]
+async fn rebalance(client: Client, cache: &SchedulingCache) -> Result<u64, Error> {
+    let snapshot = cache.snapshot()?;
+    let Some(config) = snapshot
+        .cluster
+        .as_ref()
+        .and_then(|cluster| cluster.spec.rebalancer.clone())
+        .filter(|config| config.enabled)
+    else {
+        return Ok(DEFAULT_INTERVAL_SECONDS);
+    };
+    let RebalancerSpec {
+        interval_seconds,
+        max_concurrent_migrations,
+        imbalance_threshold_percent,
+        ..
+    } = config;
+    let interval = interval_seconds.unwrap_or(DEFAULT_INTERVAL_SECONDS);
+
+    let in_progress = migrations_in_progress(&snapshot);
+    let budget = max_concurrent_migrations
+        .unwrap_or(DEFAULT_MAX_CONCURRENT_MIGRATIONS)
+        .saturating_sub(in_progress);
+    if budget == 0 {
+        debug!("rebalancer: {in_progress} migrations in progress, migration budget used up");
+        return Ok(interval);
+    }
+
+    // Load is only evened out once the placement rules are satisfied, as the affinity migrations
+    // change the load anyway
+    let mut planned = plan_affinity_migrations(&snapshot)?;
+    if planned.is_empty() {
+        let threshold = imbalance_threshold_percent.unwrap_or(DEFAULT_IMBALANCE_THRESHOLD_PERCENT);
+        planned.extend(plan_load_migration(&snapshot, threshold)?);
+    }
+
+    for migration in planned.into_iter().take(budget) {
+        info!(
+            "rebalancer: requesting migration of {} away from {}: {}",
+            migration.vm.name_prefixed_with_namespace(),
+            migration.source.name_unchecked(),
+            migration.reason
+        );
+        // The VM controller picks the new node, which prefers the least allocated one
+        let mut vm = migration.vm.clone();
+        vm.request_migration_away_from(migration.source, &FIELD_MANAGER, client.clone())
+            .await?;
+    }
+
+    Ok(interval)
+}
+
+/// Periodically move VMs with live migrations to restore their affinity rules and to even out
+/// the load between nodes. Configured through the rebalancer settings of the default Cluster.
+pub async fn run(client: Client, cache: Arc<SchedulingCache>) -> Result<(), Error> {
+    info!("rebalancer: starting");
+    cache.wait_ready().await;
+    loop {
+        let interval = match rebalance(client.clone(), &cache).await {
+            Ok(interval) => interval,
+            Err(e) => {
+                error!("rebalancer: round failed: {e}");
+                DEFAULT_INTERVAL_SECONDS
+            }
+        };
+        sleep(Duration::from_secs(interval)).await;
+    }
+}
@@ -1,67 +1,909 @@
 use k8s_openapi::api::core::v1::Node;
-use kube::{
-    api::{Api, ListParams, ResourceExt},
-    Client,
-};
+use kube::{Client, api::ResourceExt};
 use rand::seq::SliceRandom;
-use tracing::instrument;
+use std::collections::{BTreeMap, BTreeSet};
+use tracing::{debug, instrument};
 
-use crate::crd::virtualmachine::VirtualMachine;
+use crate::cluster::controllers::virtualmachine::cache::SchedulingCache;
+use crate::crd::cluster::Cluster;
+use crate::crd::libvirtnode::{CpuAllocation, PciDevice};
+use crate::crd::virtualmachine::{VirtualMachine, VmAffinityTerm};
 use crate::errors::Error;
-use crate::utils::traits::kube::{ApiExt, ExtendResource, TryStatus};
+use crate::utils::libvirt_capabilities::{CpuDefinition, HostCapabilities, parse_cpu_definition};
+use crate::utils::libvirt_nodedev::pick_pci_devices;
+use crate::utils::strings::{parse_memory_bytes, parse_quantity_bytes};
+use crate::utils::traits::kube::{ExtendResource, TryStatus};
 use crate::utils::traits::node::NodeExt;
 use crate::utils::traits::virtualmachine::VirtualMachineExt;
 
-/// Find all VMs registered to the k8s apiserver with the given label set to the given value
-#[instrument(skip(client))]
-async fn get_vms_with_label(
-    client: Client,
-    label: &str,
-    value: &str,
-) -> Result<Vec<VirtualMachine>, Error> {
-    let vm_api: Api<VirtualMachine> = Api::all(client.clone());
-    let list_params = ListParams {
-        label_selector: Some(format!("{label}={value}")),
-        ..ListParams::default()
-    };
-    let conflicting_vms = vm_api.list(&list_params).await?;
-    Ok(conflicting_vms.items)
+const ANTI_AFFINITY_LABEL: &str = "antiAffinity";
+const DEFAULT_SOFT_WEIGHT: i64 = 1;
+
+/// Snapshot of the cluster state that scheduling decisions are made against
+#[derive(Clone)]
+pub(crate) struct ClusterSnapshot {
+    /// All nodes by name, including the ones that are not eligible for scheduling
+    pub nodes: BTreeMap<String, Node>,
+    pub vms: Vec<VirtualMachine>,
+    /// Capabilities reported by the libvirt host controllers by node name
+    pub capabilities: BTreeMap<String, HostCapabilities>,
+    /// Nodes whose reported capabilities could not be parsed
+    pub invalid_capabilities: BTreeSet<String>,
+    /// Host CPUs reserved and dedicated to VMs by node name
+    pub cpu_usage: BTreeMap<String, CpuUsage>,
+    /// Hugepages configured on each node as number of pages by page size name
+    pub hugepages: BTreeMap<String, BTreeMap<String, u64>>,
+    /// PCI devices of each node and their allocations to VMs
+    pub pci_devices: BTreeMap<String, PciInventory>,
+    /// The default cluster configuration, if it exists
+    pub cluster: Option<Cluster>,
 }
 
-/// Return all VMs except self in the same anti-affinity group as the given VM
-#[instrument(skip(client))]
-async fn get_others_in_affinity_group(
-    client: Client,
-    ref_vm: &VirtualMachine,
-) -> Result<Vec<VirtualMachine>, Error> {
-    let maybe_group = ref_vm.labels().get(ANTI_AFFINITY_LABEL);
-    if let Some(group) = maybe_group {
-        let mut vms = get_vms_with_label(client, ANTI_AFFINITY_LABEL, group).await?;
-        vms.retain(|vm| vm.name_prefixed_with_namespace() != ref_vm.name_prefixed_with_namespace());
-        Ok(vms)
-    } else {
-        Ok(Vec::new())
+/// Host CPUs of a node that are not available for dedicating to a VM
+#[derive(Debug, Default, Clone)]
+pub(crate) struct CpuUsage {
+    /// CPUs kept for the host itself
+    pub reserved: BTreeSet<u32>,
+    /// CPUs allocated by the host controller, by namespaced VM name
+    pub allocations: BTreeMap<String, CpuAllocation>,
+}
+
+/// PCI devices reported by the host controller of a node
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PciInventory {
+    pub devices: Vec<PciDevice>,
+    /// Addresses of the devices passed through, by namespaced VM name
+    pub allocations: BTreeMap<String, Vec<String>>,
+}
+
+/// The cluster state as seen by a single VM being scheduled
+pub(crate) struct SchedulingContext<'a> {
+    pub vm: &'a VirtualMachine,
+    pub nodes: &'a BTreeMap<String, Node>,
+    /// All VMs in the cluster except the one being scheduled
+    pub other_vms: Vec<&'a VirtualMachine>,
+    pub capabilities: &'a BTreeMap<String, HostCapabilities>,
+    pub invalid_capabilities: &'a BTreeSet<String>,
+    pub cpu_usage: &'a BTreeMap<String, CpuUsage>,
+    pub hugepages: &'a BTreeMap<String, BTreeMap<String, u64>>,
+    pub pci_devices: &'a BTreeMap<String, PciInventory>,
+    /// CPU the VM will be started with, from the VM or the cluster defaults
+    pub cpu: Option<CpuDefinition>,
+    /// Machine type the VM will be started with, from the VM or the cluster defaults
+    pub machine_type: Option<String>,
+    /// Bypass the VM (anti-)affinity rules, e.g. during host maintenance
+    pub ignore_affinity: bool,
+    /// Memory limit of the nodes in percent of their allocatable memory, None to overcommit
+    pub memory_limit_percent: Option<u64>,
+}
+
+type FilterFn = fn(&SchedulingContext, &Node) -> bool;
+type ScoreFn = fn(&SchedulingContext, &Node) -> i64;
+
+/// Filter stages, a node must pass all of them to be a scheduling candidate
+const FILTERS: &[(&str, FilterFn)] = &[
+    ("maintenance", filter_maintenance),
+    ("requested_node", filter_requested_node),
+    ("no_schedule", filter_no_schedule),
+    ("migration_source", filter_migration_source),
+    ("node_selector", filter_node_selector),
+    ("cpu_compatibility", filter_cpu_compatibility),
+    ("machine_type", filter_machine_type),
+    ("memory_capacity", filter_memory_capacity),
+    ("hugepages", filter_hugepages),
+    ("dedicated_cpus", filter_dedicated_cpus),
+    ("host_devices", filter_host_devices),
+    ("vm_affinity", filter_vm_affinity),
+    ("vm_anti_affinity", filter_vm_anti_affinity),
+];
+
+/// Filter stages checking the capacity left on the node, which evicting VMs can free up
+pub(crate) const CAPACITY_FILTERS: &[&str] = &[
+    "memory_capacity",
+    "hugepages",
+    "dedicated_cpus",
+    "host_devices",
+];
+
+/// Score stages, the candidate with the highest total score is selected
+const SCORERS: &[(&str, ScoreFn)] = &[
+    ("node_preferences", score_node_preferences),
+    ("vm_affinity", score_vm_affinity),
+    ("vm_anti_affinity", score_vm_anti_affinity),
+];
+
+impl ClusterSnapshot {
+    /// Copy of the snapshot as if the VMs were gone, with their CPUs and PCI devices freed
+    pub fn without(&self, removed: &[&VirtualMachine]) -> ClusterSnapshot {
+        let names: BTreeSet<String> = removed
+            .iter()
+            .map(|vm| vm.name_prefixed_with_namespace())
+            .collect();
+        let mut snapshot = self.clone();
+        snapshot
+            .vms
+            .retain(|vm| !names.contains(&vm.name_prefixed_with_namespace()));
+        for usage in snapshot.cpu_usage.values_mut() {
+            usage
+                .allocations
+                .retain(|name, _allocation| !names.contains(name));
+        }
+        for inventory in snapshot.pci_devices.values_mut() {
+            inventory
+                .allocations
+                .retain(|name, _addresses| !names.contains(name));
+        }
+        snapshot
+    }
+
+    /// Sum the vCPUs and memory bytes of the VMs scheduled to each node
+    pub fn node_loads(&self) -> BTreeMap<String, NodeLoad> {
+        let mut loads: BTreeMap<String, NodeLoad> = self
+            .nodes
+            .keys()
+            .map(|name| (name.clone(), NodeLoad::default()))
+            .collect();
+        for vm in &self.vms {
+            if let Some(load) = get_vm_node(vm).and_then(|node| loads.get_mut(&node)) {
+                load.add(vm);
+            }
+        }
+        loads
     }
 }
 
-/// Check for compliance with anti-affinity groups
-#[instrument(skip(client))]
-pub async fn is_uncompliant(vm: &VirtualMachine, client: Client) -> Result<bool, Error> {
-    let status = vm.try_status()?.clone();
+/// Resources allocated to the VMs on a node
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct NodeLoad {
+    pub cpus: u64,
+    pub memory: u64,
+}
+
+impl NodeLoad {
+    pub fn add(&mut self, vm: &VirtualMachine) {
+        self.cpus += vm.cpus() as u64;
+        self.memory += vm_memory(vm);
+    }
+
+    pub fn remove(&mut self, vm: &VirtualMachine) {
+        self.cpus = self.cpus.saturating_sub(vm.cpus() as u64);
+        self.memory = self.memory.saturating_sub(vm_memory(vm));
+    }
+}
+
+/// Memory of the VM in bytes, zero if it can not be parsed
+pub(crate) fn vm_memory(vm: &VirtualMachine) -> u64 {
+    parse_memory_bytes(vm.memory()).unwrap_or(0)
+}
+
+/// Memory the node has available for VMs, if the node reports it. It is a Kubernetes quantity,
+/// where "M" and "G" are decimal unlike in VM memory sizes.
+fn allocatable_memory(node: &Node) -> Option<u64> {
+    node.status
+        .as_ref()
+        .and_then(|status| status.allocatable.as_ref())
+        .and_then(|allocatable| allocatable.get("memory"))
+        .and_then(|quantity| parse_quantity_bytes(&quantity.0))
+}
+
+impl<'a> SchedulingContext<'a> {
+    /// Resolve the view of the snapshot for the VM
+    pub fn new(
+        vm: &'a VirtualMachine,
+        snapshot: &'a ClusterSnapshot,
+        ignore_affinity: bool,
+    ) -> Result<SchedulingContext<'a>, Error> {
+        let other_vms = snapshot
+            .vms
+            .iter()
+            .filter(|other| {
+                other.name_prefixed_with_namespace() != vm.name_prefixed_with_namespace()
+            })
+            .collect();
+
+        let cluster = snapshot.cluster.as_ref();
+        let cpu = match vm.cpu_model() {
+            Some(cpu) => Some(parse_cpu_definition(cpu).map_err(|error| {
+                Error::InvalidResource(format!(
+                    "CPU definition of {} can not be parsed: {error}",
+                    vm.name_prefixed_with_namespace()
+                ))
+            })?),
+            None => match cluster.map(|c| &c.spec.cpu) {
+                Some(cpu) => Some(parse_cpu_definition(cpu).map_err(|error| {
+                    Error::InvalidResource(format!(
+                        "default CPU definition of the cluster can not be parsed: {error}"
+                    ))
+                })?),
+                None => None,
+            },
+        };
+        let machine_type = vm
+            .machine_type()
+            .cloned()
+            .or(cluster.map(|c| c.spec.machine_type.clone()));
+
+        Ok(SchedulingContext {
+            vm,
+            nodes: &snapshot.nodes,
+            other_vms,
+            capabilities: &snapshot.capabilities,
+            invalid_capabilities: &snapshot.invalid_capabilities,
+            cpu_usage: &snapshot.cpu_usage,
+            hugepages: &snapshot.hugepages,
+            pci_devices: &snapshot.pci_devices,
+            cpu,
+            machine_type,
+            ignore_affinity,
+            memory_limit_percent: cluster.and_then(|c| c.spec.memory_limit_percent),
+        })
+    }
+
+    /// Whether the node passes all filter stages
+    pub fn is_feasible(&self, node: &Node) -> bool {
+        FILTERS.iter().all(|(_name, filter)| filter(self, node))
+    }
+
+    /// Whether the node passes all filter stages except the named ones
+    pub fn is_feasible_without(&self, node: &Node, skipped_filters: &[&str]) -> bool {
+        FILTERS
+            .iter()
+            .filter(|(name, _filter)| !skipped_filters.contains(name))
+            .all(|(_name, filter)| filter(self, node))
+    }
+
+    /// Total score of the node over all score stages
+    pub fn score(&self, node: &Node) -> i64 {
+        SCORERS
+            .iter()
+            .map(|(_name, scorer)| scorer(self, node))
+            .sum()
+    }
+
+    /// Whether the node selector or a hard anti-affinity rule of the VM forbids it from running
+    /// on the node. The rules of the other VMs there are theirs to enforce, so that only one of
+    /// two conflicting VMs is moved.
+    pub fn violates_placement_rules(&self, node: &Node) -> bool {
+        !filter_node_selector(self, node) || !own_anti_affinity_allows(self, node)
+    }
+
+    /// vCPUs allocated to the other VMs on the node
+    fn allocated_cpus(&self, node: &Node) -> usize {
+        self.vms_on_node(node).map(|other| other.cpus()).sum()
+    }
+
+    /// Bytes of memory missing on the node to fit the VM under the cluster memory limit. Zero if
+    /// the VM fits or memory is overcommitted without a limit.
+    pub fn missing_memory(&self, node: &Node) -> u64 {
+        match self.memory_limit_percent {
+            Some(limit_percent) => self.missing_memory_within(node, limit_percent),
+            None => 0,
+        }
+    }
 
-    if status.scheduled {
-        let anti_affinity_members = get_others_in_affinity_group(client.clone(), vm).await?;
-        let uncompliant = anti_affinity_members.iter().any(|other_vm| {
-            let mynode = vm.status.as_ref().and_then(|status| status.node.as_ref());
-            let othernode = other_vm
-                .status
-                .as_ref()
-                .and_then(|status| status.node.as_ref());
+    /// Bytes of memory missing on the node to fit the VM next to the other VMs there, with the
+    /// VMs given the percentage of the allocatable memory. Zero if the VM fits or the node does
+    /// not report its allocatable memory. Hugepages are not part of the allocatable memory, so
+    /// VMs backed by them are left out.
+    pub fn missing_memory_within(&self, node: &Node, limit_percent: u64) -> u64 {
+        let Some(allocatable) = allocatable_memory(node) else {
+            return 0;
+        };
+        if self.vm.spec.hugepages.is_some() {
+            return 0;
+        }
+        let limit = allocatable.saturating_mul(limit_percent) / 100;
+        let allocated: u64 = self
+            .vms_on_node(node)
+            .filter(|other| other.spec.hugepages.is_none())
+            .map(vm_memory)
+            .sum();
+        (allocated + vm_memory(self.vm)).saturating_sub(limit)
+    }
+
+    /// The other VMs scheduled to the node
+    pub fn vms_on_node(&self, node: &Node) -> impl Iterator<Item = &'a VirtualMachine> {
+        let node_name = node.name_unchecked();
+        self.other_vms
+            .iter()
+            .copied()
+            .filter(move |other| get_vm_node(other).as_ref() == Some(&node_name))
+    }
+
+    /// Whether any VM matched by the term has been scheduled to some node
+    fn any_matching_scheduled(&self, term: &VmAffinityTerm) -> bool {
+        self.other_vms.iter().any(|other| {
+            labels_match(other.labels(), &term.match_labels) && get_vm_node(other).is_some()
+        })
+    }
+
+    /// Count the VMs matched by the term that are scheduled to the same topology domain as the node
+    fn matching_vms_in_domain(&self, term: &VmAffinityTerm, node: &Node) -> usize {
+        let Some(domain) = topology_domain(node, &term.topology_key) else {
+            return 0;
+        };
+
+        self.other_vms
+            .iter()
+            .filter(|other| labels_match(other.labels(), &term.match_labels))
+            .filter_map(|other| get_vm_node(other))
+            .filter_map(|node_name| self.nodes.get(&node_name))
+            .filter(|other_node| {
+                topology_domain(other_node, &term.topology_key).as_ref() == Some(&domain)
+            })
+            .count()
+    }
+
+    /// Whether a VM scheduled to the topology domain of the node has a hard anti-affinity term
+    /// matching the VM
+    fn repelled_in_domain(&self, node: &Node) -> bool {
+        self.other_vms.iter().any(|other| {
+            let Some(other_node) = get_vm_node(other).and_then(|name| self.nodes.get(&name)) else {
+                return false;
+            };
+            anti_affinity_terms(other)
+                .iter()
+                .filter(|term| is_required(term))
+                .filter(|term| labels_match(self.vm.labels(), &term.match_labels))
+                .any(|term| {
+                    let domain = topology_domain(node, &term.topology_key);
+                    domain.is_some() && domain == topology_domain(other_node, &term.topology_key)
+                })
+        })
+    }
+
+    /// A VM with an explicitly requested node bypasses the automatic placement rules
+    fn is_pinned(&self) -> bool {
+        self.vm.spec.node.is_some()
+    }
+}
+
+/// Check that the label set contains all the wanted labels
+fn labels_match(labels: &BTreeMap<String, String>, wanted: &BTreeMap<String, String>) -> bool {
+    wanted
+        .iter()
+        .all(|(key, value)| labels.get(key) == Some(value))
+}
 
-            mynode.is_some() && mynode == othernode
+/// Return the topology domain the node belongs to for the given key. Without a key every node is
+/// its own domain. Nodes without the label are not part of any domain.
+fn topology_domain(node: &Node, topology_key: &Option<String>) -> Option<String> {
+    match topology_key {
+        Some(key) => node.labels().get(key).cloned(),
+        None => Some(node.name_unchecked()),
+    }
+}
+
+fn is_required(term: &VmAffinityTerm) -> bool {
+    term.required.unwrap_or(true)
+}
+
+fn affinity_terms(vm: &VirtualMachine) -> Vec<VmAffinityTerm> {
+    vm.spec
+        .affinity
+        .as_ref()
+        .and_then(|affinity| affinity.vm_affinity.clone())
+        .unwrap_or_default()
+}
+
+/// Return the anti-affinity terms of the VM. The legacy antiAffinity label is treated as a hard
+/// anti-affinity term against all VMs with the same label value.
+fn anti_affinity_terms(vm: &VirtualMachine) -> Vec<VmAffinityTerm> {
+    let mut terms = vm
+        .spec
+        .affinity
+        .as_ref()
+        .and_then(|affinity| affinity.vm_anti_affinity.clone())
+        .unwrap_or_default();
+
+    if let Some(group) = vm.labels().get(ANTI_AFFINITY_LABEL) {
+        terms.push(VmAffinityTerm {
+            match_labels: BTreeMap::from([(String::from(ANTI_AFFINITY_LABEL), group.clone())]),
+            ..VmAffinityTerm::default()
         });
+    }
+    terms
+}
+
+/// Remove all nodes which have the maintenance annotation set
+fn filter_maintenance(_ctx: &SchedulingContext, node: &Node) -> bool {
+    !node.in_maintenance_mode()
+}
+
+/// Node specified in VM spec, only allow that one
+fn filter_requested_node(ctx: &SchedulingContext, node: &Node) -> bool {
+    match &ctx.vm.spec.node {
+        Some(requested_node) => &node.name_unchecked() == requested_node,
+        None => true,
+    }
+}
+
+/// Do not automatically schedule to nodes with the no-schedule annotation
+fn filter_no_schedule(ctx: &SchedulingContext, node: &Node) -> bool {
+    ctx.is_pinned() || node.allows_scheduling()
+}
+
+/// Remove a node we are migrating away from (most of the time same as a node in maintenance)
+fn filter_migration_source(ctx: &SchedulingContext, node: &Node) -> bool {
+    ctx.vm.migration_requested_from() != Some(node.name_unchecked())
+}
+
+/// Only allow nodes that have all the labels from the VM node selector
+fn filter_node_selector(ctx: &SchedulingContext, node: &Node) -> bool {
+    match &ctx.vm.spec.node_selector {
+        Some(selector) if !ctx.is_pinned() => labels_match(node.labels(), selector),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_node(name: &str, labels: &[(&str, &str)]) -> Node {
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    Node {
+        metadata: ObjectMeta {
+            name: Some(String::from(name)),
+            labels: Some(
+                labels
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+            ),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_vm(name: &str, labels: &[(&str, &str)], node: Option<&str>) -> VirtualMachine {
+    use crate::crd::virtualmachine::VirtualMachineStatus;
+
+    let mut vm = VirtualMachine::new(name, Default::default());
+    vm.metadata.namespace = Some(String::from("default"));
+    vm.metadata.labels = Some(
+        labels
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+    );
+    vm.status = Some(VirtualMachineStatus {
+        scheduled: node.is_some(),
+        running: node.is_some(),
+        migration_pending: false,
+        node: node.map(String::from),
+        domain_name: String::new(),
+        ip_addresses: None,
+        ip_addresses_string: None,
+        networks: vec![],
+        conditions: vec![],
+        ovn_ip_addresses: vec![],
+        guest: None,
+        applied_credentials: None,
+        template: None,
+    });
+    vm
+}
+
+#[cfg(test)]
+pub(crate) fn test_snapshot(nodes: Vec<Node>, vms: Vec<VirtualMachine>) -> ClusterSnapshot {
+    ClusterSnapshot {
+        nodes: nodes
+            .into_iter()
+            .map(|node| (node.name_unchecked(), node))
+            .collect(),
+        vms,
+        capabilities: BTreeMap::new(),
+        invalid_capabilities: BTreeSet::new(),
+        cpu_usage: BTreeMap::new(),
+        hugepages: BTreeMap::new(),
+        pci_devices: BTreeMap::new(),
+        cluster: None,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_filter_node_selector() {
+    let snapshot = test_snapshot(
+        vec![
+            test_node("a", &[("zone", "a"), ("ssd", "true")]),
+            test_node("b", &[("zone", "b")]),
+            test_node("c", &[]),
+        ],
+        vec![],
+    );
+    let mut vm = test_vm("vm", &[], None);
+    vm.spec.node_selector = Some(BTreeMap::from([(String::from("zone"), String::from("a"))]));
+    let ctx = SchedulingContext::new(&vm, &snapshot, false).unwrap();
+    assert!(filter_node_selector(&ctx, &snapshot.nodes["a"]));
+    assert!(!filter_node_selector(&ctx, &snapshot.nodes["b"]));
+    assert!(!filter_node_selector(&ctx, &snapshot.nodes["c"]));
+
+    // A VM with a requested node bypasses the selector
+    vm.spec.node = Some(String::from("b"));
+    let ctx = SchedulingContext::new(&vm, &snapshot, false).unwrap();
+    assert!(filter_node_selector(&ctx, &snapshot.nodes["b"]));
+}
 
-        return Ok(uncompliant);
+/// Only allow nodes whose CPU can run the VM CPU definition. If the VM is currently placed on
+/// another node, the node must also be a valid live migration target from there. Nodes which have
+/// not reported their capabilities are not filtered, nodes whose capabilities can not be parsed
+/// are removed.
+fn filter_cpu_compatibility(ctx: &SchedulingContext, node: &Node) -> bool {
+    if ctx.cpu.is_some() && ctx.invalid_capabilities.contains(&node.name_unchecked()) {
+        return false;
+    }
+    let (Some(cpu), Some(capabilities)) = (&ctx.cpu, ctx.capabilities.get(&node.name_unchecked()))
+    else {
+        return true;
+    };
+
+    let source = get_vm_node(ctx.vm)
+        .filter(|current_node| current_node != &node.name_unchecked())
+        .and_then(|current_node| ctx.capabilities.get(&current_node));
+
+    let result = match source {
+        Some(source) => capabilities.check_migration_from(source, cpu),
+        None => capabilities.check_cpu(cpu),
+    };
+    result.is_ok()
+}
+
+/// Only allow nodes whose hypervisor supports the VM machine type
+fn filter_machine_type(ctx: &SchedulingContext, node: &Node) -> bool {
+    if ctx.machine_type.is_some() && ctx.invalid_capabilities.contains(&node.name_unchecked()) {
+        return false;
+    }
+    let (Some(machine_type), Some(capabilities)) = (
+        &ctx.machine_type,
+        ctx.capabilities.get(&node.name_unchecked()),
+    ) else {
+        return true;
+    };
+    capabilities.supports_machine_type(machine_type)
+}
+
+#[cfg(test)]
+#[test]
+fn test_invalid_capabilities() {
+    let mut snapshot = test_snapshot(vec![test_node("a", &[]), test_node("b", &[])], vec![]);
+    snapshot.invalid_capabilities = BTreeSet::from([String::from("a")]);
+    let mut vm = test_vm("vm", &[], None);
+    vm.spec.cpu_model = Some(String::from("<cpu mode='host-model'/>"));
+
+    // Only the node with unparseable capabilities is removed
+    let ctx = SchedulingContext::new(&vm, &snapshot, false).unwrap();
+    assert!(!filter_cpu_compatibility(&ctx, &snapshot.nodes["a"]));
+    assert!(filter_cpu_compatibility(&ctx, &snapshot.nodes["b"]));
+
+    // An unparseable CPU definition only fails its own VM
+    vm.spec.cpu_model = Some(String::from("<cpu"));
+    assert!(matches!(
+        SchedulingContext::new(&vm, &snapshot, false),
+        Err(Error::InvalidResource(_))
+    ));
+}
+
+/// Only allow nodes with enough memory left under the cluster memory limit for the VM
+fn filter_memory_capacity(ctx: &SchedulingContext, node: &Node) -> bool {
+    ctx.missing_memory(node) == 0
+}
+
+/// Only allow nodes with enough hugepages of the requested size left for the VM memory
+fn filter_hugepages(ctx: &SchedulingContext, node: &Node) -> bool {
+    let Some(size) = &ctx.vm.spec.hugepages else {
+        return true;
+    };
+    let pages = ctx
+        .hugepages
+        .get(&node.name_unchecked())
+        .and_then(|hugepages| hugepages.get(size.name()))
+        .copied()
+        .unwrap_or(0);
+    let used: u64 = ctx
+        .vms_on_node(node)
+        .filter(|other| other.spec.hugepages.as_ref() == Some(size))
+        .map(vm_memory)
+        .sum();
+    used + vm_memory(ctx.vm) <= pages * size.size_kib() * 1024
+}
+
+/// Only allow nodes with enough free host CPUs to dedicate to the VM. Besides the CPUs the host
+/// controller has already allocated, the CPUs of dedicated VMs scheduled to the node but not yet
+/// started there are counted as used. Nodes which have not reported their topology can not pin
+/// CPUs and are removed.
+fn filter_dedicated_cpus(ctx: &SchedulingContext, node: &Node) -> bool {
+    if !ctx.vm.spec.has_dedicated_cpus() {
+        return true;
+    }
+    let node_name = node.name_unchecked();
+    let Some(capabilities) = ctx.capabilities.get(&node_name) else {
+        return false;
+    };
+    let usage = ctx.cpu_usage.get(&node_name).cloned().unwrap_or_default();
+
+    let vm_name = ctx.vm.name_prefixed_with_namespace();
+    let unavailable: BTreeSet<u32> = usage
+        .allocations
+        .iter()
+        .filter(|(name, _allocation)| **name != vm_name)
+        .flat_map(|(_name, allocation)| allocation.cpus.iter().copied())
+        .chain(usage.reserved.iter().copied())
+        .collect();
+    let pending: usize = ctx
+        .vms_on_node(node)
+        .filter(|other| other.spec.has_dedicated_cpus())
+        .filter(|other| {
+            !usage
+                .allocations
+                .contains_key(&other.name_prefixed_with_namespace())
+        })
+        .map(|other| other.cpus())
+        .sum();
+
+    let fits_node = capabilities
+        .allocate_cpus(&unavailable, ctx.vm.cpus() + pending, false)
+        .is_some();
+    let fits_cell = !ctx.vm.spec.numa_single_cell()
+        || capabilities
+            .allocate_cpus(&unavailable, ctx.vm.cpus(), true)
+            .is_some();
+    fits_node && fits_cell
+}
+
+/// Only allow nodes with free PCI devices matching the host device requests of the VM. Devices
+/// are first picked for the VMs scheduled to the node but not yet started there, as the host
+/// controller will pass devices through to them too.
+fn filter_host_devices(ctx: &SchedulingContext, node: &Node) -> bool {
+    if !ctx.vm.spec.has_host_devices() {
+        return true;
+    }
+    let requests = ctx.vm.spec.host_devices.clone().unwrap_or_default();
+    let Some(inventory) = ctx.pci_devices.get(&node.name_unchecked()) else {
+        return false;
+    };
+
+    let vm_name = ctx.vm.name_prefixed_with_namespace();
+    let mut unavailable: BTreeSet<String> = inventory
+        .allocations
+        .iter()
+        .filter(|(name, _addresses)| **name != vm_name)
+        .flat_map(|(_name, addresses)| addresses.iter().cloned())
+        .collect();
+    let pending = ctx.vms_on_node(node).filter(|other| {
+        other.spec.has_host_devices()
+            && !inventory
+                .allocations
+                .contains_key(&other.name_prefixed_with_namespace())
+    });
+    for other in pending {
+        let other_requests = other.spec.host_devices.clone().unwrap_or_default();
+        match pick_pci_devices(&inventory.devices, &unavailable, &other_requests) {
+            Some(addresses) => unavailable.extend(addresses),
+            None => return false,
+        }
+    }
+
+    pick_pci_devices(&inventory.devices, &unavailable, &requests).is_some()
+}
+
+/// Only allow nodes sharing a topology domain with the VMs matched by hard affinity terms. A term
+/// that matches no scheduled VM at all does not restrict placement, so that the first VM of a
+/// group can be placed.
+fn filter_vm_affinity(ctx: &SchedulingContext, node: &Node) -> bool {
+    if ctx.ignore_affinity || ctx.is_pinned() {
+        return true;
+    }
+    affinity_terms(ctx.vm)
+        .iter()
+        .filter(|term| is_required(term))
+        .all(|term| !ctx.any_matching_scheduled(term) || ctx.matching_vms_in_domain(term, node) > 0)
+}
+
+#[cfg(test)]
+#[test]
+fn test_filter_vm_affinity() {
+    use crate::crd::virtualmachine::Affinity;
+
+    let nodes = vec![
+        test_node("a", &[("rack", "1")]),
+        test_node("b", &[("rack", "1")]),
+        test_node("c", &[("rack", "2")]),
+    ];
+    let mut vm = test_vm("app", &[], None);
+    vm.spec.affinity = Some(Affinity {
+        vm_affinity: Some(vec![VmAffinityTerm {
+            match_labels: BTreeMap::from([(String::from("app"), String::from("db"))]),
+            topology_key: Some(String::from("rack")),
+            ..Default::default()
+        }]),
+        ..Default::default()
+    });
+
+    // The first VM of a group can go anywhere
+    let snapshot = test_snapshot(nodes.clone(), vec![test_vm("db", &[("app", "db")], None)]);
+    let ctx = SchedulingContext::new(&vm, &snapshot, false).unwrap();
+    assert!(nodes.iter().all(|node| filter_vm_affinity(&ctx, node)));
+
+    // Once the matched VM runs somewhere, only its rack is allowed
+    let snapshot = test_snapshot(
+        nodes.clone(),
+        vec![test_vm("db", &[("app", "db")], Some("a"))],
+    );
+    let ctx = SchedulingContext::new(&vm, &snapshot, false).unwrap();
+    assert!(filter_vm_affinity(&ctx, &nodes[0]));
+    assert!(filter_vm_affinity(&ctx, &nodes[1]));
+    assert!(!filter_vm_affinity(&ctx, &nodes[2]));
+
+    let ctx = SchedulingContext::new(&vm, &snapshot, true).unwrap();
+    assert!(filter_vm_affinity(&ctx, &nodes[2]));
+}
+
+/// Whether the hard anti-affinity terms of the VM itself allow the node
+fn own_anti_affinity_allows(ctx: &SchedulingContext, node: &Node) -> bool {
+    ctx.ignore_affinity
+        || ctx.is_pinned()
+        || anti_affinity_terms(ctx.vm)
+            .iter()
+            .filter(|term| is_required(term))
+            .all(|term| ctx.matching_vms_in_domain(term, node) == 0)
+}
+
+/// Remove nodes that are in the same topology domain as VMs matched by hard anti-affinity terms,
+/// either the terms of the VM or the terms of the VMs already scheduled there
+fn filter_vm_anti_affinity(ctx: &SchedulingContext, node: &Node) -> bool {
+    if ctx.ignore_affinity || ctx.is_pinned() {
+        return true;
+    }
+    own_anti_affinity_allows(ctx, node) && !ctx.repelled_in_domain(node)
+}
+
+#[cfg(test)]
+#[test]
+fn test_filter_vm_anti_affinity() {
+    use crate::crd::virtualmachine::Affinity;
+
+    let snapshot = test_snapshot(
+        vec![
+            test_node("a", &[]),
+            test_node("b", &[]),
+            test_node("c", &[]),
+        ],
+        vec![
+            test_vm("web-1", &[("app", "web")], Some("a")),
+            test_vm("legacy-1", &[(ANTI_AFFINITY_LABEL, "group")], Some("b")),
+        ],
+    );
+    let mut vm = test_vm("web-2", &[(ANTI_AFFINITY_LABEL, "group")], None);
+    vm.spec.affinity = Some(Affinity {
+        vm_anti_affinity: Some(vec![VmAffinityTerm {
+            match_labels: BTreeMap::from([(String::from("app"), String::from("web"))]),
+            ..Default::default()
+        }]),
+        ..Default::default()
+    });
+
+    let ctx = SchedulingContext::new(&vm, &snapshot, false).unwrap();
+    assert!(!filter_vm_anti_affinity(&ctx, &snapshot.nodes["a"]));
+    // The legacy label is a hard term against the VMs with the same value
+    assert!(!filter_vm_anti_affinity(&ctx, &snapshot.nodes["b"]));
+    assert!(filter_vm_anti_affinity(&ctx, &snapshot.nodes["c"]));
+
+    // Soft terms do not remove nodes
+    if let Some(terms) = vm
+        .spec
+        .affinity
+        .as_mut()
+        .and_then(|affinity| affinity.vm_anti_affinity.as_mut())
+    {
+        terms[0].required = Some(false);
+    }
+    let ctx = SchedulingContext::new(&vm, &snapshot, false).unwrap();
+    assert!(filter_vm_anti_affinity(&ctx, &snapshot.nodes["a"]));
+
+    // The hard terms of the VMs already scheduled keep the matched VMs away too
+    let mut db = test_vm("db-1", &[], Some("c"));
+    db.spec.affinity = Some(Affinity {
+        vm_anti_affinity: Some(vec![VmAffinityTerm {
+            match_labels: BTreeMap::from([(String::from("app"), String::from("web"))]),
+            ..Default::default()
+        }]),
+        ..Default::default()
+    });
+    let snapshot = test_snapshot(vec![test_node("a", &[]), test_node("c", &[])], vec![db]);
+    let vm = test_vm("web-3", &[("app", "web")], None);
+    let ctx = SchedulingContext::new(&vm, &snapshot, false).unwrap();
+    assert!(filter_vm_anti_affinity(&ctx, &snapshot.nodes["a"]));
+    assert!(!filter_vm_anti_affinity(&ctx, &snapshot.nodes["c"]));
+    assert!(!ctx.violates_placement_rules(&snapshot.nodes["c"]));
+}
+
+fn score_node_preferences(ctx: &SchedulingContext, node: &Node) -> i64 {
+    ctx.vm
+        .spec
+        .affinity
+        .as_ref()
+        .and_then(|affinity| affinity.node_preferences.as_ref())
+        .map(|preferences| {
+            preferences
+                .iter()
+                .filter(|preference| labels_match(node.labels(), &preference.match_labels))
+                .map(|preference| preference.weight)
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+fn score_vm_affinity(ctx: &SchedulingContext, node: &Node) -> i64 {
+    affinity_terms(ctx.vm)
+        .iter()
+        .filter(|term| !is_required(term) && ctx.matching_vms_in_domain(term, node) > 0)
+        .map(|term| term.weight.unwrap_or(DEFAULT_SOFT_WEIGHT))
+        .sum()
+}
+
+fn score_vm_anti_affinity(ctx: &SchedulingContext, node: &Node) -> i64 {
+    -anti_affinity_terms(ctx.vm)
+        .iter()
+        .filter(|term| !is_required(term) && ctx.matching_vms_in_domain(term, node) > 0)
+        .map(|term| term.weight.unwrap_or(DEFAULT_SOFT_WEIGHT))
+        .sum::<i64>()
+}
+
+#[cfg(test)]
+#[test]
+fn test_score_ordering() {
+    use crate::crd::virtualmachine::{Affinity, NodePreference};
+
+    let snapshot = test_snapshot(
+        vec![
+            test_node("preferred", &[("ssd", "true")]),
+            test_node("plain", &[]),
+            test_node("near-cache", &[]),
+            test_node("near-web", &[]),
+        ],
+        vec![
+            test_vm("cache", &[("app", "cache")], Some("near-cache")),
+            test_vm("web-1", &[("app", "web")], Some("near-web")),
+        ],
+    );
+    let soft = |app: &str, weight: i64| VmAffinityTerm {
+        match_labels: BTreeMap::from([(String::from("app"), String::from(app))]),
+        required: Some(false),
+        weight: Some(weight),
+        ..Default::default()
+    };
+    let mut vm = test_vm("web-2", &[], None);
+    vm.spec.affinity = Some(Affinity {
+        vm_affinity: Some(vec![soft("cache", 5)]),
+        vm_anti_affinity: Some(vec![soft("web", 3)]),
+        node_preferences: Some(vec![NodePreference {
+            match_labels: BTreeMap::from([(String::from("ssd"), String::from("true"))]),
+            weight: 10,
+        }]),
+    });
+
+    let ctx = SchedulingContext::new(&vm, &snapshot, false).unwrap();
+    let score = |node: &str| ctx.score(&snapshot.nodes[node]);
+    assert_eq!(score("preferred"), 10);
+    assert_eq!(score("near-cache"), 5);
+    assert_eq!(score("plain"), 0);
+    assert_eq!(score("near-web"), -3);
+    assert_eq!(run_stages(&ctx).selected.as_deref(), Some("preferred"));
+}
+
+/// Check for compliance with node selectors and hard anti-affinity rules at the current node
+#[instrument(skip(cache))]
+pub(crate) fn is_uncompliant(vm: &VirtualMachine, cache: &SchedulingCache) -> Result<bool, Error> {
+    let status = vm.try_status()?.clone();
+
+    if let (true, Some(current_node)) = (status.scheduled, status.node.as_ref()) {
+        let snapshot = cache.snapshot()?;
+        let ctx = SchedulingContext::new(vm, &snapshot, false)?;
+        if let Some(node) = ctx.nodes.get(current_node) {
+            return Ok(ctx.violates_placement_rules(node));
+        }
     }
 
     Ok(false)
@@ -100,101 +942,204 @@ pub async fn clear_successful_migration(
 
 /// Try to return the node the VM is scheduled to run on.
 /// If the VM has not yet been scheduled, return None
-fn get_vm_node(vm: &VirtualMachine) -> Option<String> {
+pub(crate) fn get_vm_node(vm: &VirtualMachine) -> Option<String> {
     vm.status.as_ref().and_then(|status| status.node.clone())
 }
 
-/// Return all nodes that have a VM scheduled with the given label
-#[instrument(skip(client))]
-async fn get_nodes_with_label_scheduled(
-    client: Client,
-    label: &str,
-    value: &str,
-) -> Result<Vec<String>, Error> {
-    let vms_scheduled = get_vms_with_label(client.clone(), label, value).await?;
-    let nodes_scheduled: Vec<String> = vms_scheduled.iter().filter_map(get_vm_node).collect();
-    Ok(nodes_scheduled)
+/// How a single node fared in the filter and score stages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NodeTrace {
+    pub node: String,
+    /// The first filter stage which removed the node, if any
+    pub rejected_by: Option<&'static str>,
+    /// Score of each score stage, empty for rejected nodes
+    pub scores: Vec<(&'static str, i64)>,
 }
 
-/// Remove nodes by the given names from the candidate list
-fn remove_candidate_nodes(candidates: &mut Vec<Node>, nodes_to_remove: &Vec<String>) {
-    for node_to_remove in nodes_to_remove {
-        candidates.retain(|candidate| &candidate.name_unchecked() != node_to_remove);
+impl NodeTrace {
+    pub fn total_score(&self) -> i64 {
+        self.scores.iter().map(|(_stage, score)| score).sum()
     }
 }
 
-/// Remove all nodes which have the maintenance annotation set
-fn remove_nodes_in_maintenance(candidates: &mut Vec<Node>) {
-    candidates.retain(|candidate| !candidate.in_maintenance_mode());
+/// Record of a scheduling decision: the outcome of every stage for every node
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ScheduleTrace {
+    pub nodes: Vec<NodeTrace>,
+    pub selected: Option<String>,
 }
 
-/// Remove all nodes which have the noschedule annotation
-fn remove_nodes_with_no_schedule(candidates: &mut Vec<Node>) {
-    candidates.retain(|candidate| candidate.allows_scheduling());
+impl ScheduleTrace {
+    /// One line summary, e.g. "0/3 nodes available: 2 memory_capacity, 1 maintenance"
+    pub fn summary(&self) -> String {
+        let mut rejections: BTreeMap<&str, usize> = BTreeMap::new();
+        for stage in self.nodes.iter().filter_map(|node| node.rejected_by) {
+            *rejections.entry(stage).or_default() += 1;
+        }
+        let available = self.nodes.len() - rejections.values().sum::<usize>();
+
+        let mut summary = format!("{available}/{} nodes available", self.nodes.len());
+        if !rejections.is_empty() {
+            let reasons: Vec<String> = rejections
+                .iter()
+                .map(|(stage, count)| format!("{count} {stage}"))
+                .collect();
+            summary = format!("{summary}: {}", reasons.join(", "));
+        }
+        if let Some(selected) = &self.selected {
+            summary = format!("{summary}, selected {selected}");
+        }
+        summary
+    }
 }
 
-const ANTI_AFFINITY_LABEL: &str = "antiAffinity";
+#[cfg(test)]
+#[test]
+fn test_schedule_trace_summary() {
+    let trace = ScheduleTrace {
+        nodes: vec![
+            NodeTrace {
+                node: String::from("a"),
+                rejected_by: Some("maintenance"),
+                scores: vec![],
+            },
+            NodeTrace {
+                node: String::from("b"),
+                rejected_by: None,
+                scores: vec![("vm_affinity", 1)],
+            },
+        ],
+        selected: Some(String::from("b")),
+    };
+    assert_eq!(
+        trace.summary(),
+        "1/2 nodes available: 1 maintenance, selected b"
+    );
+}
+
+impl std::fmt::Display for ScheduleTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.summary())?;
+        for node in &self.nodes {
+            match node.rejected_by {
+                Some(stage) => writeln!(f, "  {}: rejected by {stage}", node.node)?,
+                None => {
+                    let scores: Vec<String> = node
+                        .scores
+                        .iter()
+                        .map(|(stage, score)| format!("{stage}={score}"))
+                        .collect();
+                    let selected = if self.selected.as_ref() == Some(&node.node) {
+                        " <- selected"
+                    } else {
+                        ""
+                    };
+                    writeln!(
+                        f,
+                        "  {}: score {} ({}){selected}",
+                        node.node,
+                        node.total_score(),
+                        scores.join(", ")
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run the filter and score stages over all nodes and pick the best candidate. Ties are broken
+/// by the least allocated vCPUs and then randomly to spread VMs over equally good nodes.
+fn run_stages(ctx: &SchedulingContext) -> ScheduleTrace {
+    let nodes: Vec<NodeTrace> = ctx
+        .nodes
+        .values()
+        .map(|node| {
+            let rejected_by = FILTERS
+                .iter()
+                .find(|(_name, filter)| !filter(ctx, node))
+                .map(|(name, _filter)| *name);
+            let scores = match rejected_by {
+                Some(_) => vec![],
+                None => SCORERS
+                    .iter()
+                    .map(|(name, scorer)| (*name, scorer(ctx, node)))
+                    .collect(),
+            };
+            NodeTrace {
+                node: node.name_unchecked(),
+                rejected_by,
+                scores,
+            }
+        })
+        .collect();
+
+    let candidates: Vec<&NodeTrace> = nodes
+        .iter()
+        .filter(|node| node.rejected_by.is_none())
+        .collect();
+    let best_score = candidates.iter().map(|node| node.total_score()).max();
+    let best: Vec<&Node> = candidates
+        .iter()
+        .filter(|node| Some(node.total_score()) == best_score)
+        .filter_map(|node| ctx.nodes.get(&node.node))
+        .collect();
+
+    let least_allocated = best.iter().map(|node| ctx.allocated_cpus(node)).min();
+    let best: Vec<&Node> = best
+        .into_iter()
+        .filter(|node| Some(ctx.allocated_cpus(node)) == least_allocated)
+        .collect();
+
+    let selected = best
+        .choose(&mut rand::thread_rng())
+        .map(|node| node.name_unchecked());
+    ScheduleTrace { nodes, selected }
+}
+
+/// Run the scheduler for the VM without committing to the decision, returning the full trace
+pub(crate) fn explain(
+    vm: &VirtualMachine,
+    snapshot: &ClusterSnapshot,
+    ignore_affinity: bool,
+) -> Result<ScheduleTrace, Error> {
+    let ctx = SchedulingContext::new(vm, snapshot, ignore_affinity)?;
+    let trace = run_stages(&ctx);
+    debug!("scheduler: {}: {trace}", vm.name_prefixed_with_namespace());
+    Ok(trace)
+}
 
 /// Try to schedule the VM to some node according to rules. Returns either a node-object, or an
-/// Error if no node meets the requirements.
+/// Error explaining why no node meets the requirements.
 ///
 /// ignore_affinity allows temporarily bypassing anti-affinity rules which can be useful in case
 /// of e.g. host maintenance
-#[instrument(skip(client))]
-pub(crate) async fn schedule(
+#[instrument(skip(snapshot))]
+pub(crate) fn schedule(
     vm: &VirtualMachine,
+    snapshot: &ClusterSnapshot,
     ignore_affinity: bool,
-    client: Client,
 ) -> Result<Node, Error> {
-    let node_api: Api<Node> = Api::all(client.clone());
-
-    // Get all nodes
-    let mut candidates = node_api.list_default().await?;
-
-    // Remove nodes in maintenance
-    remove_nodes_in_maintenance(&mut candidates.items);
-
-    // Node specified in VM spec, try to use that or fail if not found
-    if let Some(requested_node) = &vm.spec.node {
-        if let Some(node) = candidates.iter().find(|candidate| {
-            candidate.metadata.name.as_ref().unwrap_or(&String::new()) == requested_node
-        }) {
-            return Ok(node.clone());
-        } else {
-            return Err(Error::ScheduleFailed(format!(
-                "{} requested node {} which is not available",
-                vm.name_unchecked(),
-                requested_node
-            )));
-        }
-    }
-
-    // Do not automatically schedule to nodes with no-schedule annotation
-    remove_nodes_with_no_schedule(&mut candidates.items);
-
-    // Remove a node we are migrating away from (most of the time same as a node in maintenance)
-    if let Some(source_node) = vm.migration_requested_from() {
-        remove_candidate_nodes(&mut candidates.items, &vec![source_node.clone()])
-    }
-
-    if !ignore_affinity {
-        // Remove nodes that already have VMs in the same anti-affinity group
-        let labels = vm.labels();
-        let anti_affinity_group = labels.get(ANTI_AFFINITY_LABEL);
-        if let Some(anti_affinity_group) = anti_affinity_group {
-            let blocked_nodes = get_nodes_with_label_scheduled(
-                client.clone(),
-                ANTI_AFFINITY_LABEL,
-                anti_affinity_group,
-            )
-                .await?;
-            remove_candidate_nodes(&mut candidates.items, &blocked_nodes);
-        }
-    }
+    let trace = explain(vm, snapshot, ignore_affinity)?;
 
-    if let Some(node) = candidates.items.choose(&mut rand::thread_rng()) {
+    if let Some(node) = trace
+        .selected
+        .as_ref()
+        .and_then(|selected| snapshot.nodes.get(selected))
+    {
         Ok(node.clone())
+    } else if let Some(requested_node) = &vm.spec.node {
+        Err(Error::ScheduleFailed(format!(
+            "{} requested node {} which is not available: {}",
+            vm.name_unchecked(),
+            requested_node,
+            trace.summary()
+        )))
     } else {
-        Err(Error::ScheduleFailed(vm.metadata.name.clone().unwrap()))
+        Err(Error::ScheduleFailed(format!(
+            "{}: {}",
+            vm.name_unchecked(),
+            trace.summary()
+        )))
     }
 }
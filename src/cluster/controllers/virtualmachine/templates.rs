@@ -0,0 +1,120 @@
+use kube::{Api, Client};
+use tracing::{info, instrument};
+
+use crate::crd::instancetype::InstanceType;
+use crate::crd::virtualmachine::{
+    AppliedTemplate, TemplateValues, VirtualMachine, VirtualMachineStatus, set_vm_status,
+};
+use crate::crd::virtualmachinetemplate::VirtualMachineTemplate;
+use crate::errors::Error;
+use crate::utils::traits::kube::{ExtendResource, TryStatus};
+
+/// Collect the values of the instance type and template of the VM, in order of precedence:
+/// the instance type of the VM, the template, then the instance type of the template
+async fn resolve_values(vm: &VirtualMachine, client: Client) -> Result<TemplateValues, Error> {
+    let instance_types: Api<InstanceType> = Api::all(client.clone());
+    let templates: Api<VirtualMachineTemplate> =
+        Api::namespaced(client.clone(), &vm.namespace_unchecked());
+
+    let mut values = TemplateValues::default();
+    if let Some(name) = &vm.spec.instance_type {
+        values = values.or(instance_types.get(name).await?.spec.values());
+    }
+    if let Some(name) = &vm.spec.template {
+        let template = templates.get(name).await?;
+        values = values.or(template.spec.values);
+        if let Some(name) = &template.spec.instance_type {
+            values = values.or(instance_types.get(name).await?.spec.values());
+        }
+    }
+    Ok(values)
+}
+
+/// Record the values of the instance type and template of a VM in its status when it starts
+/// referencing them or switches to other ones. The spec is left as it is, the effective values
+/// are read through the getters of the VM. Fails for VMs which end up without cpus or memory.
+#[instrument(skip(client))]
+pub async fn resolve_template(vm: &mut VirtualMachine, client: Client) -> Result<(), Error> {
+    let status = vm.try_status()?.clone();
+    let references = (vm.spec.template.clone(), vm.spec.instance_type.clone());
+    let up_to_date = match &status.template {
+        Some(applied) => (applied.template.clone(), applied.instance_type.clone()) == references,
+        None => references == (None, None),
+    };
+
+    if !up_to_date {
+        let values = resolve_values(vm, client.clone()).await?;
+        info!(
+            "Resolved {} against template {:?} and instance type {:?}",
+            vm.name_prefixed_with_namespace(),
+            references.0,
+            references.1
+        );
+        let template = (references != (None, None)).then_some(AppliedTemplate {
+            template: references.0,
+            instance_type: references.1,
+            values,
+        });
+        let new_status = VirtualMachineStatus { template, ..status };
+        set_vm_status(vm, new_status.clone(), client).await?;
+        vm.status = Some(new_status);
+    }
+
+    if vm.cpus() == 0 || vm.memory().is_empty() {
+        return Err(Error::TemplateResolution(format!(
+            "{} has no cpus or memory set by its spec, template or instance type",
+            vm.name_prefixed_with_namespace()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn test_effective_values() {
+    use crate::crd::virtualmachine::v1beta3::VirtualMachineSpec;
+
+    let mut vm = VirtualMachine::new(
+        "vm",
+        VirtualMachineSpec {
+            memory: String::from("8G"),
+            template: Some(String::from("small")),
+            ..Default::default()
+        },
+    );
+    assert_eq!(vm.cpus(), 0);
+    vm.status = Some(VirtualMachineStatus {
+        scheduled: false,
+        running: false,
+        migration_pending: false,
+        node: None,
+        domain_name: String::new(),
+        ip_addresses: None,
+        ip_addresses_string: None,
+        networks: vec![],
+        conditions: vec![],
+        ovn_ip_addresses: vec![],
+        guest: None,
+        applied_credentials: None,
+        template: Some(AppliedTemplate {
+            template: Some(String::from("small")),
+            instance_type: None,
+            values: TemplateValues {
+                cpus: Some(2),
+                memory: Some(String::from("4G")),
+                machine_type: Some(String::from("pc-q35-rhel8.6.0")),
+                ..Default::default()
+            },
+        }),
+    });
+
+    // Values set in the spec win over the template
+    assert_eq!(vm.cpus(), 2);
+    assert_eq!(vm.memory(), "8G");
+    assert_eq!(
+        vm.machine_type().map(String::as_str),
+        Some("pc-q35-rhel8.6.0")
+    );
+    assert_eq!(vm.cpu_model(), None);
+    assert!(!vm.compatibility_mode());
+}
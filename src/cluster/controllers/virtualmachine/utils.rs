@@ -1,33 +1,15 @@
+use crate::crd::ceph::Volume;
 use crate::crd::network::{Network, NetworkType};
 use crate::crd::virtualmachine::{
     NetworkAttachment, VirtualMachine, VirtualMachineStatus, set_vm_status,
 };
 use crate::errors::Error;
-use crate::utils::traits::kube::{ExtendResource, TryStatus};
-use kube::{Api, Client};
+use crate::utils::libvirt_storage::{StorageType, parse_storage_location};
+use crate::utils::traits::kube::{ApiExt, ExtendResource, TryStatus};
+use kube::{Api, Client, Resource, ResourceExt};
 use serde_json::json;
-use sha2::{Digest, Sha256};
+use std::collections::HashSet;
 use tracing::instrument;
-use uuid::Uuid;
-
-const PREFIX: &str = "52:54:00";
-
-/// Takes a VM name and a network interface specification and generates
-/// a MAC address based on a hash of the information
-pub fn generate_mac_address(vm_name: &str, nic: &NetworkAttachment, index: usize) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(vm_name);
-    let network = nic.name.clone();
-    let bridge = nic.bridge.clone();
-    hasher.update(
-        network
-            .or(bridge)
-            .expect("bridge or network name should be set"),
-    );
-    hasher.update(vec![index as u8]);
-    let hash = hasher.finalize();
-    format!("{}:{:x}:{:x}:{:x}", PREFIX, hash[29], hash[30], hash[31])
-}
 
 /// Search for network attachment with the same target (network name or bridge)
 fn find_matching_network<'a>(
@@ -44,17 +26,23 @@ fn find_matching_network<'a>(
     }
 }
 
-/// For every network in the VM spec, add MAC addresses and OVN port IDs where necessary and not
-/// specified. Save the extra information in the status subresource
+/// For every network in the VM spec, save the MAC address, the OVN port ID and the VLAN of the
+/// network in the status subresource. The MAC addresses and OVN port IDs are filled in by the
+/// mutating webhook, VMs created before it keep the ones already in their status.
 #[instrument(skip(client))]
 pub async fn fill_nics(vm: &mut VirtualMachine, client: Client) -> Result<(), Error> {
     let network_api: Api<Network> = Api::namespaced(client.clone(), &vm.namespace_unchecked());
     let vm_name = vm.name_prefixed_with_namespace();
+    let missing = |what: &str| {
+        Error::InvalidResource(format!(
+            "{vm_name} has an interface without {what}, is the mutating webhook running?"
+        ))
+    };
 
     let status_networks = vm.try_status()?.networks.clone();
     let mut new_status_networks = Vec::new();
 
-    for (index, nic_spec) in vm.spec.networks.iter_mut().enumerate() {
+    for nic_spec in &vm.spec.networks {
         let mut nic_status = find_matching_network(&status_networks, nic_spec)
             .cloned()
             .unwrap_or(NetworkAttachment {
@@ -66,11 +54,10 @@ pub async fn fill_nics(vm: &mut VirtualMachine, client: Client) -> Result<(), Er
         // Update number of queues
         nic_status.queues = nic_spec.queues;
 
-        // Generate a new MAC address if not set
         if nic_spec.mac_address.is_some() {
             nic_status.mac_address.clone_from(&nic_spec.mac_address);
         } else if nic_status.mac_address.is_none() {
-            nic_status.mac_address = Some(generate_mac_address(&vm_name, nic_spec, index));
+            return Err(missing("a MAC address"));
         }
 
         // Using a named/managed network
@@ -78,17 +65,11 @@ pub async fn fill_nics(vm: &mut VirtualMachine, client: Client) -> Result<(), Er
             let network = network_api.get(name).await?;
 
             match network.spec.network_type {
-                // Generate a new OVN port ID if not set and using OVN network
                 None | Some(NetworkType::Ovn) => {
                     if nic_spec.ovn_id.is_some() {
                         nic_status.ovn_id.clone_from(&nic_spec.ovn_id);
                     } else if nic_status.ovn_id.is_none() {
-                        nic_status.ovn_id = Some(
-                            Uuid::new_v4()
-                                .hyphenated()
-                                .encode_lower(&mut Uuid::encode_buffer())
-                                .into(),
-                        );
+                        return Err(missing("an OVN port ID"));
                     }
                 }
 
@@ -114,22 +95,44 @@ pub async fn fill_nics(vm: &mut VirtualMachine, client: Client) -> Result<(), Er
             ..vm.try_status()?.clone()
         };
         set_vm_status(vm, new_status, client.clone()).await?;
+
+        // Continue with the updated status
+        let vms: Api<VirtualMachine> = Api::namespaced(client, &vm.namespace_unchecked());
+        *vm = vms.get(&vm.name_any()).await?;
     }
-    vm.commit(client, "cluster-manager.libvirt").await?;
     Ok(())
 }
 
-/// Create a libvirt UUID for a VM if not yet set
+/// Make the VM an owner of the Volumes attached with delete_with_vm, so that they are garbage
+/// collected together with it. Volumes detached or no longer deleted with the VM are released.
 #[instrument(skip(client))]
-pub async fn fill_uuid(vm: &mut VirtualMachine, client: Client) -> Result<(), Error> {
-    if vm.spec.uuid.is_none() {
-        vm.spec.uuid = Some(
-            Uuid::new_v4()
-                .hyphenated()
-                .encode_lower(&mut Uuid::encode_buffer())
-                .into(),
-        );
-        vm.commit(client, "cluster-manager.libvirt").await?;
+pub async fn own_volumes(vm: &VirtualMachine, client: Client) -> Result<(), Error> {
+    let volumes: Api<Volume> = Api::namespaced(client.clone(), &vm.namespace_unchecked());
+    let owner = vm.owner_ref(&()).expect("VM should have a name and uid");
+
+    let mut owned = HashSet::new();
+    for attachment in &vm.spec.volumes {
+        if let (StorageType::Ceph, name) = parse_storage_location(&attachment.name)? {
+            if attachment.delete_with_vm.unwrap_or(false) {
+                owned.insert(name);
+            }
+        }
+    }
+
+    for mut volume in volumes.list_default().await? {
+        let wanted = owned.contains(&volume.name_any());
+        let references = volume.owner_references_mut();
+        let is_owner = references
+            .iter()
+            .any(|reference| reference.uid == owner.uid);
+        match (wanted, is_owner) {
+            (true, false) => references.push(owner.clone()),
+            (false, true) => references.retain(|reference| reference.uid != owner.uid),
+            _ => continue,
+        }
+        volume
+            .commit(client.clone(), "cluster-manager.libvirt")
+            .await?;
     }
     Ok(())
 }
@@ -1,34 +1,44 @@
-use crate::cluster::controllers::virtualmachine::scheduling;
+use crate::cluster::controllers::virtualmachine::cache::SchedulingCache;
 use crate::cluster::controllers::virtualmachine::scheduling::{
     clear_successful_migration, is_uncompliant, migration_requested,
 };
-use crate::cluster::controllers::virtualmachine::utils::{fill_nics, fill_uuid};
+use crate::cluster::controllers::virtualmachine::templates::resolve_template;
+use crate::cluster::controllers::virtualmachine::utils::{fill_nics, own_volumes};
+use crate::cluster::controllers::virtualmachine::{preemption, scheduling};
 use crate::crd::virtualmachine::{VirtualMachine, VirtualMachineStatus, set_vm_status};
 use crate::errors::Error;
 use crate::ok_and_requeue;
-use crate::utils::resource_controller::{DefaultState, ResourceControllerBuilder};
+use crate::utils::events::EventRecorder;
+use crate::utils::resource_controller::ResourceControllerBuilder;
 use crate::utils::strings::field_manager;
 use crate::utils::traits::kube::{ExtendResource, TryStatus};
 use kube::Client;
 use kube::runtime::controller::Action;
 use lazy_static::lazy_static;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use tokio::time::Duration;
-use tracing::{Instrument, info, info_span, instrument};
+use tracing::{info, instrument, warn};
+
+const SCHEDULED_CONDITION: &str = "Scheduled";
 
 lazy_static! {
     static ref FIELD_MANAGER: String = field_manager("vm");
-    static ref SCHEDULE_MUTEX: Mutex<()> = Mutex::new(());
+}
+
+/// State available for the reconcile functions of the VM controller
+pub struct State {
+    pub client: Client,
+    pub cache: Arc<SchedulingCache>,
+    pub recorder: EventRecorder,
 }
 
 #[instrument(skip(_ctx))]
-async fn delete_fn(_vm: Arc<VirtualMachine>, _ctx: Arc<DefaultState>) -> Result<Action, Error> {
+async fn delete_fn(_vm: Arc<VirtualMachine>, _ctx: Arc<State>) -> Result<Action, Error> {
     Ok(Action::await_change())
 }
 
 #[instrument(skip(ctx))]
-async fn create_fn(vm: Arc<VirtualMachine>, ctx: Arc<DefaultState>) -> Result<Action, Error> {
+async fn create_fn(vm: Arc<VirtualMachine>, ctx: Arc<State>) -> Result<Action, Error> {
     let client = ctx.client.clone();
     let mut vm = vm.as_ref().to_owned();
     let name = vm.name_prefixed_with_namespace();
@@ -36,10 +46,11 @@ async fn create_fn(vm: Arc<VirtualMachine>, ctx: Arc<DefaultState>) -> Result<Ac
 
     initialize_status(&client, &mut vm, &name).await?;
 
+    resolve_template(&mut vm, client.clone()).await?;
     fill_nics(&mut vm, client.clone()).await?;
-    fill_uuid(&mut vm, client.clone()).await?;
+    own_volumes(&vm, client.clone()).await?;
 
-    scheduling_and_migrations(client, &mut vm, &name).await?;
+    scheduling_and_migrations(client, &ctx.cache, &ctx.recorder, &mut vm, &name).await?;
 
     info!("libvirt: updated: {}", name);
     ok_and_requeue!(600)
@@ -63,6 +74,11 @@ async fn initialize_status(
                 ip_addresses: None,
                 ip_addresses_string: None,
                 networks: vec![],
+                conditions: vec![],
+                ovn_ip_addresses: vec![],
+                guest: None,
+                applied_credentials: None,
+                template: None,
             },
             client.clone(),
         )
@@ -71,55 +87,129 @@ async fn initialize_status(
     Ok(())
 }
 
-#[instrument(skip(client))]
+#[instrument(skip(client, cache, recorder))]
 async fn scheduling_and_migrations(
     client: Client,
+    cache: &SchedulingCache,
+    recorder: &EventRecorder,
     vm: &mut VirtualMachine,
     name: &str,
 ) -> Result<(), Error> {
-    let mut status = vm.try_status()?.clone();
+    let status = vm.try_status()?.clone();
+
+    // VMs with host devices can not be live migrated, once placed they stay on their node
+    let stays_on_node = status.scheduled && vm.spec.has_host_devices();
+    if stays_on_node && migration_requested(vm) {
+        warn!(
+            "Ignoring the migration request of {name}: VMs with host devices can not be live migrated"
+        );
+    }
 
     // Check if we have a pending migration request
-    let migration_required = migration_requested(vm);
+    let migration_required = !stays_on_node && migration_requested(vm);
 
     // Check if we are non-compliant with anti-affinity groups
-    let reschedule_required = is_uncompliant(vm, client.clone()).await?;
+    let reschedule_required = !stays_on_node && is_uncompliant(vm, cache)?;
 
     if !status.scheduled || migration_required || reschedule_required {
-        let _mutex = SCHEDULE_MUTEX
-            .lock()
-            .instrument(info_span!("wait for scheduler mutex"))
-            .await;
-        info!("libvirt: Acquired mutex to schedule: {}", name);
-
-        // Schedule normally
-        let schedule_result = scheduling::schedule(vm, false, client.clone()).await;
-        // If scheduling failed and we have requested a migration, allow bypassing of affinity
-        // so that we can temporarily remove a hypervisor when N(affinity group) == N(hypervisors)
-        let node = if migration_required && schedule_result.is_err() {
-            scheduling::schedule(vm, true, client.clone()).await?
-        } else {
-            schedule_result?
+        // The decision is reserved in the cache, so that VMs reconciled at the same time are not
+        // placed against stale allocations while the status update is in flight
+        let decision = cache.place(|snapshot| {
+            // Schedule normally
+            let schedule_result = scheduling::schedule(vm, snapshot, false);
+            // If scheduling failed and we have requested a migration, allow bypassing of affinity
+            // so that we can temporarily remove a hypervisor when N(affinity group) == N(hypervisors)
+            let node = if migration_required && schedule_result.is_err() {
+                scheduling::schedule(vm, snapshot, true)?
+            } else {
+                schedule_result?
+            };
+
+            let mut new_status = status.clone();
+            new_status.node = Some(node.metadata.name.expect("Unknown node name"));
+            new_status.scheduled = true;
+            if migration_required {
+                new_status.migration_pending = true;
+            }
+
+            let mut placed = vm.clone();
+            placed.status = Some(new_status.clone());
+            Ok((new_status, vec![placed]))
+        });
+
+        let decision = match decision {
+            // No room anywhere, evict lower priority VMs if the VM is allowed to
+            Err(Error::ScheduleFailed(reason)) if !status.scheduled && vm.spec.may_preempt() => {
+                let preempted = preemption::preempt(vm, cache, client.clone(), recorder).await;
+                if preempted.is_err() {
+                    // Some victims may have been written already, but the VM is not placed
+                    cache.release(vm);
+                }
+                match preempted? {
+                    Some(node) => Ok(VirtualMachineStatus {
+                        node: node.metadata.name,
+                        scheduled: true,
+                        ..status.clone()
+                    }),
+                    None => Err(Error::ScheduleFailed(reason)),
+                }
+            }
+            decision => decision,
         };
-        status.node = Some(node.metadata.name.expect("Unknown node name"));
-        status.scheduled = true;
 
-        if migration_required {
-            status.migration_pending = true;
-        }
+        let mut new_status = match decision {
+            Ok(new_status) => new_status,
+            Err(Error::ScheduleFailed(reason)) => {
+                // Tell the user why, a VM which is already placed keeps running where it is
+                let mut failed_status = status.clone();
+                let (condition_status, condition_reason) = if status.scheduled {
+                    (true, "RescheduleFailed")
+                } else {
+                    (false, "Unschedulable")
+                };
+                if failed_status.set_condition(
+                    SCHEDULED_CONDITION,
+                    condition_status,
+                    condition_reason,
+                    &reason,
+                ) {
+                    set_vm_status(vm, failed_status, client.clone()).await?;
+                }
+                return Err(Error::ScheduleFailed(reason));
+            }
+            Err(e) => return Err(e),
+        };
+        info!("libvirt: scheduled {} to {:?}", name, new_status.node);
+        let message = format!("Scheduled to {}", new_status.node.as_deref().unwrap_or("?"));
+        new_status.set_condition(SCHEDULED_CONDITION, true, "Scheduled", &message);
 
-        // Status must be updated before we release the scheduling mutex
-        set_vm_status(vm, status, client.clone()).await?;
+        if let Err(e) = set_vm_status(vm, new_status, client.clone()).await {
+            cache.release(vm);
+            return Err(e);
+        }
+        let reason = if migration_required {
+            "MigrationScheduled"
+        } else if status.scheduled {
+            "Rescheduled"
+        } else {
+            "Scheduled"
+        };
+        recorder.normal(vm, reason, "Schedule", message).await;
     }
 
     clear_successful_migration(vm, client.clone(), &FIELD_MANAGER).await?;
     Ok(())
 }
 
-pub async fn create(client: Client) -> Result<(), Error> {
+pub async fn create(client: Client, cache: Arc<SchedulingCache>) -> Result<(), Error> {
     info!("libvirt: Starting vm controller");
-    ResourceControllerBuilder::new(client)
-        .with_default_state()
+    cache.wait_ready().await;
+    ResourceControllerBuilder::new(client.clone())
+        .with_state(State {
+            recorder: EventRecorder::new(client.clone()),
+            client,
+            cache,
+        })
         .with_default_error_policy()
         .with_functions(create_fn, delete_fn)
         .run()
@@ -0,0 +1,343 @@
+use kube::runtime::controller::Action;
+use kube::{
+    Client, Resource, ResourceExt,
+    api::{Api, DeleteParams, ListParams, PostParams},
+};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use tokio::time::Duration;
+use tracing::{info, instrument};
+
+use crate::crd::ceph::{Volume, VolumeSpec};
+use crate::crd::virtualmachine::v1beta3::VirtualMachineSpec;
+use crate::crd::virtualmachine::{VirtualMachine, VmAffinityTerm, VolumeAttachment};
+use crate::crd::virtualmachinepool::{
+    PoolVolumeTemplate, VirtualMachinePool, VirtualMachinePoolSpec, VirtualMachinePoolStatus,
+};
+use crate::errors::Error;
+use crate::labels_and_annotations::{POOL_INDEX_LABEL, POOL_LABEL, POOL_TEMPLATE_HASH_LABEL};
+use crate::utils::resource_controller::{DefaultState, ResourceControllerBuilder};
+use crate::utils::traits::kube::ExtendResource;
+use crate::{create_set_status_namespaced, ok_and_requeue, ok_no_requeue};
+
+const DEFAULT_MAX_UNAVAILABLE: usize = 1;
+
+create_set_status_namespaced!(
+    VirtualMachinePool,
+    VirtualMachinePoolStatus,
+    set_pool_status
+);
+
+/// Hash of everything the replicas are created from, to tell the outdated replicas apart
+fn template_hash(spec: &VirtualMachinePoolSpec) -> String {
+    let template = json!({
+        "labels": spec.labels,
+        "template": spec.template,
+        "volumeTemplates": spec.volume_templates,
+    });
+    Sha256::digest(template.to_string())
+        .iter()
+        .take(5)
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn replica_name(pool: &str, index: usize) -> String {
+    format!("{pool}-{index}")
+}
+
+fn volume_name(replica: &str, template: &PoolVolumeTemplate) -> String {
+    format!("{replica}-{}", template.name)
+}
+
+/// Index of the replica a VM or Volume of the pool belongs to
+fn replica_index(resource: &impl ResourceExt) -> Option<usize> {
+    resource.labels().get(POOL_INDEX_LABEL)?.parse().ok()
+}
+
+/// Spec of a replica: the template with the volumes of the replica attached first, and a soft
+/// anti-affinity term spreading the replicas over the nodes
+fn replica_spec(pool: &VirtualMachinePool, replica: &str) -> VirtualMachineSpec {
+    let mut spec = pool.spec.template.clone();
+    // Every replica gets an identity of its own
+    spec.uuid = None;
+
+    let pool_volumes = pool
+        .spec
+        .volume_templates
+        .iter()
+        .map(|template| VolumeAttachment {
+            name: volume_name(replica, template),
+            ..Default::default()
+        });
+    spec.volumes = pool_volumes.chain(spec.volumes).collect();
+
+    let mut affinity = spec.affinity.unwrap_or_default();
+    affinity
+        .vm_anti_affinity
+        .get_or_insert_with(Vec::new)
+        .push(VmAffinityTerm {
+            match_labels: BTreeMap::from([(String::from(POOL_LABEL), pool.name_any())]),
+            required: Some(false),
+            ..VmAffinityTerm::default()
+        });
+    spec.affinity = Some(affinity);
+    spec
+}
+
+#[cfg(test)]
+#[test]
+fn test_replica_spec() {
+    let mut pool = VirtualMachinePool::new(
+        "web",
+        VirtualMachinePoolSpec {
+            replicas: 3,
+            template: VirtualMachineSpec {
+                cpus: 2,
+                uuid: Some(String::from("8a9e8bbc-6b1f-4f4e-9d0c-1f6e3c5a7d21")),
+                volumes: vec![VolumeAttachment {
+                    name: String::from("shared-iso"),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            volume_templates: vec![PoolVolumeTemplate {
+                name: String::from("root"),
+                size: String::from("10G"),
+                image: Some(String::from("ubuntu")),
+            }],
+            ..Default::default()
+        },
+    );
+    pool.metadata.namespace = Some(String::from("test"));
+
+    let spec = replica_spec(&pool, &replica_name("web", 1));
+    assert_eq!(spec.uuid, None);
+    assert_eq!(
+        spec.volumes,
+        vec![
+            VolumeAttachment {
+                name: String::from("web-1-root"),
+                ..Default::default()
+            },
+            VolumeAttachment {
+                name: String::from("shared-iso"),
+                ..Default::default()
+            }
+        ]
+    );
+    let terms = spec.affinity.unwrap().vm_anti_affinity.unwrap();
+    assert_eq!(terms[0].match_labels[POOL_LABEL], "web");
+    assert_eq!(terms[0].required, Some(false));
+
+    let hash = template_hash(&pool.spec);
+    assert_eq!(hash.len(), 10);
+    pool.spec.replicas = 5;
+    assert_eq!(template_hash(&pool.spec), hash);
+    pool.spec.template.cpus = 4;
+    assert_ne!(template_hash(&pool.spec), hash);
+}
+
+fn replica_labels(pool: &VirtualMachinePool, index: usize, hash: &str) -> BTreeMap<String, String> {
+    let mut labels = pool.spec.labels.clone();
+    labels.insert(String::from(POOL_LABEL), pool.name_any());
+    labels.insert(String::from(POOL_INDEX_LABEL), index.to_string());
+    labels.insert(String::from(POOL_TEMPLATE_HASH_LABEL), hash.to_string());
+    labels
+}
+
+/// Create the volumes of a replica which do not exist yet, then the VM itself. Both are owned by
+/// the pool, so that they are garbage collected with it. A volume still being deleted can not be
+/// reused, the replica is created once it is gone.
+#[instrument(skip(pool, existing_volumes, client))]
+async fn create_replica(
+    pool: &VirtualMachinePool,
+    index: usize,
+    hash: &str,
+    existing_volumes: &[&Volume],
+    client: Client,
+) -> Result<(), Error> {
+    let namespace = pool.namespace_unchecked();
+    let replica = replica_name(&pool.name_any(), index);
+    let labels = replica_labels(pool, index, hash);
+    let owner = pool
+        .controller_owner_ref(&())
+        .expect("pool should have a name and uid");
+
+    let volumes: Api<Volume> = Api::namespaced(client.clone(), &namespace);
+    for template in &pool.spec.volume_templates {
+        let name = volume_name(&replica, template);
+        match existing_volumes
+            .iter()
+            .find(|volume| volume.name_any() == name)
+        {
+            Some(volume) if volume.meta().deletion_timestamp.is_some() => {
+                info!("Waiting for volume {namespace}/{name} to be deleted to create {replica}");
+                return Ok(());
+            }
+            Some(_volume) => continue,
+            None => {}
+        }
+        let mut volume = Volume::new(
+            &name,
+            VolumeSpec {
+                size: template.size.clone(),
+                // Images are stored prefixed with their namespace
+                template: template
+                    .image
+                    .as_ref()
+                    .map(|image| format!("{namespace}-{image}")),
+            },
+        );
+        volume.metadata.labels = Some(labels.clone());
+        volume.metadata.owner_references = Some(vec![owner.clone()]);
+        volumes.create(&PostParams::default(), &volume).await?;
+    }
+
+    let mut vm = VirtualMachine::new(&replica, replica_spec(pool, &replica));
+    vm.metadata.labels = Some(labels);
+    vm.metadata.owner_references = Some(vec![owner]);
+    let vms: Api<VirtualMachine> = Api::namespaced(client, &namespace);
+    vms.create(&PostParams::default(), &vm).await?;
+    info!("Created replica {namespace}/{replica}");
+    Ok(())
+}
+
+/// Delete a VM or Volume of the pool unless it is already being deleted
+async fn delete_once<K>(api: &Api<K>, resource: &K) -> Result<(), Error>
+where
+    K: Resource + Clone + DeserializeOwned + Debug,
+{
+    if resource.meta().deletion_timestamp.is_none() {
+        info!("Deleting {}", resource.name_any());
+        api.delete(&resource.name_any(), &DeleteParams::default())
+            .await?;
+    }
+    Ok(())
+}
+
+fn is_current(resource: &impl ResourceExt, hash: &str) -> bool {
+    resource
+        .labels()
+        .get(POOL_TEMPLATE_HASH_LABEL)
+        .map(String::as_str)
+        == Some(hash)
+}
+
+fn is_ready(vm: &VirtualMachine) -> bool {
+    vm.meta().deletion_timestamp.is_none()
+        && vm.status.as_ref().is_some_and(|status| status.running)
+}
+
+/// Bring the replicas of a pool in line with its spec: remove the surplus replicas, create the
+/// missing ones and replace the outdated ones without exceeding the unavailable budget. A
+/// replica is recreated with fresh volumes once its outdated VM and volumes are gone.
+#[instrument(skip(ctx))]
+async fn update_fn(pool: Arc<VirtualMachinePool>, ctx: Arc<DefaultState>) -> Result<Action, Error> {
+    let client = ctx.client.clone();
+    let namespace = pool.namespace_unchecked();
+    let name = pool.name_any();
+    let hash = template_hash(&pool.spec);
+    let wanted = pool.spec.replicas;
+
+    let vms: Api<VirtualMachine> = Api::namespaced(client.clone(), &namespace);
+    let volumes: Api<Volume> = Api::namespaced(client.clone(), &namespace);
+    let selector = ListParams::default().labels(&format!("{POOL_LABEL}={name}"));
+    let replicas: BTreeMap<usize, VirtualMachine> = vms
+        .list(&selector)
+        .await?
+        .into_iter()
+        .filter_map(|vm| Some((replica_index(&vm)?, vm)))
+        .collect();
+    let pool_volumes = volumes.list(&selector).await?.items;
+
+    // Scale down, removing the volumes once their VM is gone
+    for vm in replicas.range(wanted..).map(|(_, vm)| vm) {
+        delete_once(&vms, vm).await?;
+    }
+    for volume in &pool_volumes {
+        match replica_index(volume) {
+            Some(index) if index < wanted || replicas.contains_key(&index) => {}
+            _ => delete_once(&volumes, volume).await?,
+        }
+    }
+
+    // Create the missing replicas, after the outdated volumes of a replaced one are removed
+    for index in (0..wanted).filter(|index| !replicas.contains_key(index)) {
+        let replica_volumes: Vec<&Volume> = pool_volumes
+            .iter()
+            .filter(|volume| replica_index(*volume) == Some(index))
+            .collect();
+        let outdated: Vec<&&Volume> = replica_volumes
+            .iter()
+            .filter(|volume| !is_current(**volume, &hash))
+            .collect();
+        if outdated.is_empty() {
+            create_replica(&pool, index, &hash, &replica_volumes, client.clone()).await?;
+        }
+        for volume in outdated {
+            delete_once(&volumes, *volume).await?;
+        }
+    }
+
+    // Replace outdated replicas. Unavailable ones go right away, available ones only as far as
+    // the unavailable budget allows.
+    let unavailable = (0..wanted)
+        .filter(|index| !replicas.get(index).is_some_and(is_ready))
+        .count();
+    let max_unavailable = pool.spec.max_unavailable.unwrap_or(DEFAULT_MAX_UNAVAILABLE);
+    let mut budget = max_unavailable.saturating_sub(unavailable);
+    let outdated = replicas
+        .range(..wanted)
+        .map(|(_, vm)| vm)
+        .filter(|vm| !is_current(*vm, &hash) && vm.meta().deletion_timestamp.is_none());
+    for vm in outdated.rev() {
+        if is_ready(vm) {
+            if budget == 0 {
+                continue;
+            }
+            budget -= 1;
+        }
+        info!("Replacing outdated replica {namespace}/{}", vm.name_any());
+        delete_once(&vms, vm).await?;
+    }
+
+    let in_range = || replicas.range(..wanted).map(|(_, vm)| vm);
+    let status = VirtualMachinePoolStatus {
+        replicas: in_range().count(),
+        ready_replicas: in_range().filter(|vm| is_ready(vm)).count(),
+        updated_replicas: in_range().filter(|vm| is_current(*vm, &hash)).count(),
+        template_hash: hash,
+    };
+    if pool.status.as_ref() != Some(&status) {
+        set_pool_status(&pool, status, client).await?;
+    }
+    ok_and_requeue!(15)
+}
+
+/// The VMs and Volumes of the pool are garbage collected through their owner references
+#[instrument(skip(_ctx))]
+async fn remove_fn(
+    _pool: Arc<VirtualMachinePool>,
+    _ctx: Arc<DefaultState>,
+) -> Result<Action, Error> {
+    ok_no_requeue!()
+}
+
+#[instrument(skip(client))]
+pub async fn create(client: Client) -> Result<(), Error> {
+    info!("Starting VirtualMachinePool controller");
+    ResourceControllerBuilder::new(client)
+        .with_default_state()
+        .with_default_error_policy()
+        .with_functions(update_fn, remove_fn)
+        .owns::<VirtualMachine>()
+        .owns::<Volume>()
+        .run()
+        .await;
+    Ok(())
+}
@@ -12,6 +12,9 @@ use std::sync::Arc;
 use tokio::time::Duration;
 use tracing::{info, instrument};
 
+use crate::cluster::controllers::protection::{
+    PROTECTION_FINALIZER, PROTECTION_RECHECK, release_protection, volume_users,
+};
 use crate::crd::ceph::Volume;
 use crate::errors::Error;
 use crate::shared::ceph::lowlevel;
@@ -29,29 +32,26 @@ lazy_static! {
 }
 
 /// Check if an volume already exists in the cluster and
-/// create if it doesn't.
+/// create if it doesn't. Returns whether it was created.
 #[instrument]
-fn ensure_exists(name: &str, size: u64, template: Option<String>) -> Result<(), Error> {
+fn ensure_exists(name: &str, size: u64, template: Option<String>) -> Result<bool, Error> {
     let cluster = lowlevel::connect()?;
     let volume_pool = lowlevel::get_pool(cluster, POOL_VOLUMES.into())?;
     let template_pool = lowlevel::get_pool(cluster, POOL_TEMPLATES.into())?;
 
-    lowlevel::get_images(volume_pool)?
+    let created = lowlevel::get_images(volume_pool)?
         .iter()
         .find(|&existing| existing == name)
-        .map(|_| Ok(()))
+        .map(|_| Ok(false))
         .or_else(|| {
             info!("ceph: Volume {} does not exist", name);
             if let Some(template_name) = template {
-                Some(lowlevel::clone_image(
-                    volume_pool,
-                    name,
-                    size,
-                    template_pool,
-                    &template_name,
-                ))
+                Some(
+                    lowlevel::clone_image(volume_pool, name, size, template_pool, &template_name)
+                        .map(|_| true),
+                )
             } else {
-                Some(lowlevel::create_image(volume_pool, name, size))
+                Some(lowlevel::create_image(volume_pool, name, size).map(|_| true))
             }
         })
         .unwrap()?;
@@ -59,7 +59,7 @@ fn ensure_exists(name: &str, size: u64, template: Option<String>) -> Result<(),
     lowlevel::close_pool(volume_pool);
     lowlevel::close_pool(template_pool);
     lowlevel::disconnect(cluster);
-    Ok(())
+    Ok(created)
 }
 
 #[instrument]
@@ -144,7 +144,23 @@ async fn update_fn(volume: Arc<Volume>, ctx: Arc<DefaultState>) -> Result<Action
     volume
         .ensure_finalizer("ceph", ctx.client.clone(), &FIELD_MANAGER)
         .await?;
-    ensure_exists(&name, bytes, template)?;
+    volume
+        .ensure_finalizer(PROTECTION_FINALIZER, ctx.client.clone(), &FIELD_MANAGER)
+        .await?;
+    let source = match &template {
+        Some(template) => format!("cloned from template {template}"),
+        None => String::from("empty"),
+    };
+    if ensure_exists(&name, bytes, template)? {
+        ctx.recorder
+            .normal(
+                &volume,
+                "Created",
+                "CreateVolume",
+                format!("Created a {} volume, {source}", volume.spec.size),
+            )
+            .await;
+    }
     info!("ceph: Volume {name} update success");
 
     Ok(Action::requeue(Duration::from_secs(600)))
@@ -156,6 +172,11 @@ async fn remove_fn(volume: Arc<Volume>, ctx: Arc<DefaultState>) -> Result<Action
     let mut volume = (*volume).clone();
     let name = volume.name_prefixed_with_namespace();
 
+    let users = volume_users(&volume, ctx.client.clone()).await?;
+    if !release_protection(&mut volume, users, ctx.client.clone(), &FIELD_MANAGER).await? {
+        return Ok(Action::requeue(Duration::from_secs(PROTECTION_RECHECK)));
+    }
+
     info!("ceph: Volume {name} waiting for deletion");
     ensure_removed(&name)?;
     volume
@@ -0,0 +1,218 @@
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use k8s_openapi::chrono::{DateTime, Duration, Utc};
+use kube::api::{ObjectMeta, Patch, PatchParams, PostParams};
+use kube::{Api, Client};
+use serde_json::json;
+use std::env;
+use std::time::Instant;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use crate::crd::cluster::Cluster;
+use crate::errors::Error;
+use crate::utils::metrics::set_gauge;
+
+const LEASE_NAME: &str = "cluster-controller";
+/// Seconds the lease is valid without renewal, i.e. how long a crashed leader blocks failover
+const LEASE_DURATION: i64 = 15;
+/// Seconds the leader may fail to renew before giving up the lease, less than LEASE_DURATION so
+/// it stops before another replica takes over
+const RENEW_DEADLINE: u64 = 10;
+/// Seconds between attempts to renew or acquire the lease
+const RETRY_PERIOD: u64 = 2;
+/// Gauge set to 1 on the replica holding the lease
+const LEADER_METRIC: &str = "virt_controller_leader";
+
+/// Lease-based leader election between the replicas of the cluster controller. Only the leader
+/// runs the controllers, the others wait to take over the lease once it expires.
+pub struct LeaderElection {
+    leases: Api<Lease>,
+    client: Client,
+    identity: String,
+}
+
+/// Whether a lease held by someone else can be taken over at the given time
+fn lease_available(spec: &LeaseSpec, now: DateTime<Utc>) -> bool {
+    if spec
+        .holder_identity
+        .as_deref()
+        .unwrap_or_default()
+        .is_empty()
+    {
+        return true;
+    }
+    match (&spec.renew_time, spec.lease_duration_seconds) {
+        (Some(renewed), Some(duration)) => renewed.0 + Duration::seconds(duration.into()) < now,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_lease_available() {
+    let now = Utc::now();
+    let held = |renewed: DateTime<Utc>| LeaseSpec {
+        holder_identity: Some(String::from("cluster-controller-0")),
+        lease_duration_seconds: Some(15),
+        renew_time: Some(MicroTime(renewed)),
+        ..Default::default()
+    };
+    assert!(lease_available(&LeaseSpec::default(), now));
+    assert!(!lease_available(&held(now - Duration::seconds(5)), now));
+    assert!(lease_available(&held(now - Duration::seconds(20)), now));
+    // Released leases have no holder
+    let released = LeaseSpec {
+        holder_identity: None,
+        ..held(now)
+    };
+    assert!(lease_available(&released, now));
+}
+
+impl LeaderElection {
+    /// The identity is the pod name from $POD_NAME, replicas on host network share the hostname
+    pub fn new(client: Client, namespace: &str) -> Self {
+        let identity = env::var("POD_NAME").unwrap_or_else(|_| Uuid::new_v4().to_string());
+        set_gauge(LEADER_METRIC, 0.0);
+        LeaderElection {
+            leases: Api::namespaced(client.clone(), namespace),
+            client,
+            identity,
+        }
+    }
+
+    /// Take or renew the lease if it is free or already ours. Returns whether we hold it, losing
+    /// a race against another replica is not an error.
+    #[instrument(skip(self), fields(identity = %self.identity))]
+    async fn try_acquire_or_renew(&self) -> Result<bool, Error> {
+        let now = Utc::now();
+        let Some(mut lease) = self.leases.get_opt(LEASE_NAME).await? else {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(String::from(LEASE_NAME)),
+                    ..Default::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(self.identity.clone()),
+                    lease_duration_seconds: Some(LEASE_DURATION as i32),
+                    acquire_time: Some(MicroTime(now)),
+                    renew_time: Some(MicroTime(now)),
+                    lease_transitions: Some(0),
+                }),
+            };
+            return match self.leases.create(&PostParams::default(), &lease).await {
+                Ok(_) => Ok(true),
+                Err(kube::Error::Api(response)) if response.code == 409 => Ok(false),
+                Err(error) => Err(error.into()),
+            };
+        };
+
+        let spec = lease.spec.get_or_insert_with(Default::default);
+        let ours = spec.holder_identity.as_ref() == Some(&self.identity);
+        if !ours && !lease_available(spec, now) {
+            return Ok(false);
+        }
+        if !ours {
+            spec.holder_identity = Some(self.identity.clone());
+            spec.acquire_time = Some(MicroTime(now));
+            spec.lease_transitions = Some(spec.lease_transitions.unwrap_or_default() + 1);
+        }
+        spec.lease_duration_seconds = Some(LEASE_DURATION as i32);
+        spec.renew_time = Some(MicroTime(now));
+
+        // The resource version in the metadata makes the replace fail if someone else got there
+        // first
+        match self
+            .leases
+            .replace(LEASE_NAME, &PostParams::default(), &lease)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(response)) if response.code == 409 => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Wait until this replica is the leader
+    pub async fn acquire(&self) -> Result<(), Error> {
+        info!("Waiting to become the leader as {}", self.identity);
+        loop {
+            match self.try_acquire_or_renew().await {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(error) => warn!("Failed to acquire the leader lease: {error}"),
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(RETRY_PERIOD)).await;
+        }
+        info!("Became the leader as {}", self.identity);
+        set_gauge(LEADER_METRIC, 1.0);
+        self.publish_leader(Some(&self.identity)).await;
+        Ok(())
+    }
+
+    /// Keep renewing the lease. Returns an error once the leadership is lost, after which the
+    /// controllers must stop. A renewal hanging in the API is cut off at the renew deadline, so
+    /// that it can not keep a stale leader running.
+    pub async fn hold(&self) -> Result<(), Error> {
+        let deadline = std::time::Duration::from_secs(RENEW_DEADLINE);
+        let mut renewed = Instant::now();
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(RETRY_PERIOD)).await;
+            let remaining = deadline.saturating_sub(renewed.elapsed());
+            match tokio::time::timeout(remaining, self.try_acquire_or_renew()).await {
+                Ok(Ok(true)) => renewed = Instant::now(),
+                Ok(Ok(false)) => {
+                    set_gauge(LEADER_METRIC, 0.0);
+                    return Err(Error::LeadershipLost(String::from(
+                        "lease taken over by another replica",
+                    )));
+                }
+                Ok(Err(error)) => warn!("Failed to renew the leader lease: {error}"),
+                Err(_) => warn!("Renewing the leader lease timed out"),
+            }
+            if renewed.elapsed().as_secs() >= RENEW_DEADLINE {
+                set_gauge(LEADER_METRIC, 0.0);
+                return Err(Error::LeadershipLost(format!(
+                    "lease not renewed in {RENEW_DEADLINE} seconds"
+                )));
+            }
+        }
+    }
+
+    /// Give up the lease on shutdown, so another replica takes over without waiting for it to
+    /// expire
+    pub async fn release(&self) -> Result<(), Error> {
+        let Some(mut lease) = self.leases.get_opt(LEASE_NAME).await? else {
+            return Ok(());
+        };
+        let spec = lease.spec.get_or_insert_with(Default::default);
+        if spec.holder_identity.as_ref() != Some(&self.identity) {
+            return Ok(());
+        }
+        spec.holder_identity = None;
+        spec.renew_time = None;
+        self.leases
+            .replace(LEASE_NAME, &PostParams::default(), &lease)
+            .await?;
+        info!("Released the leader lease");
+        set_gauge(LEADER_METRIC, 0.0);
+        self.publish_leader(None).await;
+        Ok(())
+    }
+
+    /// Show the current leader in the status of the default Cluster
+    async fn publish_leader(&self, leader: Option<&str>) {
+        let clusters: Api<Cluster> = Api::all(self.client.clone());
+        let patch = json!({
+            "status": {
+                "leader": leader
+            }
+        });
+        let result = clusters
+            .patch_status("default", &PatchParams::default(), &Patch::Merge(patch))
+            .await;
+        if let Err(error) = result {
+            warn!("Failed to set the leader in the Cluster status: {error}");
+        }
+    }
+}
@@ -3,13 +3,21 @@ use kube::{
     Api, Client,
     api::{Patch, PatchParams},
 };
-use tracing::error;
+use tokio::signal::unix::{SignalKind, signal};
+use tracing::{error, info};
 
 use crate::errors::Error;
 use crate::host::daemonset;
+use crate::interfaces::ovn::lowlevel::Ovn;
+use crate::shared::ceph;
+use crate::utils::health::{CLUSTER_HEALTH_PORT, HealthServer};
+use crate::utils::supervisor::SupervisorHealth;
 use crate::{NAMESPACE, crd};
 
 mod controllers;
+mod leader;
+
+pub use controllers::explain_schedule;
 
 const DEPLOYMENT_NAME: &str = "cluster-controller";
 
@@ -31,6 +39,26 @@ pub async fn run(client: Client, namespace: &str) -> Result<(), Error> {
     // Create cluster CRD
     crd::cluster::create(client.clone()).await?;
 
+    // Standby replicas answer the probes too
+    let health = SupervisorHealth::default();
+    let ovn_client = client.clone();
+    let health_server = HealthServer::default()
+        .with_supervisor(health.clone())
+        .with_readiness_check("ovn", move || {
+            let client = ovn_client.clone();
+            async move { Ovn::try_from_annotations(client).await.map(|_| ()) }
+        })
+        .with_readiness_check("ceph", || async {
+            tokio::task::spawn_blocking(ceph::check_connection)
+                .await
+                .map_err(|error| Error::UnexpectedExit(error.to_string()))?
+        });
+    tokio::spawn(health_server.bind(CLUSTER_HEALTH_PORT)?);
+
+    // Only one replica may run the controllers at a time
+    let election = leader::LeaderElection::new(client.clone(), namespace);
+    election.acquire().await?;
+
     // Create libvirt host controllers
     let image = get_running_image(client.clone()).await?;
     let libvirt_ds = daemonset::make_daemonset(image)?;
@@ -42,9 +70,19 @@ pub async fn run(client: Client, namespace: &str) -> Result<(), Error> {
         )
         .await?;
 
-    let result = controllers::run(client).await;
-    error!(
-        "supervisor: ERROR: One of the controllers died, killing the rest of the application: {result:#?}"
-    );
-    std::process::exit(1);
+    // The controllers are restarted individually by their supervisor, which only returns once
+    // one of them keeps failing
+    let mut terminate = signal(SignalKind::terminate())?;
+    let result = tokio::select! {
+        result = controllers::run(client, health) => result,
+        result = election.hold() => result,
+        _ = terminate.recv() => {
+            info!("Terminating, handing over the leadership");
+            return election.release().await;
+        }
+    };
+    if let Err(error) = &result {
+        error!("supervisor: stopping the cluster controllers: {error}");
+    }
+    result
 }
@@ -1,17 +1,10 @@
-use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
-use kube::{
-    api::{Patch, PatchParams},
-    Api, Client, CustomResource, CustomResourceExt,
-};
+use kube::{Client, CustomResource};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
+use crate::crd::versioning::{self, VersionedCrd};
 use crate::errors::Error;
-use crate::utils::wait_crd_ready;
-
-const VOLUME_CRD_NAME: &str = "volumes.cluster-virt.acl.fi";
-const IMAGE_CRD_NAME: &str = "images.cluster-virt.acl.fi";
 
 #[derive(
     CustomResource, Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema,
@@ -62,19 +55,12 @@ pub struct ImageStatus {
     import_in_progress: bool,
 }
 
-#[instrument(skip(client))]
-pub async fn create(client: Client) -> Result<(), Error> {
-    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
-    let patch_params = PatchParams::apply("cluster-manager.ceph").force();
+impl VersionedCrd for Volume {}
 
-    let crd = Volume::crd();
-    crds.patch(VOLUME_CRD_NAME, &patch_params, &Patch::Apply(&crd))
-        .await?;
-    wait_crd_ready(&crds, VOLUME_CRD_NAME).await?;
+impl VersionedCrd for Image {}
 
-    let crd = Image::crd();
-    crds.patch(IMAGE_CRD_NAME, &patch_params, &Patch::Apply(&crd))
-        .await?;
-    wait_crd_ready(&crds, IMAGE_CRD_NAME).await?;
-    Ok(())
+#[instrument(skip(client))]
+pub async fn create(client: Client) -> Result<(), Error> {
+    versioning::create::<Volume>(client.clone(), "cluster-manager.ceph").await?;
+    versioning::create::<Image>(client, "cluster-manager.ceph").await
 }
@@ -1,12 +1,13 @@
 use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube::{
-    api::{Patch, PatchParams},
     Api, Client, CustomResource, CustomResourceExt,
+    api::{Patch, PatchParams},
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
+use crate::crd::virtualmachine::DomainPatch;
 use crate::errors::Error;
 use crate::utils::wait_crd_ready;
 
@@ -21,7 +22,8 @@ const CRD_NAME: &str = "clusters.cluster-virt.acl.fi";
     kind = "Cluster",
     status = "ClusterStatus",
     derive = "PartialEq",
-    derive = "Default"
+    derive = "Default",
+    printcolumn = r#"{"name":"Leader", "type":"string", "description":"Replica of the cluster controller currently running the controllers", "jsonPath":".status.leader"}"#
 )]
 pub struct ClusterSpec {
     // e.g. pc-q35-rhel8.3.0
@@ -29,10 +31,62 @@ pub struct ClusterSpec {
 
     // <cpu>...</cpu>
     pub cpu: String,
+
+    /// Periodically live migrate VMs to even out node load and restore affinity rules
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rebalancer: Option<RebalancerSpec>,
+
+    /// Firmware images for VMs booting with UEFI, defaults to the edk2 OVMF images
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uefi: Option<FirmwareImages>,
+
+    /// Firmware images for VMs booting with UEFI Secure Boot, defaults to the edk2 OVMF images
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uefi_secure_boot: Option<FirmwareImages>,
+
+    /// Patches applied to the domain XML of every VM, before the ones of the VM itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain_overrides: Option<Vec<DomainPatch>>,
+
+    /// Memory the VMs on a node may be given, in percent of the allocatable memory of the node.
+    /// Memory is overcommitted without a limit by default, preempting lower priority VMs then
+    /// frees memory up to the allocatable memory of the node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_limit_percent: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
+pub struct FirmwareImages {
+    /// Path of the read-only firmware code on the hosts
+    pub loader: String,
+    /// Path of the variable store template on the hosts, copied to the NVRAM of each VM
+    pub vars_template: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
+pub struct RebalancerSpec {
+    pub enabled: bool,
+
+    /// Seconds between rebalancing rounds, defaults to 300
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval_seconds: Option<u64>,
+
+    /// Maximum number of migrations in progress at the same time, defaults to 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_migrations: Option<usize>,
+
+    /// Difference between the most and least loaded node, in percent of the average node load,
+    /// above which VMs are moved. Defaults to 25
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub imbalance_threshold_percent: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
-pub struct ClusterStatus {}
+pub struct ClusterStatus {
+    /// Replica of the cluster controller holding the leader lease
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leader: Option<String>,
+}
 
 #[instrument(skip(client))]
 pub async fn create(client: Client) -> Result<(), Error> {
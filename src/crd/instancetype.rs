@@ -0,0 +1,61 @@
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use kube::{
+    Api, Client, CustomResource, CustomResourceExt,
+    api::{Patch, PatchParams},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::crd::virtualmachine::TemplateValues;
+use crate::errors::Error;
+use crate::utils::wait_crd_ready;
+
+const CRD_NAME: &str = "instancetypes.cluster-virt.acl.fi";
+
+/// A named size of VM, referenced by VMs and VirtualMachineTemplates
+#[derive(
+    CustomResource, Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema,
+)]
+#[kube(
+    group = "cluster-virt.acl.fi",
+    version = "v1beta",
+    kind = "InstanceType",
+    derive = "PartialEq",
+    derive = "Default",
+    printcolumn = r#"{"name":"CPUs", "type":"integer", "jsonPath":".spec.cpus"}"#,
+    printcolumn = r#"{"name":"Memory", "type":"string", "jsonPath":".spec.memory"}"#
+)]
+pub struct InstanceTypeSpec {
+    pub cpus: usize,
+    // String to allow suffixes like '1 Gi'
+    pub memory: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub machine_type: Option<String>,
+}
+
+impl InstanceTypeSpec {
+    pub fn values(&self) -> TemplateValues {
+        TemplateValues {
+            cpus: Some(self.cpus),
+            memory: Some(self.memory.clone()),
+            cpu_model: self.cpu_model.clone(),
+            machine_type: self.machine_type.clone(),
+            compatibility_mode: None,
+        }
+    }
+}
+
+#[instrument(skip(client))]
+pub async fn create(client: Client) -> Result<(), Error> {
+    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
+    let patch_params = PatchParams::apply("virt-controller").force();
+
+    let crd = InstanceType::crd();
+    crds.patch(CRD_NAME, &patch_params, &Patch::Apply(&crd))
+        .await?;
+    wait_crd_ready(&crds, CRD_NAME).await?;
+    Ok(())
+}
@@ -1,28 +1,21 @@
-use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube::Resource;
 use kube::ResourceExt;
 use kube::api::PostParams;
-use kube::core::crd::merge_crds;
-use kube::{
-    Api, Client, CustomResource, CustomResourceExt,
-    api::{Patch, PatchParams},
-};
+use kube::{Api, Client, CustomResource};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tracing::info;
+use std::collections::BTreeMap;
 
+use crate::crd::versioning::{self, VersionedCrd};
 use crate::errors::Error;
-use crate::utils::wait_crd_ready;
 use tracing::instrument;
 
-const CRD_NAME: &str = "libvirtnodes.cluster-virt.acl.fi";
-
 mod latest {
-    pub const VERSION: &str = "v1beta1";
-
     pub type LibvirtNode = super::v1beta1::LibvirtNode;
     pub type LibvirtNodeStatus = super::v1beta1::LibvirtNodeStatus;
+    pub type CpuAllocation = super::v1beta1::CpuAllocation;
+    pub type PciDevice = super::v1beta1::PciDevice;
 }
 
 pub mod v1beta1 {
@@ -40,61 +33,73 @@ pub mod v1beta1 {
         derive = "Default",
         shortname = "lvnode"
     )]
-    pub struct LibvirtNodeSpec {}
+    pub struct LibvirtNodeSpec {
+        /// Host CPUs kept for the host itself, never handed out to VMs with dedicated CPUs
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub reserved_cpus: Vec<u32>,
+    }
 
     #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema, Default)]
     pub struct LibvirtNodeStatus {
         pub capabilities: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub domain_capabilities: Option<String>,
+        /// Host CPUs dedicated to VMs on this node, by namespaced VM name
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        pub cpu_allocations: BTreeMap<String, CpuAllocation>,
+        /// Hugepages configured on the host as number of pages by page size, e.g. "2M"
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        pub hugepages: BTreeMap<String, u64>,
+        /// PCI devices of the host
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub pci_devices: Vec<PciDevice>,
+        /// Addresses of the PCI devices passed through to VMs, by namespaced VM name
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        pub pci_allocations: BTreeMap<String, Vec<String>>,
     }
-}
-
-#[instrument(skip(client))]
-pub async fn create(client: Client) -> Result<(), Error> {
-    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
-    let patch_params = PatchParams::apply("cluster-manager.libvirt").force();
-
-    if let Ok(crd) = crds.get(CRD_NAME).await {
-        let current_versions: Vec<String> = crd
-            .spec
-            .versions
-            .into_iter()
-            .map(|version| version.name)
-            .collect();
-        if current_versions == [String::from(latest::VERSION)] {
-            info!("CRD virtualmachines: no migrations required");
-
-            let latest_crd = latest::LibvirtNode::crd();
-            crds.patch(CRD_NAME, &patch_params, &Patch::Apply(&latest_crd))
-                .await?;
 
-            return Ok(());
-        }
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema, Default)]
+    pub struct PciDevice {
+        /// Address as domain:bus:slot.function, e.g. 0000:3b:00.1
+        pub address: String,
+        /// Vendor ID in hex without prefix, e.g. 8086
+        pub vendor_id: String,
+        /// Product ID in hex without prefix
+        pub product_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub vendor_name: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub product_name: Option<String>,
+        /// Host driver the device is bound to, only devices bound to vfio-pci are passed through
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub driver: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub numa_node: Option<u32>,
+        /// Address of the physical function, if this is an SR-IOV virtual function
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub physical_function: Option<String>,
     }
 
-    // Create all possible versions to exist in parallel
-    let crd_versions = vec![v1beta1::LibvirtNode::crd()];
-    let crd = merge_crds(crd_versions, latest::VERSION)?;
-    crds.patch(CRD_NAME, &patch_params, &Patch::Apply(&crd))
-        .await?;
-    wait_crd_ready(&crds, CRD_NAME).await?;
-
-    // Migrate all objects to the latest version
-    run_migrations(client.clone()).await?;
-
-    // Remove all but the latest CRD version
-    let latest_crd = latest::LibvirtNode::crd();
-    crds.patch(CRD_NAME, &patch_params, &Patch::Apply(&latest_crd))
-        .await?;
-
-    Ok(())
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema, Default)]
+    pub struct CpuAllocation {
+        /// Host CPU for each vCPU, in vCPU order
+        pub cpus: Vec<u32>,
+        /// NUMA cell all the CPUs belong to, if the VM is bound to one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub numa_cell: Option<u32>,
+    }
 }
 
-#[instrument(skip(_client))]
-pub async fn run_migrations(_client: Client) -> Result<(), Error> {
-    Ok(())
+impl VersionedCrd for latest::LibvirtNode {}
+
+#[instrument(skip(client))]
+pub async fn create(client: Client) -> Result<(), Error> {
+    versioning::create::<latest::LibvirtNode>(client, "cluster-manager.libvirt").await
 }
 
 pub(crate) type LibvirtNode = latest::LibvirtNode;
 pub(crate) type LibvirtNodeStatus = latest::LibvirtNodeStatus;
+pub(crate) type CpuAllocation = latest::CpuAllocation;
+pub(crate) type PciDevice = latest::PciDevice;
 
 create_set_status_cluster_scoped!(LibvirtNode, LibvirtNodeStatus, set_libvirtnode_status);
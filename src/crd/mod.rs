@@ -1,8 +1,10 @@
 pub mod ceph;
 pub mod cluster;
+pub mod instancetype;
 pub mod libvirtnode;
 pub mod network;
 pub mod router;
+pub mod versioning;
 pub mod virtualmachine;
-
-mod utils;
+pub mod virtualmachinepool;
+pub mod virtualmachinetemplate;
@@ -1,16 +1,10 @@
+use crate::crd::versioning::{self, VersionedCrd};
 use crate::errors::Error;
-use crate::utils::wait_crd_ready;
-use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
-use kube::{
-    api::{Patch, PatchParams}, Api, Client, CustomResource,
-    CustomResourceExt,
-};
+use kube::{Client, CustomResource};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
-const NETWORK_CRD_NAME: &str = "networks.cluster-virt.acl.fi";
-
 #[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
 pub struct DhcpOptions {
     pub cidr: String,
@@ -22,10 +16,28 @@ pub struct DhcpOptions {
 
 #[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
 pub struct RouterAttachment {
+    /// Router in the namespace of the network, or <namespace>/<name>
     pub name: String,
     pub address: String,
 }
 
+impl RouterAttachment {
+    /// Namespace and name of the attached router
+    pub fn router(&self, network_namespace: &str) -> Result<(String, String), Error> {
+        let parts: Vec<&str> = self.name.split('/').collect();
+        match parts.as_slice() {
+            [name] if !name.is_empty() => Ok((network_namespace.to_string(), name.to_string())),
+            [namespace, name] if !namespace.is_empty() && !name.is_empty() => {
+                Ok((namespace.to_string(), name.to_string()))
+            }
+            _ => Err(Error::InvalidResource(format!(
+                "malformed router name {}, expected <name> or <namespace>/<name>",
+                self.name
+            ))),
+        }
+    }
+}
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 pub enum NetworkType {
     #[default]
@@ -83,16 +95,11 @@ pub mod v1beta1 {
     }
 }
 
+impl VersionedCrd for latest::Network {}
+
 #[instrument(skip(client))]
 pub async fn create(client: Client) -> Result<(), Error> {
-    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
-    let patch_params = PatchParams::apply("cluster-manager.ceph").force();
-
-    let network_crd = Network::crd();
-    crds.patch(NETWORK_CRD_NAME, &patch_params, &Patch::Apply(&network_crd))
-        .await?;
-    wait_crd_ready(&crds, NETWORK_CRD_NAME).await?;
-    Ok(())
+    versioning::create::<latest::Network>(client, "cluster-manager.ceph").await
 }
 
 pub(crate) type Network = latest::Network;
@@ -1,16 +1,10 @@
+use crate::crd::versioning::{self, VersionedCrd};
 use crate::errors::Error;
-use crate::utils::wait_crd_ready;
-use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
-use kube::{
-    Api, Client, CustomResource, CustomResourceExt,
-    api::{Patch, PatchParams},
-};
+use kube::{Client, CustomResource};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
-const ROUTER_CRD_NAME: &str = "routers.cluster-virt.acl.fi";
-
 #[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
 pub struct Route {
     pub cidr: String,
@@ -40,14 +34,9 @@ pub struct RouterStatus {
     pub is_created: bool,
 }
 
+impl VersionedCrd for Router {}
+
 #[instrument(skip(client))]
 pub async fn create(client: Client) -> Result<(), Error> {
-    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
-    let patch_params = PatchParams::apply("cluster-manager.ceph").force();
-
-    let router_crd = Router::crd();
-    crds.patch(ROUTER_CRD_NAME, &patch_params, &Patch::Apply(&router_crd))
-        .await?;
-    wait_crd_ready(&crds, ROUTER_CRD_NAME).await?;
-    Ok(())
+    versioning::create::<Router>(client, "cluster-manager.ceph").await
 }
@@ -0,0 +1,369 @@
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+    CustomResourceConversion, CustomResourceDefinition, ServiceReference, WebhookClientConfig,
+    WebhookConversion,
+};
+use kube::api::{ApiResource, DynamicObject, ListParams, Patch, PatchParams, PostParams};
+use kube::core::GroupVersionKind;
+use kube::core::crd::merge_crds;
+use kube::{Api, Client, CustomResourceExt, Resource, ResourceExt};
+use serde_json::{Value, json};
+use std::env;
+use tracing::{debug, info, instrument};
+
+use crate::crd::ceph::{Image, Volume};
+use crate::crd::libvirtnode::LibvirtNode;
+use crate::crd::network::Network;
+use crate::crd::router::Router;
+use crate::crd::virtualmachine::VirtualMachine;
+use crate::errors::Error;
+use crate::utils::wait_crd_ready;
+
+/// Service of the admission webhook deployment, which also serves the conversions at /convert
+const WEBHOOK_SERVICE: &str = "virt-webhook";
+const WEBHOOK_NAMESPACE: &str = "virt-controller";
+/// Serve the older versions of the CRDs next to the storage version when set to "true". Versions
+/// with differing schemas are then converted by the webhook, which needs cert-manager for its
+/// certificate.
+const SERVE_OLDER_VERSIONS_ENV: &str = "SERVE_OLDER_CRD_VERSIONS";
+
+/// A CRD with several API versions. Objects are stored in the latest version, `Self`, and
+/// converted between adjacent versions. Only the latest version is served unless older versions
+/// are asked for, those are converted by the conversion webhook.
+pub trait VersionedCrd: CustomResourceExt + Resource<DynamicType = ()> {
+    /// Definitions of all the served versions, oldest first. The last one is the storage
+    /// version.
+    fn versions() -> Vec<CustomResourceDefinition> {
+        vec![Self::crd()]
+    }
+
+    /// Convert an object of the given version to the next newer version
+    fn upgrade(_version: &str, object: Value) -> Result<Value, Error> {
+        Ok(object)
+    }
+
+    /// Convert an object of the given version to the next older version. Fields missing from
+    /// the older schema are pruned by the API server.
+    fn downgrade(_version: &str, object: Value) -> Result<Value, Error> {
+        Ok(object)
+    }
+}
+
+fn version_names<T: VersionedCrd>() -> Vec<String> {
+    T::versions()
+        .into_iter()
+        .flat_map(|crd| crd.spec.versions.into_iter().map(|version| version.name))
+        .collect()
+}
+
+/// Convert an object of the CRD to the desired API version, like cluster-virt.acl.fi/v1beta3,
+/// one version at a time
+pub fn convert<T: VersionedCrd>(
+    mut object: Value,
+    desired_api_version: &str,
+) -> Result<Value, Error> {
+    let versions = version_names::<T>();
+    let position = |api_version: &str| {
+        let version = api_version.rsplit('/').next().unwrap_or_default();
+        versions
+            .iter()
+            .position(|name| name == version)
+            .ok_or_else(|| Error::CrdConversion(format!("unknown version {api_version}")))
+    };
+    let api_version = object["apiVersion"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    let mut current = position(&api_version)?;
+    let desired = position(desired_api_version)?;
+
+    while current < desired {
+        object = T::upgrade(&versions[current], object)?;
+        current += 1;
+    }
+    while current > desired {
+        object = T::downgrade(&versions[current], object)?;
+        current -= 1;
+    }
+    object["apiVersion"] = json!(desired_api_version);
+    Ok(object)
+}
+
+/// Convert an object of any of the versioned CRDs
+pub fn convert_object(object: Value, desired_api_version: &str) -> Result<Value, Error> {
+    match object["kind"].as_str().unwrap_or_default() {
+        "VirtualMachine" => convert::<VirtualMachine>(object, desired_api_version),
+        "LibvirtNode" => convert::<LibvirtNode>(object, desired_api_version),
+        "Network" => convert::<Network>(object, desired_api_version),
+        "Router" => convert::<Router>(object, desired_api_version),
+        "Volume" => convert::<Volume>(object, desired_api_version),
+        "Image" => convert::<Image>(object, desired_api_version),
+        kind => Err(Error::CrdConversion(format!("{kind} is not versioned"))),
+    }
+}
+
+fn serve_older_versions() -> bool {
+    env::var(SERVE_OLDER_VERSIONS_ENV).is_ok_and(|value| value == "true")
+}
+
+/// Apply the CRD as the field manager and wait for it to be established
+async fn apply_crd(
+    crds: &Api<CustomResourceDefinition>,
+    crd: &CustomResourceDefinition,
+    field_manager: &str,
+) -> Result<(), Error> {
+    let crd_name = crd.name_any();
+    crds.patch(
+        &crd_name,
+        &PatchParams::apply(field_manager).force(),
+        &Patch::Apply(crd),
+    )
+    .await?;
+    wait_crd_ready(crds, &crd_name).await
+}
+
+/// Definition of the CRD with the versions to serve. The conversion webhook is only used when
+/// older versions with a different schema are served.
+fn served_crd<T: VersionedCrd>() -> Result<CustomResourceDefinition, Error> {
+    if !serve_older_versions() {
+        return Ok(T::crd());
+    }
+    let mut crd = merge_crds(T::versions(), &T::version(&()))?;
+    let schemas: Vec<_> = crd
+        .spec
+        .versions
+        .iter()
+        .map(|version| &version.schema)
+        .collect();
+    if schemas.windows(2).any(|pair| pair[0] != pair[1]) {
+        crd.spec.conversion = Some(conversion_webhook());
+        crd.annotations_mut().insert(
+            String::from("cert-manager.io/inject-ca-from"),
+            format!("{WEBHOOK_NAMESPACE}/{WEBHOOK_SERVICE}"),
+        );
+    }
+    Ok(crd)
+}
+
+/// Create or update the CRD. Objects stored in older versions are first migrated to the storage
+/// version, with the CRD serving all its versions without a conversion webhook meanwhile, so the
+/// migration does not depend on the webhook being up.
+#[instrument(skip(client))]
+pub async fn create<T: VersionedCrd>(client: Client, field_manager: &str) -> Result<(), Error> {
+    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
+    let crd_name = T::crd_name();
+    let versions = version_names::<T>();
+
+    remove_dropped_versions::<T>(client.clone(), &versions).await?;
+
+    let storage_version = T::version(&()).to_string();
+    let stored_versions = crds
+        .get_opt(crd_name)
+        .await?
+        .and_then(|crd| crd.status)
+        .and_then(|status| status.stored_versions)
+        .unwrap_or_default();
+    if stored_versions
+        .iter()
+        .any(|version| version != &storage_version)
+    {
+        let crd = merge_crds(T::versions(), &storage_version)?;
+        apply_crd(&crds, &crd, field_manager).await?;
+        migrate_storage::<T>(client, &stored_versions).await?;
+    }
+
+    apply_crd(&crds, &served_crd::<T>()?, field_manager).await
+}
+
+fn conversion_webhook() -> CustomResourceConversion {
+    CustomResourceConversion {
+        strategy: String::from("Webhook"),
+        webhook: Some(WebhookConversion {
+            client_config: Some(WebhookClientConfig {
+                service: Some(ServiceReference {
+                    name: String::from(WEBHOOK_SERVICE),
+                    namespace: String::from(WEBHOOK_NAMESPACE),
+                    path: Some(String::from("/convert")),
+                    port: Some(443),
+                }),
+                ..Default::default()
+            }),
+            conversion_review_versions: vec![String::from("v1")],
+        }),
+    }
+}
+
+/// Remove the versions of the existing CRD which have been dropped from the code, as they can no
+/// longer be converted. Objects still stored in them are first rewritten through the storage
+/// version of the existing CRD. If that fails, or the storage version itself has been dropped,
+/// the CRD is left alone and an error returned.
+#[instrument(skip(client))]
+async fn remove_dropped_versions<T: VersionedCrd>(
+    client: Client,
+    versions: &[String],
+) -> Result<(), Error> {
+    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
+    let crd_name = T::crd_name();
+    let Some(existing) = crds.get_opt(crd_name).await? else {
+        return Ok(());
+    };
+    let dropped: Vec<String> = existing
+        .spec
+        .versions
+        .iter()
+        .map(|version| version.name.clone())
+        .filter(|name| !versions.contains(name))
+        .collect();
+    if dropped.is_empty() {
+        return Ok(());
+    }
+
+    let stored_versions = existing
+        .status
+        .and_then(|status| status.stored_versions)
+        .unwrap_or_default();
+    if stored_versions
+        .iter()
+        .any(|version| dropped.contains(version))
+    {
+        let storage_version = existing
+            .spec
+            .versions
+            .iter()
+            .find(|version| version.storage)
+            .map(|version| version.name.clone())
+            .filter(|name| !dropped.contains(name))
+            .ok_or_else(|| {
+                Error::CrdConversion(format!(
+                    "CRD {crd_name}: the storage version is one of the dropped {dropped:?}, \
+                     the objects stored in them can not be migrated"
+                ))
+            })?;
+        info!("CRD {crd_name}: migrating objects stored in {dropped:?} to {storage_version}");
+        let gvk = GroupVersionKind::gvk(&T::group(&()), &storage_version, &T::kind(&()));
+        let resource = ApiResource::from_gvk_with_plural(&gvk, &T::plural(&()));
+        rewrite_objects(client.clone(), &resource).await?;
+    }
+
+    for version in dropped {
+        info!("CRD {crd_name}: removing version {version}");
+        remove_crd_version(crd_name, &version, client.clone()).await?;
+    }
+    Ok(())
+}
+
+/// Read and replace every object of the resource, which stores it in the storage version
+async fn rewrite_objects(client: Client, resource: &ApiResource) -> Result<(), Error> {
+    let objects: Api<DynamicObject> = Api::all_with(client.clone(), resource);
+    for object in objects.list(&ListParams::default()).await? {
+        let api: Api<DynamicObject> = match object.namespace() {
+            Some(namespace) => Api::namespaced_with(client.clone(), &namespace, resource),
+            None => Api::all_with(client.clone(), resource),
+        };
+        debug!("{}: rewriting {}", resource.plural, object.name_any());
+        api.replace(&object.name_any(), &PostParams::default(), &object)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Rewrite every object still stored in an older version in the storage version, and drop the
+/// older versions from the stored versions of the CRD. Without a conversion webhook the objects
+/// are read as they are stored, so they are converted here from the oldest stored version.
+#[instrument(skip(client))]
+async fn migrate_storage<T: VersionedCrd>(
+    client: Client,
+    stored_versions: &[String],
+) -> Result<(), Error> {
+    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
+    let crd_name = T::crd_name();
+    let storage_version = T::version(&()).to_string();
+    let oldest = version_names::<T>()
+        .into_iter()
+        .find(|version| stored_versions.contains(version))
+        .unwrap_or_else(|| storage_version.clone());
+
+    info!("CRD {crd_name}: migrating {stored_versions:?} to {storage_version}");
+    let resource = ApiResource::erase::<T>(&());
+    let objects: Api<DynamicObject> = Api::all_with(client.clone(), &resource);
+    for object in objects.list(&ListParams::default()).await? {
+        let name = object.name_any();
+        let api: Api<DynamicObject> = match object.namespace() {
+            Some(namespace) => Api::namespaced_with(client.clone(), &namespace, &resource),
+            None => Api::all_with(client.clone(), &resource),
+        };
+        let mut stored = serde_json::to_value(&object)?;
+        stored["apiVersion"] = json!(format!("{}/{oldest}", T::group(&())));
+        let converted: DynamicObject =
+            serde_json::from_value(convert::<T>(stored, &T::api_version(&()))?)?;
+
+        debug!("{}: rewriting {name}", resource.plural);
+        let mut replaced = api
+            .replace(&name, &PostParams::default(), &converted)
+            .await?;
+        // The status is not written through the main resource
+        if let Some(status) = converted
+            .data
+            .get("status")
+            .filter(|status| replaced.data.get("status") != Some(*status))
+        {
+            replaced.data["status"] = status.clone();
+            api.replace_status(
+                &name,
+                &PostParams::default(),
+                serde_json::to_vec(&replaced)?,
+            )
+            .await?;
+        }
+    }
+
+    let patch = json!({
+        "status": {
+            "storedVersions": [storage_version]
+        }
+    });
+    crds.patch_status(crd_name, &PatchParams::default(), &Patch::Merge(patch))
+        .await?;
+    Ok(())
+}
+
+/// Remove a version of a CRD from both its stored and served versions. The objects must have
+/// been migrated away from it, see remove_dropped_versions.
+#[instrument(skip(client))]
+pub async fn remove_crd_version(
+    crd_name: &str,
+    crd_version: &str,
+    client: Client,
+) -> Result<(), Error> {
+    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
+
+    // Delete from .status.stored_versions
+
+    let crd = crds.get(crd_name).await?;
+    let mut stored_versions = crd
+        .status
+        .and_then(|status| status.stored_versions)
+        .unwrap_or_default();
+
+    stored_versions.retain(|v| v != crd_version);
+
+    let patch = json!({
+        "status": {
+            "storedVersions": stored_versions
+        }
+    });
+
+    crds.patch_status(
+        crd_name,
+        &PatchParams::apply("cluster-manager.libvirt"),
+        &Patch::Merge(patch),
+    )
+    .await?;
+
+    // Delete from .spec.versions
+
+    let mut crd = crds.get(crd_name).await?;
+    crd.spec.versions.retain(|v| v.name != crd_version);
+    crds.replace(crd_name, &PostParams::default(), &crd).await?;
+    info!("CRD: Patching {:?}", &crd);
+    Ok(())
+}
@@ -1,25 +1,24 @@
 use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
-use kube::core::crd::merge_crds;
+use k8s_openapi::chrono::Utc;
 use kube::{
-    Api, Client, CustomResource, CustomResourceExt, Resource, ResourceExt,
-    api::{Patch, PatchParams, PostParams},
+    Api, Client, CustomResource, CustomResourceExt, Resource, ResourceExt, api::PostParams,
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use tracing::{debug, info};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
 
-use crate::crd::utils;
+use crate::crd::versioning::{self, VersionedCrd};
 use crate::errors::Error;
-use crate::utils::traits::kube::ApiExt;
-use crate::utils::{get_namespace_names, wait_crd_ready};
 use tracing::instrument;
 
-const CRD_NAME: &str = "virtualmachines.cluster-virt.acl.fi";
-
 #[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
 pub struct VolumeAttachment {
     pub name: String,
+
+    /// Delete the Volume together with the VM, through an owner reference to the VM
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delete_with_vm: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
@@ -48,9 +47,180 @@ pub struct NetworkAttachment {
     pub tagged_vlans: Option<Vec<u16>>,
 }
 
-mod latest {
-    pub const VERSION: &str = "v1beta3";
+/// Host PCI devices to pass through to the VM, matched by their IDs
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
+pub struct HostDeviceRequest {
+    /// PCI vendor ID in hex, e.g. "8086"
+    pub vendor_id: String,
+
+    /// PCI product ID in hex, e.g. "1889" for an Intel E810 virtual function
+    pub product_id: String,
+
+    /// Number of matching devices to pass through (default: 1)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+}
+
+/// Latest observation of some aspect of the VM, like the conditions of built-in resources
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
+pub struct VmCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// "True" or "False"
+    pub status: String,
+    /// Machine readable reason for the last transition
+    pub reason: String,
+    pub message: String,
+    /// RFC 3339 timestamp of the last status change
+    pub last_transition_time: String,
+}
+
+/// Values a VM can take from an InstanceType or a VirtualMachineTemplate
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
+pub struct TemplateValues {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpus: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub machine_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compatibility_mode: Option<bool>,
+}
+
+impl TemplateValues {
+    /// Fill the values not set here from a layer with lower precedence
+    pub fn or(self, other: TemplateValues) -> TemplateValues {
+        TemplateValues {
+            cpus: self.cpus.or(other.cpus),
+            memory: self.memory.or(other.memory),
+            cpu_model: self.cpu_model.or(other.cpu_model),
+            machine_type: self.machine_type.or(other.machine_type),
+            compatibility_mode: self.compatibility_mode.or(other.compatibility_mode),
+        }
+    }
+}
+
+/// The templates a VM has been resolved against and the values they gave
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
+pub struct AppliedTemplate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_type: Option<String>,
+    pub values: TemplateValues,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, JsonSchema)]
+pub enum DomainPatchOperation {
+    /// Append the XML fragment as the last child of the element
+    Add,
+    /// Replace the element with the XML fragment
+    Replace,
+    /// Remove the element
+    Remove,
+}
+
+/// A change to the libvirt domain XML generated for a VM, for settings which are not modelled
+/// in the spec, like a watchdog or a different video model
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, JsonSchema)]
+pub struct DomainPatch {
+    /// Absolute path of a single element in a subset of XPath: element names with optional
+    /// `[@attribute='value']` and 1-based `[index]` predicates, e.g.
+    /// `/domain/devices/interface[@type='bridge'][2]`
+    pub path: String,
+    pub operation: DomainPatchOperation,
+    /// XML fragment for Add and Replace
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xml: Option<String>,
+}
+
+/// Credentials the host applies inside the running guest through the QEMU guest agent, without
+/// a reboot. The Secret, in the namespace of the VM, may hold a `password` and
+/// `ssh-authorized-keys` with one key per line. Changes to the Secret are applied within a
+/// minute. Authorized keys are only ever added, removing a key from the Secret does not remove
+/// it from the guest. Only Secrets of the type or with the label
+/// `cluster-virt.acl.fi/guest-credentials: "true"` are used, so that editing a VM does not give
+/// access to the other Secrets of the namespace.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
+pub struct AccessCredentials {
+    pub secret_name: String,
+
+    /// Guest user whose password and authorized keys are set (default: root)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+/// A network interface as seen from inside the guest
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
+pub struct GuestInterface {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac_address: Option<String>,
+    /// Addresses without prefix length, leaving out loopback and IPv6 link-local addresses
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ip_addresses: Vec<String>,
+}
+
+/// Information reported by the QEMU guest agent running inside the VM
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
+pub struct GuestInfo {
+    /// Whether the guest agent answered the last time the host asked
+    pub agent_connected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub interfaces: Vec<GuestInterface>,
+}
+
+/// A rule placing a VM relative to other VMs selected by their labels
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
+pub struct VmAffinityTerm {
+    /// Labels another VM must have (all of them) to be matched by this term
+    pub match_labels: BTreeMap<String, String>,
+
+    /// Node label defining the topology domain, e.g. a rack label. Defaults to the node itself
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topology_key: Option<String>,
+
+    /// Hard terms remove nodes from scheduling, soft terms only affect scoring (default: true)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required: Option<bool>,
+
+    /// Weight of a soft term when scoring nodes (default: 1)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight: Option<i64>,
+}
 
+/// A weighted preference for nodes with the given labels
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
+pub struct NodePreference {
+    pub match_labels: BTreeMap<String, String>,
+    pub weight: i64,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
+pub struct Affinity {
+    /// Place the VM in the same topology domain as the matched VMs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vm_affinity: Option<Vec<VmAffinityTerm>>,
+
+    /// Keep the VM out of the topology domains of the matched VMs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vm_anti_affinity: Option<Vec<VmAffinityTerm>>,
+
+    /// Prefer nodes with certain labels
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_preferences: Option<Vec<NodePreference>>,
+}
+
+mod latest {
     pub type VirtualMachine = super::v1beta3::VirtualMachine;
     pub type VirtualMachineStatus = super::v1beta3::VirtualMachineStatus;
 }
@@ -111,13 +281,16 @@ pub mod v1beta3 {
         shortname = "vm",
         namespaced,
         printcolumn = r#"{"name":"Node", "type":"string", "description":"Node the VM is scheduled to", "jsonPath":".status.node"}"#,
-        printcolumn = r#"{"name":"IPs", "type":"string", "description":"Dynamic IPs assigned", "jsonPath":".status.ip_addresses_string"}"#
+        printcolumn = r#"{"name":"IPs", "type":"string", "description":"IPs assigned by OVN or reported by the guest agent", "jsonPath":".status.ip_addresses_string"}"#
     )]
     pub struct VirtualMachineSpec {
+        /// May be left out when given by the instance type or template
+        #[serde(default)]
         pub cpus: usize,
         #[serde(skip_serializing_if = "Option::is_none")]
         pub cpu_model: Option<String>,
         // String to allow suffixes like '1 Gi'
+        #[serde(default)]
         pub memory: String,
         pub volumes: Vec<VolumeAttachment>,
         pub networks: Vec<NetworkAttachment>,
@@ -140,6 +313,77 @@ pub mod v1beta3 {
 
         #[serde(skip_serializing_if = "Option::is_none")]
         pub machine_type: Option<String>,
+
+        /// Only schedule the VM to nodes which have all of these labels
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub node_selector: Option<BTreeMap<String, String>>,
+
+        /// Placement rules relative to other VMs and node preferences
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub affinity: Option<Affinity>,
+
+        /// Scheduling priority, higher is more important. Defaults to 0
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub priority: Option<i32>,
+
+        /// Whether the VM may evict lower priority VMs when no node has room for it
+        /// Options:
+        /// - Never: Wait until there is room (default)
+        /// - PreemptLowerPriority: Migrate lower priority VMs elsewhere, or shut them down
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub preemption_policy: Option<PreemptionPolicy>,
+
+        /// How the vCPUs are backed by host CPUs
+        /// Options:
+        /// - Shared: vCPUs float over the host CPUs shared with other VMs (default)
+        /// - Dedicated: every vCPU is pinned to a host CPU no other VM uses
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub cpu_policy: Option<CpuPolicy>,
+
+        /// NUMA placement of a VM with dedicated CPUs
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub numa: Option<NumaPlacement>,
+
+        /// Back the guest memory with hugepages of the given size, 2M or 1G
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub hugepages: Option<HugepageSize>,
+
+        /// Firmware the VM boots with
+        /// Options:
+        /// - Bios: Legacy BIOS boot (default)
+        /// - Uefi: UEFI boot with the variables kept in a per-VM NVRAM image
+        /// - UefiSecureBoot: UEFI boot with Secure Boot enforced
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub firmware: Option<Firmware>,
+
+        /// Attach an emulated TPM 2.0, with its state kept in a per-VM image
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub tpm: Option<bool>,
+
+        /// Host PCI devices, like SR-IOV virtual functions or GPUs, passed through to the VM.
+        /// VMs with host devices can not be live migrated.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub host_devices: Option<Vec<HostDeviceRequest>>,
+
+        /// Password and SSH keys to set inside the guest, e.g. to recover access to the VM
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub access_credentials: Option<AccessCredentials>,
+
+        /// Patches applied to the domain XML after the ones of the Cluster. The result is
+        /// validated against the libvirt schema before the domain is started.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub domain_overrides: Option<Vec<DomainPatch>>,
+
+        /// Cluster-scoped InstanceType giving cpus, memory, cpu_model and machine_type. Takes
+        /// precedence over the template, values set in the spec itself over both.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub instance_type: Option<String>,
+
+        /// VirtualMachineTemplate in the namespace of the VM. The values are recorded in the
+        /// status once, later edits of the template or instance type only apply to VMs which
+        /// switch to another one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub template: Option<String>,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
@@ -152,6 +396,80 @@ pub mod v1beta3 {
         pub ip_addresses: Option<Vec<String>>,
         pub ip_addresses_string: Option<String>,
         pub networks: Vec<NetworkAttachment>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub conditions: Vec<VmCondition>,
+        /// Dynamic addresses assigned by OVN, which together with the addresses reported by the
+        /// guest agent make up ip_addresses
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub ovn_ip_addresses: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub guest: Option<GuestInfo>,
+        /// Version of the access credentials Secret last applied in the guest, as
+        /// <name>/<resourceVersion>
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub applied_credentials: Option<String>,
+        /// Instance type and template the VM has been resolved against, with the values they
+        /// give for the fields left out of the spec
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub template: Option<AppliedTemplate>,
+    }
+
+    impl VirtualMachineStatus {
+        /// Combine the addresses assigned by OVN and the ones reported by the guest agent into
+        /// ip_addresses
+        pub fn refresh_ip_addresses(&mut self) {
+            let mut ip_addresses = self.ovn_ip_addresses.clone();
+            let guest_addresses = self
+                .guest
+                .iter()
+                .flat_map(|guest| guest.interfaces.iter())
+                .flat_map(|interface| interface.ip_addresses.iter());
+            for address in guest_addresses {
+                if !ip_addresses.contains(address) {
+                    ip_addresses.push(address.clone());
+                }
+            }
+            self.ip_addresses_string = Some(ip_addresses.join(","));
+            self.ip_addresses = Some(ip_addresses);
+        }
+
+        /// Set a condition, keeping the transition time if its status does not change. Returns
+        /// whether anything changed.
+        pub fn set_condition(
+            &mut self,
+            type_: &str,
+            status: bool,
+            reason: &str,
+            message: &str,
+        ) -> bool {
+            let status = if status { "True" } else { "False" };
+            let existing = self.conditions.iter_mut().find(|c| c.type_ == type_);
+
+            match existing {
+                Some(c) if c.status == status && c.reason == reason && c.message == message => {
+                    false
+                }
+                Some(c) => {
+                    if c.status != status {
+                        c.last_transition_time = Utc::now().to_rfc3339();
+                    }
+                    c.status = status.to_string();
+                    c.reason = reason.to_string();
+                    c.message = message.to_string();
+                    true
+                }
+                None => {
+                    self.conditions.push(VmCondition {
+                        type_: type_.to_string(),
+                        status: status.to_string(),
+                        reason: reason.to_string(),
+                        message: message.to_string(),
+                        last_transition_time: Utc::now().to_rfc3339(),
+                    });
+                    true
+                }
+            }
+        }
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
@@ -162,6 +480,124 @@ pub mod v1beta3 {
         Manual,
     }
 
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+    pub enum PreemptionPolicy {
+        Never,
+        PreemptLowerPriority,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+    pub enum CpuPolicy {
+        Shared,
+        Dedicated,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+    pub enum NumaMemoryMode {
+        Strict,
+        Preferred,
+        Interleave,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+    pub enum Firmware {
+        Bios,
+        Uefi,
+        UefiSecureBoot,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+    pub enum HugepageSize {
+        #[serde(rename = "2M")]
+        Size2M,
+        #[serde(rename = "1G")]
+        Size1G,
+    }
+
+    impl HugepageSize {
+        pub fn size_kib(&self) -> u64 {
+            match self {
+                HugepageSize::Size2M => 2 * 1024,
+                HugepageSize::Size1G => 1024 * 1024,
+            }
+        }
+
+        /// Name of the size as used in the LibvirtNode status
+        pub fn name(&self) -> &'static str {
+            match self {
+                HugepageSize::Size2M => "2M",
+                HugepageSize::Size1G => "1G",
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
+    pub struct NumaPlacement {
+        /// Take all dedicated CPUs from a single host NUMA cell (default: true)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub single_cell: Option<bool>,
+
+        /// Binding of the guest memory to the cell of the CPUs, as in libvirt numatune.
+        /// Defaults to Strict.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub memory_mode: Option<NumaMemoryMode>,
+    }
+
+    /// The effective values of the fields a template or instance type may give: the value of
+    /// the spec, or else the one recorded in the status when the template was resolved
+    impl VirtualMachine {
+        fn template_values(&self) -> Option<&TemplateValues> {
+            self.status
+                .as_ref()
+                .and_then(|status| status.template.as_ref())
+                .map(|applied| &applied.values)
+        }
+
+        pub fn cpus(&self) -> usize {
+            match self.spec.cpus {
+                0 => self
+                    .template_values()
+                    .and_then(|values| values.cpus)
+                    .unwrap_or(0),
+                cpus => cpus,
+            }
+        }
+
+        pub fn memory(&self) -> &str {
+            match self.spec.memory.as_str() {
+                "" => self
+                    .template_values()
+                    .and_then(|values| values.memory.as_deref())
+                    .unwrap_or_default(),
+                memory => memory,
+            }
+        }
+
+        pub fn cpu_model(&self) -> Option<&String> {
+            let template = self.template_values();
+            self.spec
+                .cpu_model
+                .as_ref()
+                .or(template.and_then(|values| values.cpu_model.as_ref()))
+        }
+
+        pub fn machine_type(&self) -> Option<&String> {
+            let template = self.template_values();
+            self.spec
+                .machine_type
+                .as_ref()
+                .or(template.and_then(|values| values.machine_type.as_ref()))
+        }
+
+        pub fn compatibility_mode(&self) -> bool {
+            let template = self.template_values();
+            self.spec
+                .compatibility_mode
+                .or(template.and_then(|values| values.compatibility_mode))
+                .unwrap_or(false)
+        }
+    }
+
     impl VirtualMachineSpec {
         pub fn get_power_action(&self) -> PowerAction {
             if let Some(action) = self.power_action.as_ref() {
@@ -170,100 +606,109 @@ pub mod v1beta3 {
                 PowerAction::PowerOn
             }
         }
+
+        pub fn get_priority(&self) -> i32 {
+            self.priority.unwrap_or(0)
+        }
+
+        pub fn may_preempt(&self) -> bool {
+            self.preemption_policy == Some(PreemptionPolicy::PreemptLowerPriority)
+        }
+
+        pub fn get_firmware(&self) -> Firmware {
+            self.firmware.clone().unwrap_or(Firmware::Bios)
+        }
+
+        pub fn has_tpm(&self) -> bool {
+            self.tpm.unwrap_or(false)
+        }
+
+        pub fn has_host_devices(&self) -> bool {
+            self.host_devices
+                .as_ref()
+                .is_some_and(|devices| !devices.is_empty())
+        }
+
+        pub fn has_dedicated_cpus(&self) -> bool {
+            self.cpu_policy == Some(CpuPolicy::Dedicated)
+        }
+
+        pub fn numa_single_cell(&self) -> bool {
+            self.numa
+                .as_ref()
+                .and_then(|numa| numa.single_cell)
+                .unwrap_or(true)
+        }
+
+        /// Memory mode for libvirt numatune
+        pub fn numa_memory_mode(&self) -> &'static str {
+            match self
+                .numa
+                .as_ref()
+                .and_then(|numa| numa.memory_mode.as_ref())
+            {
+                Some(NumaMemoryMode::Preferred) => "preferred",
+                Some(NumaMemoryMode::Interleave) => "interleave",
+                Some(NumaMemoryMode::Strict) | None => "strict",
+            }
+        }
     }
 }
 
-#[instrument(skip(client))]
-pub async fn create(client: Client) -> Result<(), Error> {
-    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
-    let patch_params = PatchParams::apply("cluster-manager.libvirt").force();
-
-    if let Ok(crd) = crds.get(CRD_NAME).await {
-        let current_versions: Vec<String> = crd
-            .spec
-            .versions
-            .into_iter()
-            .map(|version| version.name)
-            .collect();
-        if current_versions == [String::from(latest::VERSION)] {
-            info!("CRD virtualmachines: no migrations required");
-
-            let latest_crd = latest::VirtualMachine::crd();
-            crds.patch(CRD_NAME, &patch_params, &Patch::Apply(&latest_crd))
-                .await?;
-
-            return Ok(());
-        }
+impl VersionedCrd for latest::VirtualMachine {
+    fn versions() -> Vec<CustomResourceDefinition> {
+        vec![
+            v1beta2::VirtualMachine::crd(),
+            v1beta3::VirtualMachine::crd(),
+        ]
     }
 
-    // Create all possible versions to exist in parallel
-    let crd_versions = vec![
-        v1beta2::VirtualMachine::crd(),
-        v1beta3::VirtualMachine::crd(),
-    ];
-    let crd = merge_crds(crd_versions, latest::VERSION)?;
-    crds.patch(CRD_NAME, &patch_params, &Patch::Apply(&crd))
-        .await?;
-    wait_crd_ready(&crds, CRD_NAME).await?;
-
-    // Migrate all objects to the latest version
-    run_migrations(client.clone()).await?;
-
-    // Remove all but the latest CRD version
-    let latest_crd = latest::VirtualMachine::crd();
-    crds.patch(CRD_NAME, &patch_params, &Patch::Apply(&latest_crd))
-        .await?;
-
-    Ok(())
+    fn upgrade(version: &str, mut object: Value) -> Result<Value, Error> {
+        // v1beta3 keeps the generated MAC addresses and OVN port IDs in the status
+        if version == "v1beta2" && object["status"].is_object() {
+            object["status"]["networks"] = object["spec"]["networks"].clone();
+        }
+        Ok(object)
+    }
 }
 
-#[instrument(skip(client))]
-pub async fn run_migrations(client: Client) -> Result<(), Error> {
-    const CRD_NAME: &str = "virtualmachines.cluster-virt.acl.fi";
-    let patch_params = PatchParams::apply("cluster-manager.libvirt");
-    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
-    let vm_crd = crds.get(CRD_NAME).await?;
-
-    let status = vm_crd.status.expect("CRD has no status").clone();
-
-    for version in status.stored_versions.clone().unwrap_or_default() {
-        debug!("CRD virtualmachines: checking version {}", &version);
-        if &version == "v1beta2" {
-            info!("CRD virtualmachines: upgrading {}", &version);
-            for namespace in get_namespace_names(client.clone()).await? {
-                let api_v1beta2: Api<v1beta2::VirtualMachine> =
-                    Api::namespaced(client.clone(), &namespace);
-                let api_v1beta3: Api<v1beta3::VirtualMachine> =
-                    Api::namespaced(client.clone(), &namespace);
-
-                let vms_v1beta2 = api_v1beta2.list_default().await?;
-                for vm in vms_v1beta2 {
-                    debug!(
-                        "CRD virtualmachines: Upgrading {} {}/{}",
-                        &version,
-                        &namespace,
-                        &vm.metadata.name.as_ref().unwrap()
-                    );
-                    let patch = json!({
-                        "status": {
-                            "networks": &vm.spec.networks,
-                        }
-                    });
-                    api_v1beta3
-                        .patch_status(
-                            vm.metadata.name.as_ref().expect("vm has no name"),
-                            &patch_params,
-                            &Patch::Merge(patch),
-                        )
-                        .await?;
-                }
-            }
-            debug!("CRD virtualmachines: removing version {}", &version);
-            utils::remove_crd_version(CRD_NAME, &version, client.clone()).await?;
+#[cfg(test)]
+#[test]
+fn test_convert() {
+    use crate::crd::versioning::convert;
+
+    let v1beta2 = json!({
+        "apiVersion": "cluster-virt.acl.fi/v1beta2",
+        "kind": "VirtualMachine",
+        "metadata": {"name": "vm", "namespace": "test"},
+        "spec": {
+            "cpus": 1,
+            "memory": "1 GiB",
+            "volumes": [],
+            "networks": [{"name": "lan", "mac_address": "52:54:00:01:02:03"}]
+        },
+        "status": {
+            "scheduled": true,
+            "running": true,
+            "migration_pending": false,
+            "domain_name": "test-vm"
         }
-    }
+    });
+    let v1beta3 =
+        convert::<VirtualMachine>(v1beta2.clone(), "cluster-virt.acl.fi/v1beta3").unwrap();
+    assert_eq!(v1beta3["apiVersion"], "cluster-virt.acl.fi/v1beta3");
+    assert_eq!(v1beta3["status"]["networks"], v1beta2["spec"]["networks"]);
+    let vm: VirtualMachine = serde_json::from_value(v1beta3.clone()).unwrap();
+    assert_eq!(vm.status.unwrap().networks.len(), 1);
+
+    let back = convert::<VirtualMachine>(v1beta3, "cluster-virt.acl.fi/v1beta2").unwrap();
+    assert_eq!(back["apiVersion"], "cluster-virt.acl.fi/v1beta2");
+    assert!(convert::<VirtualMachine>(back, "cluster-virt.acl.fi/v1").is_err());
+}
 
-    Ok(())
+#[instrument(skip(client))]
+pub async fn create(client: Client) -> Result<(), Error> {
+    versioning::create::<latest::VirtualMachine>(client, "cluster-manager.libvirt").await
 }
 
 pub(crate) type VirtualMachine = latest::VirtualMachine;
@@ -0,0 +1,84 @@
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use kube::{
+    Api, Client, CustomResource, CustomResourceExt,
+    api::{Patch, PatchParams},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::instrument;
+
+use crate::crd::virtualmachine::v1beta3::VirtualMachineSpec;
+use crate::errors::Error;
+use crate::utils::wait_crd_ready;
+
+const CRD_NAME: &str = "virtualmachinepools.cluster-virt.acl.fi";
+
+/// A Volume created for every replica of a pool
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema)]
+pub struct PoolVolumeTemplate {
+    /// Name of the volume within a replica, the Volume is named <replica>-<name>
+    pub name: String,
+    // String to allow suffixes like '10 Gi'
+    pub size: String,
+    /// Image in the namespace of the pool to clone the volume from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+}
+
+/// A fleet of identical, stateless VMs
+#[derive(
+    CustomResource, Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema,
+)]
+#[kube(
+    group = "cluster-virt.acl.fi",
+    version = "v1beta",
+    kind = "VirtualMachinePool",
+    status = "VirtualMachinePoolStatus",
+    derive = "PartialEq",
+    derive = "Default",
+    shortname = "vmp",
+    namespaced,
+    printcolumn = r#"{"name":"Replicas", "type":"integer", "jsonPath":".spec.replicas"}"#,
+    printcolumn = r#"{"name":"Ready", "type":"integer", "jsonPath":".status.ready_replicas"}"#,
+    printcolumn = r#"{"name":"Updated", "type":"integer", "jsonPath":".status.updated_replicas"}"#
+)]
+pub struct VirtualMachinePoolSpec {
+    pub replicas: usize,
+
+    /// Labels added to every replica
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+
+    /// Spec of the replicas. The volumes created from the volume templates are attached before
+    /// the volumes listed here.
+    pub template: VirtualMachineSpec,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volume_templates: Vec<PoolVolumeTemplate>,
+
+    /// Replicas which may be unavailable while outdated replicas are replaced (default: 1)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_unavailable: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq, JsonSchema)]
+pub struct VirtualMachinePoolStatus {
+    pub replicas: usize,
+    pub ready_replicas: usize,
+    /// Replicas created from the current template
+    pub updated_replicas: usize,
+    pub template_hash: String,
+}
+
+#[instrument(skip(client))]
+pub async fn create(client: Client) -> Result<(), Error> {
+    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
+    let patch_params = PatchParams::apply("virt-controller").force();
+
+    let crd = VirtualMachinePool::crd();
+    crds.patch(CRD_NAME, &patch_params, &Patch::Apply(&crd))
+        .await?;
+    wait_crd_ready(&crds, CRD_NAME).await?;
+    Ok(())
+}
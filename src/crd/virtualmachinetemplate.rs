@@ -0,0 +1,48 @@
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use kube::{
+    Api, Client, CustomResource, CustomResourceExt,
+    api::{Patch, PatchParams},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::crd::virtualmachine::TemplateValues;
+use crate::errors::Error;
+use crate::utils::wait_crd_ready;
+
+const CRD_NAME: &str = "virtualmachinetemplates.cluster-virt.acl.fi";
+
+/// Shared settings for VMs in a namespace, referenced by VMs
+#[derive(
+    CustomResource, Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone, JsonSchema,
+)]
+#[kube(
+    group = "cluster-virt.acl.fi",
+    version = "v1beta",
+    kind = "VirtualMachineTemplate",
+    derive = "PartialEq",
+    derive = "Default",
+    shortname = "vmt",
+    namespaced
+)]
+pub struct VirtualMachineTemplateSpec {
+    /// InstanceType filling in the values not set in the template itself
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_type: Option<String>,
+
+    #[serde(flatten)]
+    pub values: TemplateValues,
+}
+
+#[instrument(skip(client))]
+pub async fn create(client: Client) -> Result<(), Error> {
+    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
+    let patch_params = PatchParams::apply("virt-controller").force();
+
+    let crd = VirtualMachineTemplate::crd();
+    crds.patch(CRD_NAME, &patch_params, &Patch::Apply(&crd))
+        .await?;
+    wait_crd_ready(&crds, CRD_NAME).await?;
+    Ok(())
+}
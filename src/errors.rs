@@ -70,6 +70,10 @@ pub enum Error {
     KubeWatcher(#[from] kube::runtime::watcher::Error),
     #[error("CRD version merge error {0}")]
     CrdMerge(#[from] kube::core::crd::MergeError),
+    #[error("CRD conversion error {0}")]
+    CrdConversion(String),
+    #[error("Lost the leader election: {0}")]
+    LeadershipLost(String),
     #[error("Resource {0} has no status")]
     NoStatusSubresource(String),
 
@@ -86,6 +90,18 @@ pub enum Error {
     ScheduleFailed(String),
     #[error("failed to parse storage location: {0}")]
     StorageLocationParse(String),
+    #[error("host {0} is not compatible with the VM: {1}")]
+    IncompatibleHost(String, String),
+    #[error("not enough free host CPUs to dedicate: {0}")]
+    CpuAllocationFailed(String),
+    #[error("no free host PCI devices to pass through: {0}")]
+    PciAllocationFailed(String),
+    #[error("invalid domain overrides: {0}")]
+    InvalidDomainOverrides(String),
+    #[error("failed to resolve VM template: {0}")]
+    TemplateResolution(String),
+    #[error("invalid memory size {0}, expected an amount and unit like 4 GiB")]
+    InvalidMemory(String),
 
     // OVN
     #[error("OVN central nodes not found")]
@@ -108,6 +124,8 @@ pub enum Error {
     Template(#[from] askama::Error),
     #[error("Error parsing CIDR: {0}")]
     ParseNetwork(#[from] ipnet::AddrParseError),
+    #[error("Error parsing XML: {0}")]
+    Xml(#[from] roxmltree::Error),
 
     // Metadata proxy
     #[error("Failed to send metadata request between threads")]
@@ -131,6 +149,14 @@ pub enum Error {
     #[error("ConfigMap {0} invalid: {1}")]
     ConfigMapInvalid(String, String),
 
+    // Console gateway
+    #[error("Unauthenticated: {0}")]
+    Unauthenticated(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Console not available: {0}")]
+    ConsoleUnavailable(String),
+
     // Host network configuration
     #[error("Error mapping VNI: {0}")]
     VniMapping(String),
@@ -142,6 +168,36 @@ pub enum Error {
     ClusterNotFound(#[from] ClusterNotFound),
     #[error("Task ended unexpectedly: {0}")]
     UnexpectedExit(String),
+    #[error("Failed to serve health checks on port {0}: {1}")]
+    HealthServerBind(u16, String),
     #[error("Feature not implemented: {0}")]
     NotImplemented(String),
+    #[error("Invalid resource: {0}")]
+    InvalidResource(String),
+    #[error("Invalid arguments: {0}")]
+    InvalidArguments(String),
+}
+
+impl Error {
+    /// Whether retrying can not help until the resource is changed, e.g. an invalid spec
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            Error::StorageLocationParse(_)
+                | Error::InvalidDomainOverrides(_)
+                | Error::InvalidMemory(_)
+                | Error::InvalidResource(_)
+                | Error::ParseHumanize(_)
+                | Error::ParseNetwork(_)
+                | Error::NotImplemented(_)
+        )
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_is_permanent() {
+    assert!(Error::StorageLocationParse(String::from("rbd:")).is_permanent());
+    assert!(!Error::OvnConnection(Box::new(Error::OvnCentralNodesNotFound)).is_permanent());
+    assert!(!Error::Timeout(String::from("migration")).is_permanent());
 }
@@ -0,0 +1,132 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use k8s_openapi::api::authentication::v1::{TokenReview, TokenReviewSpec};
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SubjectAccessReview, SubjectAccessReviewSpec,
+};
+use kube::api::PostParams;
+use kube::{Api, Client};
+
+use crate::GROUP_NAME;
+use crate::errors::Error;
+
+/// Browsers can't set headers on WebSocket requests, so like the Kubernetes API server the token
+/// can also be given as a `Sec-WebSocket-Protocol` of this prefix and the base64url encoded token
+const TOKEN_PROTOCOL_PREFIX: &str = "base64url.bearer.authorization.k8s.io.";
+
+/// Extract the token from an `Authorization: Bearer <token>` header, or failing that from the
+/// WebSocket subprotocols offered in `Sec-WebSocket-Protocol`
+pub fn bearer_token(header: Option<String>, protocols: Option<&str>) -> Result<String, Error> {
+    let from_header = header
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+    let from_protocol = || {
+        protocols?
+            .split(',')
+            .find_map(|protocol| protocol.trim().strip_prefix(TOKEN_PROTOCOL_PREFIX))
+            .and_then(|encoded| URL_SAFE_NO_PAD.decode(encoded.trim_end_matches('=')).ok())
+            .and_then(|token| String::from_utf8(token).ok())
+    };
+    from_header
+        .or_else(from_protocol)
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| Error::Unauthenticated(String::from("no bearer token given")))
+}
+
+#[cfg(test)]
+#[test]
+fn test_bearer_token() {
+    assert_eq!(
+        bearer_token(Some(String::from("Bearer abc.def")), None).unwrap(),
+        "abc.def"
+    );
+    assert!(bearer_token(Some(String::from("Basic abc")), None).is_err());
+    assert!(bearer_token(Some(String::from("Bearer ")), None).is_err());
+    assert!(bearer_token(None, None).is_err());
+    assert_eq!(
+        bearer_token(
+            None,
+            Some("binary, base64url.bearer.authorization.k8s.io.YWJjLmRlZg")
+        )
+        .unwrap(),
+        "abc.def"
+    );
+    assert!(bearer_token(None, Some("base64url.bearer.authorization.k8s.io.!!")).is_err());
+}
+
+/// The subprotocol to accept for the WebSocket, any offered one besides the token. Browsers drop
+/// the connection unless one of the offered protocols is accepted.
+pub fn accepted_protocol(protocols: Option<&str>) -> Option<String> {
+    protocols?
+        .split(',')
+        .map(str::trim)
+        .find(|protocol| !protocol.is_empty() && !protocol.starts_with(TOKEN_PROTOCOL_PREFIX))
+        .map(String::from)
+}
+
+/// Check that the token is valid and that its owner may use the given subresource (console or
+/// vnc) of the VM. Access is granted through RBAC rules like for any other subresource, e.g.
+/// `get` on `virtualmachines/console`. Returns the name of the user.
+pub async fn authorize(
+    client: Client,
+    token: String,
+    namespace: &str,
+    name: &str,
+    subresource: &str,
+) -> Result<String, Error> {
+    let token_reviews: Api<TokenReview> = Api::all(client.clone());
+    let review = TokenReview {
+        spec: TokenReviewSpec {
+            token: Some(token),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let status = token_reviews
+        .create(&PostParams::default(), &review)
+        .await?
+        .status
+        .unwrap_or_default();
+    if !status.authenticated.unwrap_or(false) {
+        return Err(Error::Unauthenticated(
+            status
+                .error
+                .unwrap_or_else(|| String::from("token rejected")),
+        ));
+    }
+    let user = status.user.unwrap_or_default();
+
+    let access_reviews: Api<SubjectAccessReview> = Api::all(client);
+    let review = SubjectAccessReview {
+        spec: SubjectAccessReviewSpec {
+            user: user.username.clone(),
+            uid: user.uid,
+            groups: user.groups,
+            extra: user.extra,
+            resource_attributes: Some(ResourceAttributes {
+                group: Some(GROUP_NAME.to_string()),
+                resource: Some(String::from("virtualmachines")),
+                subresource: Some(subresource.to_string()),
+                namespace: Some(namespace.to_string()),
+                name: Some(name.to_string()),
+                verb: Some(String::from("get")),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let status = access_reviews
+        .create(&PostParams::default(), &review)
+        .await?
+        .status
+        .unwrap_or_default();
+    let username = user.username.unwrap_or_default();
+    if !status.allowed {
+        return Err(Error::Forbidden(format!(
+            "{username} may not access {subresource} of {namespace}/{name}"
+        )));
+    }
+    Ok(username)
+}
@@ -0,0 +1,196 @@
+use kube::{Api, Client};
+use std::env;
+use std::path::Path;
+use tracing::{info, warn};
+use warp::http::header::SEC_WEBSOCKET_PROTOCOL;
+use warp::http::{HeaderValue, StatusCode};
+use warp::ws::Ws;
+use warp::{Filter, Rejection, Reply};
+
+use crate::crd::virtualmachine::VirtualMachine;
+use crate::errors::Error;
+use crate::utils::traits::kube::TryStatus;
+
+mod auth;
+mod serial;
+mod vnc;
+
+/// The gateway runs next to the host controller on every node, as the VNC servers only listen
+/// on the loopback address of the host
+pub const CONSOLE_GATEWAY_PORT: u16 = 5980;
+/// The certificate is mounted here from a TLS secret. Without one the gateway only starts when
+/// $CONSOLE_GATEWAY_INSECURE is "true", as the bearer tokens would be sent in cleartext.
+pub const CONSOLE_GATEWAY_TLS_PATH: &str = "/etc/console-gateway";
+const INSECURE_ENV: &str = "CONSOLE_GATEWAY_INSECURE";
+
+#[derive(Clone, Copy)]
+enum ConsoleType {
+    Serial,
+    Vnc,
+}
+
+impl ConsoleType {
+    /// Name of the VM subresource which RBAC rules grant access to
+    fn subresource(&self) -> &'static str {
+        match self {
+            ConsoleType::Serial => "console",
+            ConsoleType::Vnc => "vnc",
+        }
+    }
+}
+
+/// Authorize the client and resolve the domain of the VM, which has to run on this node
+async fn resolve_domain(
+    client: Client,
+    authorization: Option<String>,
+    protocols: Option<&str>,
+    namespace: &str,
+    name: &str,
+    console_type: ConsoleType,
+) -> Result<String, Error> {
+    let token = auth::bearer_token(authorization, protocols)?;
+    let user = auth::authorize(
+        client.clone(),
+        token,
+        namespace,
+        name,
+        console_type.subresource(),
+    )
+    .await?;
+
+    let vms: Api<VirtualMachine> = Api::namespaced(client, namespace);
+    let vm = vms.get(name).await?;
+    let status = vm.try_status()?;
+    let node_name = env::var("NODE_NAME").expect("failed to read $NODE_NAME");
+    match &status.node {
+        Some(node) if status.running && *node == node_name => {}
+        Some(node) if status.running => {
+            return Err(Error::ConsoleUnavailable(format!(
+                "{namespace}/{name} runs on node {node}, connect to the gateway there"
+            )));
+        }
+        _ => {
+            return Err(Error::ConsoleUnavailable(format!(
+                "{namespace}/{name} is not running"
+            )));
+        }
+    }
+    info!(
+        "Granting {user} access to {} of {namespace}/{name}",
+        console_type.subresource()
+    );
+    Ok(status.domain_name.clone())
+}
+
+fn error_reply(error: Error) -> warp::reply::Response {
+    let status = match error {
+        Error::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+        Error::Forbidden(_) => StatusCode::FORBIDDEN,
+        Error::Kube(kube::Error::Api(ref response)) if response.code == 404 => {
+            StatusCode::NOT_FOUND
+        }
+        Error::ConsoleUnavailable(_) => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    warn!("Refused console request: {error}");
+    warp::reply::with_status(format!("{error}\n"), status).into_response()
+}
+
+async fn handle_console(
+    client: Client,
+    namespace: String,
+    name: String,
+    console_type: ConsoleType,
+    authorization: Option<String>,
+    protocols: Option<String>,
+    ws: Ws,
+) -> Result<warp::reply::Response, Rejection> {
+    let domain_name = match resolve_domain(
+        client,
+        authorization,
+        protocols.as_deref(),
+        &namespace,
+        &name,
+        console_type,
+    )
+    .await
+    {
+        Ok(domain_name) => domain_name,
+        Err(error) => return Ok(error_reply(error)),
+    };
+    let mut reply = match console_type {
+        ConsoleType::Serial => ws
+            .on_upgrade(move |socket| serial::proxy_serial_console(socket, domain_name))
+            .into_response(),
+        ConsoleType::Vnc => ws
+            .on_upgrade(move |socket| vnc::proxy_vnc(socket, domain_name))
+            .into_response(),
+    };
+    if let Some(protocol) = auth::accepted_protocol(protocols.as_deref()) {
+        if let Ok(value) = HeaderValue::from_str(&protocol) {
+            reply.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+        }
+    }
+    Ok(reply)
+}
+
+/// Serve the serial console and VNC of the VMs running on this node over WebSocket at
+/// `/namespaces/<namespace>/virtualmachines/<name>/{console,vnc}`. Clients authenticate with a
+/// Kubernetes bearer token, in the `Authorization` header or as a WebSocket subprotocol.
+pub async fn run(client: Client) -> Result<(), Error> {
+    let console_type = warp::path("console")
+        .map(|| ConsoleType::Serial)
+        .or(warp::path("vnc").map(|| ConsoleType::Vnc))
+        .unify();
+    let routes = warp::path!("namespaces" / String / "virtualmachines" / String / ..)
+        .and(console_type)
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::ws())
+        .and_then(
+            move |namespace: String,
+                  name: String,
+                  console_type: ConsoleType,
+                  authorization: Option<String>,
+                  protocols: Option<String>,
+                  ws: Ws| {
+                handle_console(
+                    client.clone(),
+                    namespace,
+                    name,
+                    console_type,
+                    authorization,
+                    protocols,
+                    ws,
+                )
+            },
+        )
+        .with(warp::log("console"));
+
+    let certificate = Path::new(CONSOLE_GATEWAY_TLS_PATH).join("tls.crt");
+    let key = Path::new(CONSOLE_GATEWAY_TLS_PATH).join("tls.key");
+    if certificate.exists() && key.exists() {
+        info!("Serving console gateway over HTTPS on port {CONSOLE_GATEWAY_PORT}");
+        warp::serve(routes)
+            .tls()
+            .cert_path(certificate)
+            .key_path(key)
+            .run(([0, 0, 0, 0], CONSOLE_GATEWAY_PORT))
+            .await;
+    } else if env::var(INSECURE_ENV).is_ok_and(|value| value == "true") {
+        warn!(
+            "No TLS certificate found, serving console gateway over plain HTTP as ${INSECURE_ENV} is set"
+        );
+        warp::serve(routes)
+            .run(([0, 0, 0, 0], CONSOLE_GATEWAY_PORT))
+            .await;
+    } else {
+        return Err(Error::ConsoleUnavailable(format!(
+            "no TLS certificate in {CONSOLE_GATEWAY_TLS_PATH}, refusing to accept tokens over plain HTTP unless ${INSECURE_ENV} is \"true\""
+        )));
+    }
+    Err(Error::UnexpectedExit(String::from(
+        "console gateway HTTP API (warp) died",
+    )))
+}
@@ -0,0 +1,112 @@
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use tokio::sync::mpsc::channel;
+use tracing::{info, warn};
+use virt::domain::Domain;
+use virt::stream::Stream;
+use warp::ws::{Message, WebSocket};
+
+use crate::errors::Error;
+use crate::host::libvirt::{LIBVIRT_URI, Libvirt};
+
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// virt::stream::Stream does not implement Send due to the raw pointer to
+/// the virStream instance, see the note on Libvirt about thread safety.
+struct ConsoleStream(Stream);
+
+unsafe impl Send for ConsoleStream {}
+unsafe impl Sync for ConsoleStream {}
+
+impl ConsoleStream {
+    /// Abort the stream, which also wakes up a thread blocked in recv
+    fn abort(&self) {
+        unsafe {
+            virt::sys::virStreamAbort(self.0.as_ptr());
+        }
+    }
+}
+
+/// Open the serial console of the domain as a libvirt stream. Any existing console session is
+/// disconnected, like with `virsh console --force`.
+fn open_console(libvirt: &Libvirt, domain_name: &str) -> Result<ConsoleStream, Error> {
+    let stream = Stream::new(&libvirt.connection, 0)?;
+    let domain = Domain::lookup_by_name(&libvirt.connection, domain_name)?;
+    domain.open_console(None, &stream, virt::sys::VIR_DOMAIN_CONSOLE_FORCE)?;
+    Ok(ConsoleStream(stream))
+}
+
+/// Pass the serial console of the domain to the WebSocket and back until either end closes
+pub async fn proxy_serial_console(socket: WebSocket, domain_name: String) {
+    info!("Opening serial console of {domain_name}");
+    if let Err(error) = serial_console(socket, &domain_name).await {
+        warn!("Serial console of {domain_name} failed: {error}");
+    }
+    info!("Closed serial console of {domain_name}");
+}
+
+async fn serial_console(socket: WebSocket, domain_name: &str) -> Result<(), Error> {
+    let libvirt = Libvirt::new(LIBVIRT_URI)?;
+    let stream = Arc::new(open_console(&libvirt, domain_name)?);
+    let (mut sink, mut source) = socket.split();
+
+    // Stream I/O blocks, so the console is read on a thread of its own
+    let (output_sender, mut output_receiver) = channel::<Vec<u8>>(16);
+    let reader = tokio::task::spawn_blocking({
+        let stream = stream.clone();
+        move || -> Result<(), Error> {
+            let mut buffer = [0u8; READ_BUFFER_SIZE];
+            loop {
+                let read = stream.0.recv(&mut buffer)?;
+                if read == 0
+                    || output_sender
+                        .blocking_send(buffer[..read].to_vec())
+                        .is_err()
+                {
+                    return Ok(());
+                }
+            }
+        }
+    });
+
+    let result: Result<(), Error> = loop {
+        tokio::select! {
+            output = output_receiver.recv() => match output {
+                Some(data) => {
+                    if sink.send(Message::binary(data)).await.is_err() {
+                        break Ok(());
+                    }
+                }
+                None => break Ok(()),
+            },
+            input = source.next() => match input {
+                Some(Ok(message)) if message.is_close() => break Ok(()),
+                Some(Ok(message)) if message.is_binary() || message.is_text() => {
+                    let stream = stream.clone();
+                    let data = message.into_bytes();
+                    let sent = tokio::task::spawn_blocking(move || -> Result<(), Error> {
+                        let mut written = 0;
+                        while written < data.len() {
+                            written += stream.0.send(&data[written..])?;
+                        }
+                        Ok(())
+                    })
+                    .await?;
+                    if let Err(error) = sent {
+                        break Err(error);
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => break Ok(()),
+            },
+        }
+    };
+
+    stream.abort();
+    output_receiver.close();
+    let _ = reader.await;
+    let _ = sink.close().await;
+    drop(stream);
+    drop(libvirt);
+    result
+}
@@ -0,0 +1,94 @@
+use futures::{SinkExt, StreamExt};
+use roxmltree::Document;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+use virt::domain::Domain;
+use warp::ws::{Message, WebSocket};
+
+use crate::errors::Error;
+use crate::host::libvirt::{LIBVIRT_URI, Libvirt};
+
+const READ_BUFFER_SIZE: usize = 16384;
+
+/// Parse the port of the VNC server from the XML description of a running domain. Returns None
+/// if the domain has no VNC graphics or no port has been assigned yet.
+fn parse_vnc_port(xml: &str) -> Result<Option<u16>, Error> {
+    let document = Document::parse(xml)?;
+    let port = document
+        .descendants()
+        .find(|node| node.has_tag_name("graphics") && node.attribute("type") == Some("vnc"))
+        .and_then(|graphics| graphics.attribute("port"))
+        .and_then(|port| port.parse::<u16>().ok());
+    Ok(port)
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_vnc_port() {
+    let xml = r#"
+        <domain type='kvm'>
+          <devices>
+            <graphics type='vnc' port='5901' autoport='yes' listen='127.0.0.1'>
+              <listen type='address' address='127.0.0.1'/>
+            </graphics>
+          </devices>
+        </domain>"#;
+    assert_eq!(parse_vnc_port(xml).unwrap(), Some(5901));
+
+    let xml = r#"
+        <domain type='kvm'>
+          <devices>
+            <graphics type='vnc' port='-1' autoport='yes' listen='127.0.0.1'/>
+          </devices>
+        </domain>"#;
+    assert_eq!(parse_vnc_port(xml).unwrap(), None);
+}
+
+/// The VNC server of the domain, which only listens on the loopback address of the host
+fn vnc_address(domain_name: &str) -> Result<String, Error> {
+    let libvirt = Libvirt::new(LIBVIRT_URI)?;
+    let domain = Domain::lookup_by_name(&libvirt.connection, domain_name)?;
+    let port = parse_vnc_port(&domain.get_xml_desc(0)?)?.ok_or_else(|| {
+        Error::ConsoleUnavailable(format!("{domain_name} has no VNC server running"))
+    })?;
+    Ok(format!("127.0.0.1:{port}"))
+}
+
+/// Pass the VNC (RFB) stream of the domain to the WebSocket and back until either end closes.
+/// Clients like noVNC speak RFB over binary WebSocket messages.
+pub async fn proxy_vnc(socket: WebSocket, domain_name: String) {
+    info!("Opening VNC connection to {domain_name}");
+    if let Err(error) = vnc(socket, &domain_name).await {
+        warn!("VNC connection to {domain_name} failed: {error}");
+    }
+    info!("Closed VNC connection to {domain_name}");
+}
+
+async fn vnc(socket: WebSocket, domain_name: &str) -> Result<(), Error> {
+    let address = vnc_address(domain_name)?;
+    let (mut reader, mut writer) = TcpStream::connect(address).await?.into_split();
+    let (mut sink, mut source) = socket.split();
+
+    let mut buffer = [0u8; READ_BUFFER_SIZE];
+    loop {
+        tokio::select! {
+            read = reader.read(&mut buffer) => {
+                let read = read?;
+                if read == 0 || sink.send(Message::binary(&buffer[..read])).await.is_err() {
+                    break;
+                }
+            }
+            input = source.next() => match input {
+                Some(Ok(message)) if message.is_close() => break,
+                Some(Ok(message)) if message.is_binary() || message.is_text() => {
+                    writer.write_all(message.as_bytes()).await?
+                }
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => break,
+            },
+        }
+    }
+    let _ = sink.close().await;
+    Ok(())
+}
@@ -2,6 +2,8 @@ use k8s_openapi::api::apps::v1::DaemonSet;
 use serde_json::json;
 
 use crate::errors::Error;
+use crate::host::console::{CONSOLE_GATEWAY_PORT, CONSOLE_GATEWAY_TLS_PATH};
+use crate::utils::health::HOST_HEALTH_PORT;
 
 pub fn make_daemonset(image: String) -> Result<DaemonSet, Error> {
     let ds: DaemonSet = serde_json::from_value(json!({
@@ -39,6 +41,15 @@ pub fn make_daemonset(image: String) -> Result<DaemonSet, Error> {
                 "securityContext": {
                   "privileged": true
                 },
+                "ports": [
+                  { "name": "health", "containerPort": HOST_HEALTH_PORT }
+                ],
+                "livenessProbe": {
+                  "httpGet": { "path": "/healthz", "port": HOST_HEALTH_PORT }
+                },
+                "readinessProbe": {
+                  "httpGet": { "path": "/readyz", "port": HOST_HEALTH_PORT }
+                },
                 "env": [
                   {
                     "name": "NODE_NAME",
@@ -65,6 +76,41 @@ pub fn make_daemonset(image: String) -> Result<DaemonSet, Error> {
                     "mountPath": "/etc/ceph"
                   }
                 ]
+              },
+              {
+                "name": "console-gateway",
+                "image": image,
+                "command": ["cluster-controller", "--console-gateway"],
+                "ports": [
+                  { "name": "console", "containerPort": CONSOLE_GATEWAY_PORT }
+                ],
+                "env": [
+                  {
+                    "name": "NODE_NAME",
+                    "valueFrom": {
+                      "fieldRef": { "fieldPath": "spec.nodeName" }
+                    }
+                  },
+                  {
+                    "name": "RUST_LOG",
+                    "value": "cluster_controller=debug"
+                  },
+                  {
+                    "name": "OTLP_ENDPOINT",
+                    "value": "http://10.4.131.101:4317"
+                  }
+                ],
+                "volumeMounts": [
+                  {
+                    "name": "virtqemud-sock",
+                    "mountPath": "/var/run/libvirt/virtqemud-sock"
+                  },
+                  {
+                    "name": "console-gateway-tls",
+                    "mountPath": CONSOLE_GATEWAY_TLS_PATH,
+                    "readOnly": true
+                  }
+                ]
               }
             ],
             "volumes": [
@@ -79,6 +125,13 @@ pub fn make_daemonset(image: String) -> Result<DaemonSet, Error> {
                 "hostPath": {
                   "path": "/etc/ceph"
                 }
+              },
+              {
+                "name": "console-gateway-tls",
+                "secret": {
+                  "secretName": "console-gateway-tls",
+                  "optional": true
+                }
               }
             ],
           }
@@ -1,30 +1,44 @@
 use futures::StreamExt;
 use kube::runtime::controller::{Action, Controller};
+use kube::runtime::reflector::{self, Store};
+use kube::runtime::watcher;
 use kube::{Client, api::Api};
 use std::env;
 use std::sync::Arc;
-use tokio::time::Duration;
-use tracing::{error, info};
+use tokio::time::{Duration, sleep};
+use tracing::{error, info, warn};
 
 use super::lowlevel::Libvirt;
+use crate::crd::libvirtnode::LibvirtNode;
 use crate::crd::virtualmachine::VirtualMachine;
 use crate::errors::Error;
 use crate::host::libvirt::handlers::LIBVIRT_URI;
 use crate::host::libvirt::utils::get_domain_name;
 use crate::host::libvirt::{handlers, libvirtnode, secrets};
+use crate::utils::backoff::ErrorBackoff;
+use crate::utils::events::EventRecorder;
+use crate::utils::metrics::measure_reconcile;
 use crate::utils::traits::kube::TryStatus;
 use crate::{create_controller, ok_no_requeue};
 
+/// Time between refreshes of the host information in the LibvirtNode
+const LIBVIRTNODE_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
 /// State available for the reconcile and error_policy functions
 /// called by the Controller
 pub struct State {
     pub kube: Client,
     pub libvirt: Libvirt,
+    pub recorder: EventRecorder,
+    pub backoff: ErrorBackoff,
+    /// The LibvirtNode of this host, kept up to date by a watch
+    pub libvirt_node: Store<LibvirtNode>,
 }
 
 enum Event {
     MissingDomainName,
     Unscheduled,
+    Evicted,
     NoNode,
     InboundMigration,
     OutboundMigration,
@@ -50,6 +64,10 @@ fn get_event_type(vm: &VirtualMachine, ctx: &Arc<State>) -> Result<Event, Error>
     let vm_status = vm.try_status()?;
 
     if !vm_status.scheduled {
+        // A domain of an unscheduled VM is left over from a preemption
+        if ctx.libvirt.has_domain(&libvirt_domain_name)? {
+            return Ok(Event::Evicted);
+        }
         return Ok(Event::Unscheduled);
     }
 
@@ -86,29 +104,74 @@ fn get_event_type(vm: &VirtualMachine, ctx: &Arc<State>) -> Result<Event, Error>
 
 /// Handle updates to volumes in the cluster
 async fn reconcile(vm: Arc<VirtualMachine>, ctx: Arc<State>) -> Result<Action, Error> {
+    let (recorder, backoff) = (ctx.recorder.clone(), ctx.backoff.clone());
+    let result = measure_reconcile("VirtualMachine", handle_event(vm.clone(), ctx)).await;
+    match &result {
+        Ok(_) => {
+            backoff.reset(vm.as_ref());
+            recorder.reconcile_succeeded(vm.as_ref());
+        }
+        Err(error) => recorder.reconcile_failed(vm.as_ref(), error).await,
+    }
+    result
+}
+
+async fn handle_event(vm: Arc<VirtualMachine>, ctx: Arc<State>) -> Result<Action, Error> {
     match get_event_type(&vm, &ctx)? {
         Event::Deleted => handlers::handle_delete(&vm, ctx).await,
         Event::Added => handlers::handle_add(&vm, ctx).await,
+        Event::Updated => handlers::handle_running(&vm, ctx).await,
         Event::OutboundMigration => handlers::handle_outbound_migration(&vm, ctx).await,
         Event::InboundMigration => handlers::handle_inbound_migration(&vm, ctx).await,
+        Event::Evicted => handlers::handle_eviction(&vm, ctx).await,
+        Event::NotOurs => handlers::handle_not_ours(&vm, ctx).await,
         _ => {
             ok_no_requeue!()
         }
     }
 }
 
-fn error_policy(_object: Arc<VirtualMachine>, _error: &Error, _ctx: Arc<State>) -> Action {
-    Action::requeue(Duration::from_secs(15))
+fn error_policy(object: Arc<VirtualMachine>, error: &Error, ctx: Arc<State>) -> Action {
+    ctx.backoff.on_error(object.as_ref(), error)
+}
+
+/// Keep the capabilities and PCI devices reported in the LibvirtNode up to date, e.g. after
+/// devices have been bound to vfio-pci
+async fn refresh_libvirtnode(ctx: Arc<State>) {
+    loop {
+        sleep(LIBVIRTNODE_REFRESH_INTERVAL).await;
+        if let Err(e) = libvirtnode::update(&ctx.libvirt, ctx.kube.clone()).await {
+            warn!("Failed to refresh the LibvirtNode: {e}");
+        }
+    }
 }
 
 pub async fn create(client: Client) -> Result<(), Error> {
     let libvirt = Libvirt::new(LIBVIRT_URI)?;
     libvirtnode::update(&libvirt, client.clone()).await?;
     secrets::ensure_ceph_secret(client.clone(), &libvirt).await?;
+
+    let node_name = env::var("NODE_NAME").expect("failed to read $NODE_NAME");
+    let (libvirt_node, writer) = reflector::store();
+    let libvirt_nodes: Api<LibvirtNode> = Api::all(client.clone());
+    let config = watcher::Config::default().fields(&format!("metadata.name={node_name}"));
+    tokio::spawn(
+        reflector::reflector(writer, watcher(libvirt_nodes, config)).for_each(|event| async move {
+            if let Err(e) = event {
+                warn!("LibvirtNode watch failed: {e}");
+            }
+        }),
+    );
+
     let context = Arc::new(State {
         kube: client.clone(),
         libvirt,
+        recorder: EventRecorder::new(client.clone()),
+        backoff: ErrorBackoff::default(),
+        libvirt_node,
     });
+    tokio::spawn(refresh_libvirtnode(context.clone()));
+
     let vms: Api<VirtualMachine> = Api::all(client.clone());
     info!("Starting libvirt host controller");
     create_controller!(vms, reconcile, error_policy, context);
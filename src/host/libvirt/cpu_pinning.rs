@@ -0,0 +1,64 @@
+use kube::ResourceExt;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tracing::info;
+
+use crate::crd::libvirtnode::{CpuAllocation, set_libvirtnode_status};
+use crate::crd::virtualmachine::VirtualMachine;
+use crate::errors::Error;
+use crate::host::libvirt::controller::State;
+use crate::host::libvirt::utils::get_own_libvirtnode;
+use crate::utils::libvirt_capabilities::HostCapabilities;
+use crate::utils::traits::kube::ExtendResource;
+
+/// Return the host CPUs dedicated to the VM on this node, allocating them from the free CPUs of
+/// the host topology if the VM has none yet. Allocations are recorded in the LibvirtNode status,
+/// which the scheduler uses to avoid overbooking the node.
+pub async fn ensure_cpu_allocation(
+    vm: &VirtualMachine,
+    ctx: &Arc<State>,
+) -> Result<CpuAllocation, Error> {
+    let libvirt_node = get_own_libvirtnode(ctx).await?;
+    let vm_name = vm.name_prefixed_with_namespace();
+    let mut status = libvirt_node.status.clone().unwrap_or_default();
+    if let Some(allocation) = status.cpu_allocations.get(&vm_name) {
+        return Ok(allocation.clone());
+    }
+
+    let capabilities = HostCapabilities::from_libvirtnode(&libvirt_node)?.unwrap_or_default();
+    let unavailable: BTreeSet<u32> = libvirt_node
+        .spec
+        .reserved_cpus
+        .iter()
+        .chain(status.cpu_allocations.values().flat_map(|a| a.cpus.iter()))
+        .copied()
+        .collect();
+    let allocation = capabilities
+        .allocate_cpus(&unavailable, vm.cpus(), vm.spec.numa_single_cell())
+        .ok_or_else(|| {
+            Error::CpuAllocationFailed(format!(
+                "{} CPUs for {vm_name} on {}",
+                vm.cpus(),
+                libvirt_node.name_unchecked()
+            ))
+        })?;
+    info!("Dedicating host CPUs {:?} to {vm_name}", allocation.cpus);
+
+    // The status is replaced conditionally on its resource version, so concurrent allocations
+    // can not hand out the same CPUs
+    status.cpu_allocations.insert(vm_name, allocation.clone());
+    set_libvirtnode_status(&libvirt_node, status, ctx.kube.clone()).await?;
+    Ok(allocation)
+}
+
+/// Return the host CPUs dedicated to the VM on this node to the free pool
+pub async fn release_cpu_allocation(vm: &VirtualMachine, ctx: &Arc<State>) -> Result<(), Error> {
+    let libvirt_node = get_own_libvirtnode(ctx).await?;
+    let vm_name = vm.name_prefixed_with_namespace();
+    let mut status = libvirt_node.status.clone().unwrap_or_default();
+    if status.cpu_allocations.remove(&vm_name).is_some() {
+        info!("Releasing host CPUs dedicated to {vm_name}");
+        set_libvirtnode_status(&libvirt_node, status, ctx.kube.clone()).await?;
+    }
+    Ok(())
+}
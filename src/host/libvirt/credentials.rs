@@ -0,0 +1,163 @@
+use k8s_openapi::api::core::v1::Secret;
+use kube::{Api, ResourceExt};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{info, warn};
+use virt::domain::Domain;
+
+use crate::crd::virtualmachine::{VirtualMachine, VirtualMachineStatus};
+use crate::errors::Error;
+use crate::host::libvirt::controller::State;
+use crate::host::libvirt::guest_agent::agent_command;
+
+const CREDENTIALS_CONDITION: &str = "CredentialsApplied";
+const DEFAULT_USER: &str = "root";
+const PASSWORD_KEY: &str = "password";
+const AUTHORIZED_KEYS_KEY: &str = "ssh-authorized-keys";
+/// Secret type or label marking a Secret as meant to be copied into guests. Anyone allowed to
+/// edit a VM could otherwise read any Secret of the namespace through the guest.
+const GUEST_CREDENTIALS_MARKER: &str = "cluster-virt.acl.fi/guest-credentials";
+
+fn is_guest_credentials(secret: &Secret) -> bool {
+    secret.type_.as_deref() == Some(GUEST_CREDENTIALS_MARKER)
+        || secret
+            .labels()
+            .get(GUEST_CREDENTIALS_MARKER)
+            .is_some_and(|value| value == "true")
+}
+
+#[cfg(test)]
+#[test]
+fn test_is_guest_credentials() {
+    let mut secret = Secret::default();
+    assert!(!is_guest_credentials(&secret));
+    secret.type_ = Some(String::from(GUEST_CREDENTIALS_MARKER));
+    assert!(is_guest_credentials(&secret));
+
+    let mut secret = Secret::default();
+    secret
+        .labels_mut()
+        .insert(GUEST_CREDENTIALS_MARKER.into(), "false".into());
+    assert!(!is_guest_credentials(&secret));
+    secret
+        .labels_mut()
+        .insert(GUEST_CREDENTIALS_MARKER.into(), "true".into());
+    assert!(is_guest_credentials(&secret));
+}
+
+/// Split the authorized keys of the Secret into single keys, skipping empty lines and comments
+fn parse_authorized_keys(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_authorized_keys() {
+    let text = "# admins\nssh-ed25519 AAAAC3Nza alice@example\n\n  ssh-rsa AAAAB3Nza bob  \n";
+    assert_eq!(
+        parse_authorized_keys(text),
+        vec![
+            String::from("ssh-ed25519 AAAAC3Nza alice@example"),
+            String::from("ssh-rsa AAAAB3Nza bob")
+        ]
+    );
+}
+
+/// Set the password and add the authorized keys inside the guest
+fn apply_in_guest(
+    domain: &Domain,
+    user: &str,
+    password: Option<String>,
+    authorized_keys: Vec<String>,
+) -> Result<(), Error> {
+    if let Some(password) = password {
+        domain.set_user_password(user, &password, 0)?;
+    }
+    if !authorized_keys.is_empty() {
+        agent_command(
+            domain,
+            "guest-ssh-add-authorized-keys",
+            Some(json!({ "username": user, "keys": authorized_keys })),
+        )?;
+    }
+    Ok(())
+}
+
+/// Apply the access credentials of a running VM through the guest agent if their Secret has
+/// changed since they were last applied. Failures are reported in a condition of the status,
+/// to be retried on the next refresh. Returns whether the status changed.
+pub async fn apply_credentials(
+    vm: &VirtualMachine,
+    domain_name: &str,
+    status: &mut VirtualMachineStatus,
+    ctx: &Arc<State>,
+) -> Result<bool, Error> {
+    let Some(credentials) = vm.spec.access_credentials.as_ref() else {
+        return Ok(false);
+    };
+    let namespace = vm.namespace().expect("VM without namespace?");
+    let user = credentials.user.as_deref().unwrap_or(DEFAULT_USER);
+
+    let secrets: Api<Secret> = Api::namespaced(ctx.kube.clone(), &namespace);
+    let Some(secret) = secrets.get_opt(&credentials.secret_name).await? else {
+        let message = format!("Secret {} not found", credentials.secret_name);
+        return Ok(status.set_condition(CREDENTIALS_CONDITION, false, "SecretNotFound", &message));
+    };
+    if !is_guest_credentials(&secret) {
+        let message = format!(
+            "Secret {} is not of the type or labeled {GUEST_CREDENTIALS_MARKER}: \"true\"",
+            credentials.secret_name
+        );
+        return Ok(status.set_condition(
+            CREDENTIALS_CONDITION,
+            false,
+            "SecretNotAllowed",
+            &message,
+        ));
+    }
+    let version = format!(
+        "{}/{}",
+        credentials.secret_name,
+        secret.resource_version().unwrap_or_default()
+    );
+    if status.applied_credentials.as_ref() == Some(&version) {
+        return Ok(false);
+    }
+
+    let data = secret.data.unwrap_or_default();
+    let value = |key: &str| {
+        data.get(key)
+            .map(|value| String::from_utf8_lossy(&value.0).to_string())
+    };
+    let password = value(PASSWORD_KEY);
+    let authorized_keys = parse_authorized_keys(&value(AUTHORIZED_KEYS_KEY).unwrap_or_default());
+
+    // The guest agent calls block until the agent answers or times out
+    let result = {
+        let (ctx, name, guest_user) = (ctx.clone(), domain_name.to_string(), user.to_string());
+        tokio::task::spawn_blocking(move || {
+            let domain = Domain::lookup_by_name(&ctx.libvirt.connection, &name)?;
+            apply_in_guest(&domain, &guest_user, password, authorized_keys)
+        })
+        .await
+        .map_err(|error| Error::UnexpectedExit(error.to_string()))?
+    };
+    match result {
+        Ok(()) => {
+            info!("Applied credentials from {version} for {user} in {domain_name}");
+            status.applied_credentials = Some(version);
+            let message = format!("Applied for {user}");
+            status.set_condition(CREDENTIALS_CONDITION, true, "Applied", &message);
+            Ok(true)
+        }
+        Err(error) => {
+            warn!("Failed to apply credentials in {domain_name}: {error}");
+            let message = error.to_string();
+            Ok(status.set_condition(CREDENTIALS_CONDITION, false, "ApplyFailed", &message))
+        }
+    }
+}
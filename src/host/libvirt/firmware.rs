@@ -0,0 +1,133 @@
+use kube::ResourceExt;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tracing::info;
+
+use crate::crd::cluster::{Cluster, FirmwareImages};
+use crate::crd::virtualmachine::VirtualMachine;
+use crate::crd::virtualmachine::v1beta3::Firmware;
+use crate::errors::Error;
+use crate::shared::ceph;
+
+/// NVRAM and TPM state live next to the volumes so that any host can start the VM
+const STATE_POOL: &str = "volumes";
+/// Room for the swtpm state, which is well below this for a TPM 2.0
+const TPM_STATE_SIZE: u64 = 16 * 1024 * 1024;
+
+const OVMF_LOADER: &str = "/usr/share/edk2/ovmf/OVMF_CODE.fd";
+const OVMF_VARS: &str = "/usr/share/edk2/ovmf/OVMF_VARS.fd";
+const OVMF_SECURE_BOOT_LOADER: &str = "/usr/share/edk2/ovmf/OVMF_CODE.secboot.fd";
+const OVMF_SECURE_BOOT_VARS: &str = "/usr/share/edk2/ovmf/OVMF_VARS.secboot.fd";
+
+fn state_image_name(vm: &VirtualMachine, suffix: &str) -> String {
+    format!(
+        "{}-{}-{suffix}",
+        vm.namespace().expect("VM without namespace?"),
+        vm.name_any()
+    )
+}
+
+/// RBD image holding the UEFI variables of the VM, as pool/image
+pub fn nvram_image(vm: &VirtualMachine) -> String {
+    format!("{STATE_POOL}/{}", state_image_name(vm, "nvram"))
+}
+
+/// Block device of the mapped RBD image holding the TPM state of the VM
+pub fn tpm_state_path(vm: &VirtualMachine) -> String {
+    format!("/dev/rbd/{STATE_POOL}/{}", state_image_name(vm, "tpm"))
+}
+
+/// Firmware images the VM boots with, None for BIOS boot
+pub fn firmware_images(vm: &VirtualMachine, cluster: &Cluster) -> Option<FirmwareImages> {
+    let (configured, loader, vars_template) = match vm.spec.get_firmware() {
+        Firmware::Bios => return None,
+        Firmware::Uefi => (&cluster.spec.uefi, OVMF_LOADER, OVMF_VARS),
+        Firmware::UefiSecureBoot => (
+            &cluster.spec.uefi_secure_boot,
+            OVMF_SECURE_BOOT_LOADER,
+            OVMF_SECURE_BOOT_VARS,
+        ),
+    };
+    Some(configured.clone().unwrap_or_else(|| FirmwareImages {
+        loader: loader.to_string(),
+        vars_template: vars_template.to_string(),
+    }))
+}
+
+fn rbd_device(action: &str, image: &str) -> Result<(), Error> {
+    let args = ["device", action, image];
+    let output = Command::new("/usr/bin/rbd")
+        .args(args)
+        .output()
+        .map_err(|error| {
+            Error::CommandFailed(
+                args.iter().map(|arg| arg.to_string()).collect(),
+                format!("failed to run rbd: {error}"),
+            )
+        })?;
+    if !output.status.success() {
+        return Err(Error::CommandFailed(
+            args.iter().map(|arg| arg.to_string()).collect(),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Map the TPM state image of the VM on this host, so that swtpm can use it
+pub fn map_tpm_state(vm: &VirtualMachine) -> Result<(), Error> {
+    if !vm.spec.has_tpm() || Path::new(&tpm_state_path(vm)).exists() {
+        return Ok(());
+    }
+    info!("Mapping TPM state of {}", vm.name_any());
+    rbd_device(
+        "map",
+        &format!("{STATE_POOL}/{}", state_image_name(vm, "tpm")),
+    )
+}
+
+/// Unmap the TPM state image of a VM which no longer runs on this host
+pub fn unmap_tpm_state(vm: &VirtualMachine) -> Result<(), Error> {
+    let path = tpm_state_path(vm);
+    if !Path::new(&path).exists() {
+        return Ok(());
+    }
+    info!("Unmapping TPM state of {}", vm.name_any());
+    rbd_device("unmap", &path)
+}
+
+/// Create the NVRAM and TPM state images of the VM if they do not exist yet, and map the TPM
+/// state on this host. The NVRAM starts out as a copy of the firmware variable store template.
+pub fn prepare_state(vm: &VirtualMachine, cluster: &Cluster) -> Result<(), Error> {
+    if let Some(images) = firmware_images(vm, cluster) {
+        let vars = fs::read(&images.vars_template)?;
+        ceph::ensure_image_with_content(
+            STATE_POOL,
+            &state_image_name(vm, "nvram"),
+            vars.len() as u64,
+            &vars,
+        )?;
+    }
+    if vm.spec.has_tpm() {
+        ceph::ensure_image_with_content(
+            STATE_POOL,
+            &state_image_name(vm, "tpm"),
+            TPM_STATE_SIZE,
+            &[],
+        )?;
+        map_tpm_state(vm)?;
+    }
+    Ok(())
+}
+
+/// Remove the NVRAM and TPM state of a deleted VM
+pub fn remove_state(vm: &VirtualMachine) -> Result<(), Error> {
+    if vm.spec.get_firmware() == Firmware::Bios && !vm.spec.has_tpm() {
+        return Ok(());
+    }
+    unmap_tpm_state(vm)?;
+    ceph::remove_image_if_exists(STATE_POOL, &state_image_name(vm, "nvram"))?;
+    ceph::remove_image_if_exists(STATE_POOL, &state_image_name(vm, "tpm"))?;
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use serde_json::{Value, json};
+use std::net::IpAddr;
+use virt::domain::{Domain, Interface};
+
+use crate::crd::virtualmachine::{GuestInfo, GuestInterface};
+use crate::errors::Error;
+
+/// Seconds to wait for an answer, the agent may be slow or not installed at all
+const AGENT_TIMEOUT: i32 = 5;
+
+/// Run a command through the QEMU guest agent and return the contents of its answer
+pub fn agent_command(
+    domain: &Domain,
+    command: &str,
+    arguments: Option<Value>,
+) -> Result<Value, Error> {
+    let request = match arguments {
+        Some(arguments) => json!({ "execute": command, "arguments": arguments }),
+        None => json!({ "execute": command }),
+    }
+    .to_string();
+    let response = domain.qemu_agent_command(&request, AGENT_TIMEOUT, 0)?;
+    let mut response: Value = serde_json::from_str(&response)?;
+    Ok(response["return"].take())
+}
+
+/// Parse the answer to guest-get-osinfo into the OS name and version
+fn parse_os_info(info: &Value) -> (Option<String>, Option<String>) {
+    let field = |name: &str| info[name].as_str().map(String::from);
+    (
+        field("pretty-name").or_else(|| field("name")),
+        field("version").or_else(|| field("version-id")),
+    )
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_os_info() {
+    let info = json!({
+        "name": "Ubuntu",
+        "kernel-release": "5.15.0-91-generic",
+        "version": "22.04.3 LTS (Jammy Jellyfish)",
+        "pretty-name": "Ubuntu 22.04.3 LTS",
+        "version-id": "22.04",
+        "kernel-version": "#101-Ubuntu SMP Tue Nov 14 13:30:08 UTC 2023",
+        "machine": "x86_64",
+        "id": "ubuntu"
+    });
+    assert_eq!(
+        parse_os_info(&info),
+        (
+            Some(String::from("Ubuntu 22.04.3 LTS")),
+            Some(String::from("22.04.3 LTS (Jammy Jellyfish)"))
+        )
+    );
+
+    let info = json!({ "name": "Microsoft Windows", "version-id": "2022" });
+    assert_eq!(
+        parse_os_info(&info),
+        (
+            Some(String::from("Microsoft Windows")),
+            Some(String::from("2022"))
+        )
+    );
+}
+
+/// Whether the address is worth reporting, i.e. reachable from outside the guest
+fn is_reportable(address: &str) -> bool {
+    match address.parse::<IpAddr>() {
+        Ok(IpAddr::V4(address)) => !address.is_loopback() && !address.is_link_local(),
+        Ok(IpAddr::V6(address)) => !address.is_loopback() && !address.is_unicast_link_local(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_is_reportable() {
+    assert!(is_reportable("10.4.2.15"));
+    assert!(is_reportable("2001:db8::15"));
+    assert!(!is_reportable("127.0.0.1"));
+    assert!(!is_reportable("::1"));
+    assert!(!is_reportable("fe80::5054:ff:fe12:3456"));
+    assert!(!is_reportable("169.254.1.1"));
+    assert!(!is_reportable("not an address"));
+}
+
+fn to_guest_interface(interface: Interface) -> GuestInterface {
+    GuestInterface {
+        name: interface.name,
+        mac_address: Some(interface.hwaddr).filter(|mac| !mac.is_empty()),
+        ip_addresses: interface
+            .addrs
+            .into_iter()
+            .map(|address| address.addr)
+            .filter(|address| is_reportable(address))
+            .collect(),
+    }
+}
+
+/// Ask the guest agent of a running domain for its network interfaces, hostname and OS. Only
+/// reports the agent as disconnected if it does not answer.
+pub fn query_guest_info(domain: &Domain) -> GuestInfo {
+    if agent_command(domain, "guest-ping", None).is_err() {
+        return GuestInfo::default();
+    }
+
+    let hostname = agent_command(domain, "guest-get-host-name", None)
+        .ok()
+        .and_then(|answer| answer["host-name"].as_str().map(String::from));
+    let (os_name, os_version) = agent_command(domain, "guest-get-osinfo", None)
+        .map(|info| parse_os_info(&info))
+        .unwrap_or_default();
+    let interfaces = domain
+        .interface_addresses(virt::sys::VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_AGENT, 0)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|interface| interface.name != "lo")
+        .map(to_guest_interface)
+        .collect();
+
+    GuestInfo {
+        agent_connected: true,
+        hostname,
+        os_name,
+        os_version,
+        interfaces,
+    }
+}
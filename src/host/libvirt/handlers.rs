@@ -1,22 +1,36 @@
 use crate::Error;
+use crate::crd::libvirtnode::LibvirtNode;
 use crate::crd::virtualmachine::v1beta3::PowerAction;
 use crate::crd::virtualmachine::{VirtualMachine, VirtualMachineStatus, set_vm_status};
 use crate::host::libvirt::controller::State;
+use crate::host::libvirt::cpu_pinning::{ensure_cpu_allocation, release_cpu_allocation};
+use crate::host::libvirt::credentials::apply_credentials;
 use crate::host::libvirt::evpn::ensure_vni_mapping;
+use crate::host::libvirt::firmware::{map_tpm_state, prepare_state, remove_state, unmap_tpm_state};
+use crate::host::libvirt::guest_agent::query_guest_info;
+use crate::host::libvirt::host_devices::{ensure_pci_allocation, release_pci_allocation};
 use crate::host::libvirt::utils::{get_cluster, get_domain_name};
+use crate::utils::libvirt_capabilities::{HostCapabilities, parse_cpu_definition};
+use crate::utils::libvirt_domain_overrides::domain_patches;
 use crate::utils::strings::field_manager;
 use crate::utils::traits::kube::{ExtendResource, TryStatus};
 use crate::{ok_and_requeue, ok_no_requeue};
+use kube::Api;
 use kube::runtime::controller::Action;
 use lazy_static::lazy_static;
+use std::env;
 use std::sync::Arc;
-use tokio::time::Duration;
-use tracing::{error, info};
-use virt::domain::Domain;
+use tokio::time::{Duration, Instant, sleep};
+use tracing::{error, info, warn};
+use virt::domain::{Domain, MigrateParameters};
 
 pub const FINALIZER: &str = "libvirt-host";
 pub const LIBVIRT_URI: &str = "qemu:///system";
-const NO_BW_LIMIT: u64 = 0;
+const DOMAIN_OVERRIDES_CONDITION: &str = "DomainOverridesApplied";
+/// Seconds between refreshes of the information reported by the guest agent
+const GUEST_INFO_INTERVAL: u64 = 60;
+/// Time an evicted VM gets to shut down cleanly before it is powered off
+const EVICTION_GRACE_PERIOD: Duration = Duration::from_secs(60);
 
 lazy_static! {
     static ref FIELD_MANAGER: String = field_manager("libvirt-host");
@@ -39,12 +53,85 @@ pub async fn handle_delete(vm: &VirtualMachine, ctx: Arc<State>) -> Result<Actio
             error!("Domain {vm_name} doesn't exist, ignoring");
         }
     };
+    if vm.spec.has_dedicated_cpus() {
+        release_cpu_allocation(&vm, &ctx).await?;
+    }
+    if vm.spec.has_host_devices() {
+        release_pci_allocation(&vm, &ctx).await?;
+    }
+    remove_state(&vm)?;
     vm.remove_finalizer(FINALIZER, ctx.kube.clone(), &FIELD_MANAGER)
         .await?;
 
     ok_no_requeue!()
 }
 
+/// Whether the domain exists and is running
+fn is_domain_active(ctx: &State, vm_name: &str) -> Result<bool, Error> {
+    match Domain::lookup_by_name(&ctx.libvirt.connection, vm_name) {
+        Ok(domain) => Ok(domain.is_active()?),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Ask the guest to shut down and wait for it, powering the domain off if it is still running
+/// after the grace period
+async fn shutdown_domain(ctx: &State, vm_name: &str, grace_period: Duration) -> Result<(), Error> {
+    if !is_domain_active(ctx, vm_name)? {
+        return Ok(());
+    }
+    let requested = {
+        let domain = Domain::lookup_by_name(&ctx.libvirt.connection, vm_name)?;
+        domain.shutdown()
+    };
+    match requested {
+        Ok(_) => {
+            let deadline = Instant::now() + grace_period;
+            while Instant::now() < deadline {
+                sleep(Duration::from_secs(2)).await;
+                if !is_domain_active(ctx, vm_name)? {
+                    info!("Domain {vm_name} shut down");
+                    return Ok(());
+                }
+            }
+            warn!("Domain {vm_name} did not shut down in {grace_period:?}, destroying");
+        }
+        Err(error) => warn!("Failed to shut down domain {vm_name}, destroying: {error}"),
+    }
+
+    if let Ok(domain) = Domain::lookup_by_name(&ctx.libvirt.connection, vm_name) {
+        domain.destroy()?;
+        info!("Domain {vm_name} destroyed");
+    }
+    Ok(())
+}
+
+/// Called when a VM running on us has been unscheduled to make room for a higher priority VM.
+/// Shuts the libvirt VM down, the VM is started again once it is scheduled to some node.
+pub async fn handle_eviction(vm: &VirtualMachine, ctx: Arc<State>) -> Result<Action, Error> {
+    let vm_name = get_domain_name(vm).expect("VM has a libvirt domain name");
+    info!("VM {vm_name} has been preempted, shutting down");
+
+    shutdown_domain(&ctx, &vm_name, EVICTION_GRACE_PERIOD).await?;
+    if vm.spec.has_dedicated_cpus() {
+        release_cpu_allocation(vm, &ctx).await?;
+    }
+    if vm.spec.has_host_devices() {
+        release_pci_allocation(vm, &ctx).await?;
+    }
+    unmap_tpm_state(vm)?;
+
+    let mut status = VirtualMachineStatus {
+        running: false,
+        guest: None,
+        ..vm.try_status()?.clone()
+    };
+    status.refresh_ip_addresses();
+    set_vm_status(vm, status, ctx.kube.clone()).await?;
+
+    ok_no_requeue!()
+}
+
 /// Called when a VM has been assigned to us. Registers a finalizer and adds a libvirt VM
 /// locally
 pub async fn handle_add(vm: &VirtualMachine, ctx: Arc<State>) -> Result<Action, Error> {
@@ -62,19 +149,137 @@ pub async fn handle_add(vm: &VirtualMachine, ctx: Arc<State>) -> Result<Action,
 
     // Get cluster capabilities / definition
     let cluster = get_cluster(&ctx).await?;
+    prepare_state(&vm, &cluster)?;
+
+    let cpu_allocation = if vm.spec.has_dedicated_cpus() {
+        Some(ensure_cpu_allocation(&vm, &ctx).await?)
+    } else {
+        None
+    };
 
-    ctx.libvirt.create_domain(&vm, &cluster)?;
+    let pci_addresses = if vm.spec.has_host_devices() {
+        ensure_pci_allocation(&vm, &ctx).await?
+    } else {
+        vec![]
+    };
 
-    let status = VirtualMachineStatus {
+    let has_overrides = !domain_patches(&vm, &cluster).is_empty();
+    let created = ctx
+        .libvirt
+        .create_domain(&vm, &cluster, cpu_allocation.as_ref(), &pci_addresses);
+    if let Err(Error::InvalidDomainOverrides(reason)) = &created {
+        error!("Rejected domain overrides of {vm_name}: {reason}");
+        let mut status = vm.try_status()?.clone();
+        if status.set_condition(DOMAIN_OVERRIDES_CONDITION, false, "Rejected", reason) {
+            set_vm_status(&vm, status, ctx.kube.clone()).await?;
+        }
+    }
+    if created.is_err() {
+        // The VM may get rescheduled, do not leave its TPM state mapped here
+        if let Err(error) = unmap_tpm_state(&vm) {
+            warn!("Failed to unmap the TPM state of {vm_name}: {error}");
+        }
+    }
+    created?;
+
+    let mut status = VirtualMachineStatus {
         running: true,
         ..vm.status.clone().expect("VM didn't have existing status")
     };
+    if has_overrides {
+        status.set_condition(DOMAIN_OVERRIDES_CONDITION, true, "Applied", "");
+    }
     set_vm_status(&vm, status, ctx.kube.clone()).await?;
 
     info!("Updated: {}", vm_name);
     ok_and_requeue!(600)
 }
 
+/// Called for a VM running on us. Refreshes the IP addresses, hostname and OS reported by the
+/// guest agent in the VM status, and applies changed access credentials through the agent.
+pub async fn handle_running(vm: &VirtualMachine, ctx: Arc<State>) -> Result<Action, Error> {
+    let vm_name = get_domain_name(vm).expect("VM has a libvirt domain name");
+    // The guest agent calls block until the agent answers or times out
+    let guest = {
+        let (ctx, name) = (ctx.clone(), vm_name.clone());
+        tokio::task::spawn_blocking(move || {
+            let domain = Domain::lookup_by_name(&ctx.libvirt.connection, &name)?;
+            Ok::<_, Error>(query_guest_info(&domain))
+        })
+        .await
+        .map_err(|error| Error::UnexpectedExit(error.to_string()))??
+    };
+
+    let mut status = vm.try_status()?.clone();
+    let mut status_changed = false;
+    if status.guest.as_ref() != Some(&guest) {
+        if guest.agent_connected != status.guest.as_ref().is_some_and(|old| old.agent_connected) {
+            info!(
+                "Guest agent of {vm_name} {}",
+                if guest.agent_connected {
+                    "connected"
+                } else {
+                    "disconnected"
+                }
+            );
+        }
+        status.guest = Some(guest.clone());
+        status.refresh_ip_addresses();
+        status_changed = true;
+    }
+    if guest.agent_connected {
+        status_changed |= apply_credentials(vm, &vm_name, &mut status, &ctx).await?;
+    }
+    if status_changed {
+        set_vm_status(vm, status, ctx.kube.clone()).await?;
+    }
+
+    ok_and_requeue!(GUEST_INFO_INTERVAL)
+}
+
+/// Use the capabilities reported by both hosts to check that the VM can run on the destination,
+/// so that an incompatible host is refused before libvirt fails in the middle of a migration.
+/// If either host has not reported its capabilities, the migration is allowed.
+async fn check_migration_target(
+    vm: &VirtualMachine,
+    destination_node: &str,
+    ctx: &Arc<State>,
+) -> Result<(), Error> {
+    let my_node_name = env::var("NODE_NAME").expect("failed to read $NODE_NAME");
+    let libvirt_nodes: Api<LibvirtNode> = Api::all(ctx.kube.clone());
+
+    let (Some(source), Some(destination)) = (
+        libvirt_nodes.get_opt(&my_node_name).await?,
+        libvirt_nodes.get_opt(destination_node).await?,
+    ) else {
+        return Ok(());
+    };
+    let (Some(source), Some(destination)) = (
+        HostCapabilities::from_libvirtnode(&source)?,
+        HostCapabilities::from_libvirtnode(&destination)?,
+    ) else {
+        return Ok(());
+    };
+
+    let cluster = get_cluster(ctx).await?;
+    let cpu = parse_cpu_definition(vm.cpu_model().unwrap_or(&cluster.spec.cpu))?;
+    let machine_type = vm
+        .machine_type()
+        .cloned()
+        .unwrap_or(cluster.spec.machine_type);
+
+    destination
+        .check_migration_from(&source, &cpu)
+        .map_err(|reason| Error::IncompatibleHost(destination_node.to_string(), reason))?;
+    if !destination.supports_machine_type(&machine_type) {
+        return Err(Error::IncompatibleHost(
+            destination_node.to_string(),
+            format!("machine type {machine_type} is not supported"),
+        ));
+    }
+    Ok(())
+}
+
 /// A VM that is running on us has been scheduled for migration to another node. Start a live
 /// libvirt migration to new host and wait
 pub async fn handle_outbound_migration(
@@ -86,13 +291,59 @@ pub async fn handle_outbound_migration(
         Domain::lookup_by_name(&ctx.libvirt.connection, &vm_name).expect("Domain not found");
     let destination_node = vm.try_status()?.node.as_ref().expect("No destination node");
 
-    domain.migrate_to_uri(
+    if vm.spec.has_host_devices() {
+        error!("Refusing to migrate {vm_name}: VMs with host devices can not be live migrated");
+        return Err(Error::IncompatibleHost(
+            destination_node.to_string(),
+            String::from("VMs with host devices can not be live migrated"),
+        ));
+    }
+    if let Err(e) = check_migration_target(vm, destination_node, &ctx).await {
+        error!("Refusing to migrate {vm_name} to {destination_node}: {e}");
+        return Err(e);
+    }
+
+    // Dedicated CPUs are pinned to the ones the destination allocated, which it does before the
+    // domain arrives
+    let destination_xml = if vm.spec.has_dedicated_cpus() {
+        let libvirt_nodes: Api<LibvirtNode> = Api::all(ctx.kube.clone());
+        let allocation = libvirt_nodes
+            .get_opt(destination_node)
+            .await?
+            .and_then(|node| node.status)
+            .and_then(|status| {
+                status
+                    .cpu_allocations
+                    .get(&vm.name_prefixed_with_namespace())
+                    .cloned()
+            });
+        let Some(allocation) = allocation else {
+            info!("Waiting for {destination_node} to dedicate CPUs to {vm_name}");
+            return ok_and_requeue!(5);
+        };
+        Some(ctx.libvirt.migration_xml(&vm_name, vm, &allocation)?)
+    } else {
+        None
+    };
+
+    ctx.recorder
+        .normal(
+            vm,
+            "MigrationStarted",
+            "Migrate",
+            format!("Live migrating to {destination_node}"),
+        )
+        .await;
+    let parameters = MigrateParameters {
+        dest_xml: destination_xml,
+        ..Default::default()
+    };
+    domain.migrate_to_uri3(
         &format!("qemu+ssh://{destination_node}/system"),
+        parameters,
         virt::sys::VIR_MIGRATE_PEER2PEER
             | virt::sys::VIR_MIGRATE_LIVE
             | virt::sys::VIR_MIGRATE_AUTO_CONVERGE,
-        None,
-        NO_BW_LIMIT,
     )?;
     ok_and_requeue!(10)
 }
@@ -104,6 +355,12 @@ pub async fn handle_inbound_migration(
     ctx: Arc<State>,
 ) -> Result<Action, Error> {
     ensure_vni_mapping(vm)?;
+    // The migrated TPM state is written to the shared image, which must be there beforehand
+    map_tpm_state(vm)?;
+    // The source pins the migrated domain to the CPUs allocated here
+    if vm.spec.has_dedicated_cpus() {
+        ensure_cpu_allocation(vm, &ctx).await?;
+    }
 
     let libvirt_domain_name = get_domain_name(vm).expect("failed to get domain name");
     let vm_runs_on_us = ctx.libvirt.has_domain(&libvirt_domain_name)?;
@@ -124,6 +381,46 @@ pub async fn handle_inbound_migration(
     let mut new_status = vm.try_status()?.clone();
     new_status.migration_pending = false;
     set_vm_status(vm, new_status, ctx.kube.clone()).await?;
+    let node_name = env::var("NODE_NAME").expect("failed to read $NODE_NAME");
+    ctx.recorder
+        .normal(
+            vm,
+            "MigrationFinished",
+            "Migrate",
+            format!("Running on {node_name} after a live migration"),
+        )
+        .await;
 
     ok_and_requeue!(600)
 }
+
+/// Whether host CPUs or PCI devices are allocated to the VM on this node. Read from the watched
+/// LibvirtNode, so that the VMs of the other nodes do not each cost a request. Until the watch
+/// has listed the node the allocations are assumed to exist.
+fn has_allocations(vm: &VirtualMachine, ctx: &State) -> bool {
+    let Some(libvirt_node) = ctx.libvirt_node.state().into_iter().next() else {
+        return true;
+    };
+    let vm_name = vm.name_prefixed_with_namespace();
+    libvirt_node.status.as_ref().is_some_and(|status| {
+        status.cpu_allocations.contains_key(&vm_name)
+            || status.pci_allocations.contains_key(&vm_name)
+    })
+}
+
+/// Called for a VM that is not assigned to us. Releases the host CPUs, PCI devices and the TPM
+/// state mapping it had here after it has migrated away.
+pub async fn handle_not_ours(vm: &VirtualMachine, ctx: Arc<State>) -> Result<Action, Error> {
+    if !has_allocations(vm, &ctx) {
+        unmap_tpm_state(vm)?;
+        return ok_no_requeue!();
+    }
+    if vm.spec.has_dedicated_cpus() {
+        release_cpu_allocation(vm, &ctx).await?;
+    }
+    if vm.spec.has_host_devices() {
+        release_pci_allocation(vm, &ctx).await?;
+    }
+    unmap_tpm_state(vm)?;
+    ok_no_requeue!()
+}
@@ -0,0 +1,55 @@
+use kube::ResourceExt;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tracing::info;
+
+use crate::crd::libvirtnode::set_libvirtnode_status;
+use crate::crd::virtualmachine::VirtualMachine;
+use crate::errors::Error;
+use crate::host::libvirt::controller::State;
+use crate::host::libvirt::utils::get_own_libvirtnode;
+use crate::utils::libvirt_nodedev::pick_pci_devices;
+use crate::utils::traits::kube::ExtendResource;
+
+/// Return the addresses of the PCI devices passed through to the VM on this node, picking free
+/// devices matching its requests if it has none yet. Allocations are recorded in the LibvirtNode
+/// status, like dedicated CPUs.
+pub async fn ensure_pci_allocation(
+    vm: &VirtualMachine,
+    ctx: &Arc<State>,
+) -> Result<Vec<String>, Error> {
+    let libvirt_node = get_own_libvirtnode(ctx).await?;
+    let vm_name = vm.name_prefixed_with_namespace();
+    let mut status = libvirt_node.status.clone().unwrap_or_default();
+    if let Some(addresses) = status.pci_allocations.get(&vm_name) {
+        return Ok(addresses.clone());
+    }
+
+    let unavailable: BTreeSet<String> =
+        status.pci_allocations.values().flatten().cloned().collect();
+    let requests = vm.spec.host_devices.clone().unwrap_or_default();
+    let addresses =
+        pick_pci_devices(&status.pci_devices, &unavailable, &requests).ok_or_else(|| {
+            Error::PciAllocationFailed(format!(
+                "{requests:?} for {vm_name} on {}",
+                libvirt_node.name_unchecked()
+            ))
+        })?;
+    info!("Passing PCI devices {addresses:?} through to {vm_name}");
+
+    status.pci_allocations.insert(vm_name, addresses.clone());
+    set_libvirtnode_status(&libvirt_node, status, ctx.kube.clone()).await?;
+    Ok(addresses)
+}
+
+/// Return the PCI devices passed through to the VM on this node to the free pool
+pub async fn release_pci_allocation(vm: &VirtualMachine, ctx: &Arc<State>) -> Result<(), Error> {
+    let libvirt_node = get_own_libvirtnode(ctx).await?;
+    let vm_name = vm.name_prefixed_with_namespace();
+    let mut status = libvirt_node.status.clone().unwrap_or_default();
+    if status.pci_allocations.remove(&vm_name).is_some() {
+        info!("Releasing PCI devices passed through to {vm_name}");
+        set_libvirtnode_status(&libvirt_node, status, ctx.kube.clone()).await?;
+    }
+    Ok(())
+}
@@ -1,12 +1,53 @@
-use crate::crd::libvirtnode::{LibvirtNode, LibvirtNodeStatus, set_libvirtnode_status};
+use crate::crd::libvirtnode::{LibvirtNode, LibvirtNodeStatus, PciDevice, set_libvirtnode_status};
 use crate::errors::Error;
 use crate::host::libvirt::lowlevel::Libvirt;
+use crate::utils::libvirt_capabilities::{hugepage_size_name, parse_capabilities};
+use crate::utils::libvirt_nodedev::parse_pci_device;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
 use kube::api::PostParams;
 use kube::{Api, Client};
+use std::collections::BTreeMap;
+use tracing::warn;
+use virt::sys::VIR_CONNECT_LIST_NODE_DEVICES_CAP_PCI_DEV;
+
+/// Hugepages configured on the host as number of pages by page size name
+fn parse_hugepages(capabilities: &str) -> Result<BTreeMap<String, u64>, Error> {
+    Ok(parse_capabilities(capabilities)?
+        .hugepages
+        .into_iter()
+        .map(|(size, count)| (hugepage_size_name(size), count))
+        .collect())
+}
+
+/// PCI devices of the host as seen through the libvirt node device API
+fn list_pci_devices(libvirt: &Libvirt) -> Result<Vec<PciDevice>, Error> {
+    let mut devices = vec![];
+    for device in libvirt
+        .connection
+        .list_all_node_devices(VIR_CONNECT_LIST_NODE_DEVICES_CAP_PCI_DEV)?
+    {
+        if let Some(device) = parse_pci_device(&device.get_xml_desc(0)?)? {
+            devices.push(device);
+        }
+    }
+    devices.sort_by(|a, b| a.address.cmp(&b.address));
+    Ok(devices)
+}
 
 pub async fn update(libvirt: &Libvirt, client: Client) -> Result<(), Error> {
     let capabilities = libvirt.connection.get_capabilities()?;
+    let domain_capabilities = match libvirt
+        .connection
+        .get_domain_capabilities(None, None, None, Some("kvm"), 0)
+    {
+        Ok(xml) => Some(xml),
+        Err(e) => {
+            warn!("Failed to get domain capabilities: {e}");
+            None
+        }
+    };
+    let hugepages = parse_hugepages(&capabilities)?;
+    let pci_devices = list_pci_devices(libvirt)?;
     let libvirt_nodes: Api<LibvirtNode> = Api::all(client.clone());
 
     let node_name = std::env::var("NODE_NAME").expect("NODE_NAME should be set");
@@ -14,6 +55,9 @@ pub async fn update(libvirt: &Libvirt, client: Client) -> Result<(), Error> {
     if let Some(libvirt_node) = libvirt_nodes.get_opt(&node_name).await? {
         let mut status = libvirt_node.status.as_ref().cloned().unwrap_or_default();
         status.capabilities = capabilities;
+        status.domain_capabilities = domain_capabilities;
+        status.hugepages = hugepages;
+        status.pci_devices = pci_devices;
         set_libvirtnode_status(&libvirt_node, status, client.clone()).await?;
     } else {
         libvirt_nodes
@@ -25,7 +69,13 @@ pub async fn update(libvirt: &Libvirt, client: Client) -> Result<(), Error> {
                         ..Default::default()
                     },
                     spec: Default::default(),
-                    status: Some(LibvirtNodeStatus { capabilities }),
+                    status: Some(LibvirtNodeStatus {
+                        capabilities,
+                        domain_capabilities,
+                        hugepages,
+                        pci_devices,
+                        ..Default::default()
+                    }),
                 },
             )
             .await?;
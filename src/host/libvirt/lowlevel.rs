@@ -1,20 +1,30 @@
 use crate::Error::Volumelocked;
 use crate::crd::cluster::Cluster;
-use crate::crd::virtualmachine::{VirtualMachine, VolumeAttachment};
+use crate::crd::libvirtnode::CpuAllocation;
+use crate::crd::virtualmachine::v1beta3::Firmware;
+use crate::crd::virtualmachine::{
+    DomainPatch, DomainPatchOperation, VirtualMachine, VolumeAttachment,
+};
 use askama::Template;
 use kube::ResourceExt;
+use roxmltree::Document;
 use tracing::debug;
 use virt::connect::Connect;
 use virt::domain::Domain;
+use virt::error::ErrorNumber;
 
 use crate::errors::Error;
+use crate::host::libvirt::firmware::{firmware_images, nvram_image, tpm_state_path};
 use crate::host::libvirt::templates::{
-    CephSource, DomainTemplate, FilesystemSource, NetworkInterfaceTemplate, StorageSource,
-    StorageTemplate,
+    CephSource, CpuTuneTemplate, DomainTemplate, FilesystemSource, FirmwareTemplate,
+    HostDeviceTemplate, NetworkInterfaceTemplate, NumaTuneTemplate, StorageSource, StorageTemplate,
+    VcpuPin,
 };
-use crate::host::libvirt::utils::{get_domain_name, parse_memory};
+use crate::host::libvirt::utils::get_domain_name;
 use crate::shared::ceph;
+use crate::utils::libvirt_domain_overrides::{apply_domain_overrides, domain_patches};
 use crate::utils::libvirt_storage::{StorageType, parse_storage_location};
+use crate::utils::strings::parse_memory_bytes;
 use crate::utils::traits::kube::TryStatus;
 
 pub struct Libvirt {
@@ -35,6 +45,65 @@ impl Drop for Libvirt {
     }
 }
 
+/// Format host CPUs as a libvirt cpuset
+fn cpuset(cpus: &[u32]) -> String {
+    cpus.iter()
+        .map(|cpu| cpu.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Pinning of the vCPUs and emulator threads of the VM to the dedicated host CPUs, and the
+/// binding of its memory to their NUMA cell
+fn cpu_tuning(
+    vm: &VirtualMachine,
+    cpu_allocation: Option<&CpuAllocation>,
+) -> (Option<CpuTuneTemplate>, Option<NumaTuneTemplate>) {
+    let cputune = cpu_allocation.map(|allocation| CpuTuneTemplate {
+        vcpu_pins: allocation
+            .cpus
+            .iter()
+            .enumerate()
+            .map(|(vcpu, cpu)| VcpuPin { vcpu, cpu: *cpu })
+            .collect(),
+        emulator_cpuset: cpuset(&allocation.cpus),
+    });
+    let numatune = cpu_allocation
+        .and_then(|allocation| allocation.numa_cell)
+        .map(|cell| NumaTuneTemplate {
+            mode: vm.spec.numa_memory_mode().to_string(),
+            nodeset: cell.to_string(),
+        });
+    (cputune, numatune)
+}
+
+/// Patch the top level element of the domain XML to the rendered one, adding or removing it
+fn set_domain_element(xml: String, name: &str, element: Option<String>) -> Result<String, Error> {
+    let exists = Document::parse(&xml)?
+        .root_element()
+        .children()
+        .any(|child| child.has_tag_name(name));
+    let patch = match (exists, element) {
+        (true, Some(element)) => DomainPatch {
+            path: format!("/domain/{name}"),
+            operation: DomainPatchOperation::Replace,
+            xml: Some(element),
+        },
+        (false, Some(element)) => DomainPatch {
+            path: String::from("/domain"),
+            operation: DomainPatchOperation::Add,
+            xml: Some(element),
+        },
+        (true, None) => DomainPatch {
+            path: format!("/domain/{name}"),
+            operation: DomainPatchOperation::Remove,
+            xml: None,
+        },
+        (false, None) => return Ok(xml),
+    };
+    apply_domain_overrides(xml, &[patch])
+}
+
 fn to_storage_source(volume: &VolumeAttachment, namespace: &str) -> Result<StorageSource, Error> {
     let (schema, location) = parse_storage_location(&volume.name)?;
     let source = match schema {
@@ -66,13 +135,19 @@ impl Libvirt {
         }
     }
 
-    pub fn create_domain(&self, vm: &VirtualMachine, cluster: &Cluster) -> Result<(), Error> {
+    pub fn create_domain(
+        &self,
+        vm: &VirtualMachine,
+        cluster: &Cluster,
+        cpu_allocation: Option<&CpuAllocation>,
+        pci_addresses: &[String],
+    ) -> Result<(), Error> {
         let namespace = ResourceExt::namespace(vm).expect("VM without namespace?");
 
         let storage_device_prefix;
         let storage_bus;
         let network_model;
-        if vm.spec.compatibility_mode.unwrap_or(false) {
+        if vm.compatibility_mode() {
             storage_device_prefix = "sd";
             storage_bus = "sata";
             network_model = "e1000";
@@ -114,33 +189,76 @@ impl Libvirt {
             })
         }
         debug!("{:?}", &vm);
-        let (memory_amount, memory_unit) = parse_memory(&vm.spec.memory)?;
+        let memory = parse_memory_bytes(vm.memory())? as usize;
+        let (cputune, numatune) = cpu_tuning(vm, cpu_allocation);
+        let firmware = firmware_images(vm, cluster).map(|images| FirmwareTemplate {
+            loader: images.loader,
+            secure_boot: vm.spec.get_firmware() == Firmware::UefiSecureBoot,
+            nvram_image: nvram_image(vm),
+        });
+        let host_devices = pci_addresses
+            .iter()
+            .map(|address| HostDeviceTemplate::from_address(address))
+            .collect::<Result<_, _>>()?;
         let xml = DomainTemplate {
             name: get_domain_name(vm).expect("no domain name specified"),
             uuid: vm.spec.uuid.clone().expect("VM has no UUID"),
             machine_type: vm
-                .spec
-                .machine_type
-                .clone()
+                .machine_type()
+                .cloned()
                 .unwrap_or(cluster.spec.machine_type.clone()),
-            cpu: vm
-                .spec
-                .cpu_model
-                .clone()
-                .unwrap_or(cluster.spec.cpu.clone()),
-            cpus: vm.spec.cpus,
-            memory: memory_amount,
-            memory_unit,
+            cpu: vm.cpu_model().cloned().unwrap_or(cluster.spec.cpu.clone()),
+            firmware,
+            cpus: vm.cpus(),
+            memory,
+            memory_unit: String::from("b"),
+            hugepage_size_kib: vm.spec.hugepages.as_ref().map(|size| size.size_kib()),
+            cputune,
+            numatune,
+            tpm_state_path: vm.spec.has_tpm().then(|| tpm_state_path(vm)),
+            host_devices,
             network_interfaces: nics,
             storage_devices: volumes,
         }
         .render()?;
 
+        let patches = domain_patches(vm, cluster);
+        if patches.is_empty() {
+            debug!("{}", xml);
+            Domain::create_xml(&self.connection, &xml, 0)?;
+            return Ok(());
+        }
+
+        // Have libvirt validate the overridden XML against its schema, to reject broken
+        // overrides with a readable message
+        let xml = apply_domain_overrides(xml, &patches)?;
         debug!("{}", xml);
-        Domain::create_xml(&self.connection, &xml, 0)?;
+        Domain::create_xml(&self.connection, &xml, virt::sys::VIR_DOMAIN_START_VALIDATE).map_err(
+            |error| match error.code() {
+                ErrorNumber::XmlInvalidSchema | ErrorNumber::XmlDetail => {
+                    Error::InvalidDomainOverrides(error.message().to_string())
+                }
+                _ => error.into(),
+            },
+        )?;
         Ok(())
     }
 
+    /// XML of a running domain to migrate it with, pinned to the CPUs allocated to it on the
+    /// destination instead of the ones it has here
+    pub fn migration_xml(
+        &self,
+        name: &str,
+        vm: &VirtualMachine,
+        cpu_allocation: &CpuAllocation,
+    ) -> Result<String, Error> {
+        let xml = Domain::lookup_by_name(&self.connection, name)?
+            .get_xml_desc(virt::sys::VIR_DOMAIN_XML_MIGRATABLE)?;
+        let (cputune, numatune) = cpu_tuning(vm, Some(cpu_allocation));
+        let xml = set_domain_element(xml, "cputune", cputune.map(|t| t.render()).transpose()?)?;
+        set_domain_element(xml, "numatune", numatune.map(|t| t.render()).transpose()?)
+    }
+
     pub fn has_domain(&self, name: &str) -> Result<bool, Error> {
         let domains = self.connection.list_all_domains(0)?;
         Ok(domains
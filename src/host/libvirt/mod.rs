@@ -1,6 +1,11 @@
 mod controller;
+mod cpu_pinning;
+mod credentials;
 mod evpn;
+mod firmware;
+mod guest_agent;
 mod handlers;
+mod host_devices;
 mod libvirtnode;
 mod lowlevel;
 mod secrets;
@@ -10,8 +15,26 @@ mod utils;
 use kube::Client;
 
 use crate::errors::Error;
+use crate::shared::ceph;
+use crate::utils::health::{HOST_HEALTH_PORT, HealthServer};
+
+pub(crate) use handlers::LIBVIRT_URI;
+pub(crate) use lowlevel::Libvirt;
 
 pub async fn run(client: Client) -> Result<(), Error> {
+    let health_server = HealthServer::default()
+        .with_readiness_check("libvirt", || async {
+            tokio::task::spawn_blocking(|| Libvirt::new(LIBVIRT_URI).map(|_| ()))
+                .await
+                .map_err(|error| Error::UnexpectedExit(error.to_string()))?
+        })
+        .with_readiness_check("ceph", || async {
+            tokio::task::spawn_blocking(ceph::check_connection)
+                .await
+                .map_err(|error| Error::UnexpectedExit(error.to_string()))?
+        });
+    tokio::spawn(health_server.bind(HOST_HEALTH_PORT)?);
+
     controller::create(client.clone()).await?;
     Ok(())
 }
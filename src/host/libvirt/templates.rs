@@ -1,5 +1,7 @@
 use askama::Template;
 
+use crate::errors::Error;
+
 #[derive(Template)]
 #[template(path = "domain.xml", escape = "none")]
 pub struct DomainTemplate {
@@ -8,15 +10,81 @@ pub struct DomainTemplate {
 
     pub machine_type: String,
     pub cpu: String,
+    pub firmware: Option<FirmwareTemplate>,
 
     pub cpus: usize,
     pub memory: usize,
     pub memory_unit: String,
+    pub hugepage_size_kib: Option<u64>,
+
+    pub cputune: Option<CpuTuneTemplate>,
+    pub numatune: Option<NumaTuneTemplate>,
+
+    pub tpm_state_path: Option<String>,
+    pub host_devices: Vec<HostDeviceTemplate>,
 
     pub network_interfaces: Vec<NetworkInterfaceTemplate>,
     pub storage_devices: Vec<StorageTemplate>,
 }
 
+/// UEFI firmware with the NVRAM of the VM on RBD
+pub struct FirmwareTemplate {
+    pub loader: String,
+    pub secure_boot: bool,
+    pub nvram_image: String,
+}
+
+/// A host PCI device passed through to the VM
+pub struct HostDeviceTemplate {
+    pub domain: String,
+    pub bus: String,
+    pub slot: String,
+    pub function: String,
+}
+
+impl HostDeviceTemplate {
+    /// Split a PCI address like 0000:3b:01.1 into its parts
+    pub fn from_address(address: &str) -> Result<HostDeviceTemplate, Error> {
+        let parts = address.split_once(':').and_then(|(domain, rest)| {
+            let (bus, rest) = rest.split_once(':')?;
+            let (slot, function) = rest.split_once('.')?;
+            Some((domain, bus, slot, function))
+        });
+        let Some((domain, bus, slot, function)) = parts else {
+            return Err(Error::InvalidResource(format!(
+                "malformed PCI address {address}"
+            )));
+        };
+        Ok(HostDeviceTemplate {
+            domain: domain.to_string(),
+            bus: bus.to_string(),
+            slot: slot.to_string(),
+            function: function.to_string(),
+        })
+    }
+}
+
+pub struct VcpuPin {
+    pub vcpu: usize,
+    pub cpu: u32,
+}
+
+/// Pinning of the vCPUs and the emulator threads to dedicated host CPUs
+#[derive(Template)]
+#[template(path = "cputune.xml", escape = "none")]
+pub struct CpuTuneTemplate {
+    pub vcpu_pins: Vec<VcpuPin>,
+    pub emulator_cpuset: String,
+}
+
+/// Binding of the guest memory to a host NUMA cell
+#[derive(Template)]
+#[template(path = "numatune.xml", escape = "none")]
+pub struct NumaTuneTemplate {
+    pub mode: String,
+    pub nodeset: String,
+}
+
 #[derive(Template)]
 #[template(path = "network_interface.xml", escape = "none")]
 pub struct NetworkInterfaceTemplate {
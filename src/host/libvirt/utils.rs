@@ -1,11 +1,11 @@
 use crate::crd::cluster::Cluster;
+use crate::crd::libvirtnode::LibvirtNode;
 use crate::crd::virtualmachine::VirtualMachine;
 use crate::errors::ClusterNotFound;
 use crate::host::libvirt::controller::State;
 use crate::Error;
 use kube::{Api, ResourceExt};
-use lazy_static::lazy_static;
-use regex::Regex;
+use std::env;
 use std::sync::Arc;
 use tracing::warn;
 
@@ -43,13 +43,9 @@ pub async fn get_cluster(ctx: &Arc<State>) -> Result<Cluster, ClusterNotFound> {
     }
 }
 
-pub fn parse_memory(input: &str) -> Result<(usize, String), Error> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"(\d+)\s*([a-zA-Z]+)").unwrap();
-    }
-    let captures = RE.captures(input).unwrap();
-    Ok((
-        captures.get(1).unwrap().as_str().parse().unwrap(),
-        captures.get(2).unwrap().as_str().to_string(),
-    ))
+/// The LibvirtNode of the node this host controller runs on
+pub async fn get_own_libvirtnode(ctx: &Arc<State>) -> Result<LibvirtNode, Error> {
+    let node_name = env::var("NODE_NAME").expect("failed to read $NODE_NAME");
+    let libvirt_nodes: Api<LibvirtNode> = Api::all(ctx.kube.clone());
+    Ok(libvirt_nodes.get(&node_name).await?)
 }
@@ -9,3 +9,8 @@ pub const NO_SCHEDULE_ANNOTATION: &str = "cluster-virt.acl.fi/no-schedule";
 
 // VM annotations
 pub const MIGRATION_REQUEST_ANNOTATION: &str = "cluster-virt.acl.fi/migration-required";
+
+// VM and Volume labels
+pub const POOL_LABEL: &str = "cluster-virt.acl.fi/pool";
+pub const POOL_INDEX_LABEL: &str = "cluster-virt.acl.fi/pool-index";
+pub const POOL_TEMPLATE_HASH_LABEL: &str = "cluster-virt.acl.fi/pool-template-hash";
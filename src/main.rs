@@ -17,6 +17,7 @@ mod labels_and_annotations;
 mod logging;
 mod metadataservice;
 mod shared;
+mod webhook;
 
 const NAMESPACE: &str = "virt-controller";
 const GROUP_NAME: &str = "cluster-virt.acl.fi";
@@ -45,6 +46,15 @@ async fn main() -> Result<(), Error> {
     } else if args.contains(&String::from("--metadata-service")) {
         info!("Staring metadata service mode");
         metadataservice::run(args, client).await?;
+    } else if args.contains(&String::from("--console-gateway")) {
+        info!("Starting console gateway mode");
+        host::console::run(client).await?;
+    } else if args.contains(&String::from("--webhook")) {
+        info!("Starting admission webhook mode");
+        webhook::run(client).await?;
+    } else if args.contains(&String::from("--explain-schedule")) {
+        info!("Starting schedule explain mode");
+        cluster::explain_schedule(args, client).await?;
     } else {
         info!("Starting cluster-mode");
         cluster::run(client, NAMESPACE).await?;
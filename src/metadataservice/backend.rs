@@ -1,6 +1,7 @@
 use k8s_openapi::api::core::v1::ConfigMap;
 use kube::{Api, Client, Resource, ResourceExt};
 use std::sync::Arc;
+use tokio::sync::Mutex;
 use tokio::sync::mpsc::Receiver;
 use tracing::{error, info};
 
@@ -13,7 +14,8 @@ use crate::metadataservice::protocol::{MetadataPayload, MetadataRequest, Metadat
 use crate::utils::traits::kube::ApiExt;
 
 pub struct MetadataBackend {
-    channel_endpoint: Receiver<MetadataRequest>,
+    // Shared so a restarted backend picks up the requests of the running proxy
+    channel_endpoint: Arc<Mutex<Receiver<MetadataRequest>>>,
     // Reserved as part of the interface
     #[allow(dead_code)]
     client: Client,
@@ -21,7 +23,7 @@ pub struct MetadataBackend {
 
 impl MetadataBackend {
     pub async fn run(
-        channel_endpoint: Receiver<MetadataRequest>,
+        channel_endpoint: Arc<Mutex<Receiver<MetadataRequest>>>,
         client: Client,
     ) -> Result<(), Error> {
         info!("backend: Starting metadata backend");
@@ -34,7 +36,8 @@ impl MetadataBackend {
 
     async fn main(&mut self) -> Result<(), Error> {
         loop {
-            if let Some(msg) = self.channel_endpoint.recv().await {
+            let received = self.channel_endpoint.lock().await.recv().await;
+            if let Some(msg) = received {
                 let ip = msg.ip;
 
                 let ovn = Arc::new(Ovn::try_from_annotations(self.client.clone()).await?);
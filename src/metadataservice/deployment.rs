@@ -2,14 +2,29 @@ use k8s_openapi::api::apps::v1::Deployment;
 use k8s_openapi::api::core::v1::ServiceAccount;
 use k8s_openapi::api::rbac::v1::{ClusterRole, ClusterRoleBinding, PolicyRule, RoleRef, Subject};
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
-use kube::api::{Patch, PatchParams};
+use kube::api::{ListParams, Patch, PatchParams};
 use kube::{Api, Client, ResourceExt};
+use lazy_static::lazy_static;
 use serde_json::json;
+use std::collections::BTreeSet;
+use tokio::sync::Mutex;
 use tracing::instrument;
 
 use crate::cluster::get_running_image;
+use crate::utils::health::METADATA_HEALTH_PORT;
 use crate::Error;
 
+/// Number of ports above METADATA_HEALTH_PORT the health endpoints are spread over
+const HEALTH_PORT_RANGE: u16 = 1000;
+/// Label of the metadata service deployments
+const METADATA_SERVICE_LABEL: &str = "cluster-virt.acl.fi/metadata-service";
+/// Annotation with the health port allocated to a metadata service deployment
+const HEALTH_PORT_ANNOTATION: &str = "cluster-virt.acl.fi/health-port";
+
+lazy_static! {
+    static ref HEALTH_PORT_ALLOCATION: Mutex<()> = Mutex::new(());
+}
+
 fn make_service_account(namespace: &str) -> ServiceAccount {
     ServiceAccount {
         metadata: ObjectMeta {
@@ -40,6 +55,12 @@ fn make_cluster_role() -> ClusterRole {
                 verbs: vec![String::from("get")],
                 ..PolicyRule::default()
             },
+            PolicyRule {
+                api_groups: Some(vec![String::from("")]),
+                resources: Some(vec![String::from("nodes")]),
+                verbs: vec![String::from("list")],
+                ..PolicyRule::default()
+            },
         ]),
         ..ClusterRole::default()
     }
@@ -65,13 +86,69 @@ fn make_cluster_role_binding(namespace: &str) -> ClusterRoleBinding {
     }
 }
 
-pub fn make_deployment(image: &str, namespace: &str, router: &str) -> Result<Deployment, Error> {
+/// Lowest health port not used by the metadata service of another router. The pods of several
+/// routers can share a node on the host network, so each router gets its own port.
+fn free_health_port(used: &BTreeSet<u16>) -> Result<u16, Error> {
+    (METADATA_HEALTH_PORT..METADATA_HEALTH_PORT + HEALTH_PORT_RANGE)
+        .find(|port| !used.contains(port))
+        .ok_or_else(|| {
+            Error::InvalidResource(format!(
+                "all {HEALTH_PORT_RANGE} metadata service health ports are in use"
+            ))
+        })
+}
+
+#[cfg(test)]
+#[test]
+fn test_free_health_port() {
+    let used = BTreeSet::from([METADATA_HEALTH_PORT, METADATA_HEALTH_PORT + 2]);
+    assert_eq!(free_health_port(&used).unwrap(), METADATA_HEALTH_PORT + 1);
+    let all: BTreeSet<u16> =
+        (METADATA_HEALTH_PORT..METADATA_HEALTH_PORT + HEALTH_PORT_RANGE).collect();
+    assert!(free_health_port(&all).is_err());
+}
+
+/// The health port of the metadata service of the router, the one it already has or a free one
+async fn allocate_health_port(client: Client, namespace: &str, router: &str) -> Result<u16, Error> {
+    let deployments: Api<Deployment> = Api::all(client);
+    let name = format!("metadata-{}-{}", namespace, router);
+    let mut used = BTreeSet::new();
+    let listed = deployments
+        .list(&ListParams::default().labels(METADATA_SERVICE_LABEL))
+        .await?;
+    for deployment in listed {
+        let Some(port) = deployment
+            .annotations()
+            .get(HEALTH_PORT_ANNOTATION)
+            .and_then(|port| port.parse().ok())
+        else {
+            continue;
+        };
+        if deployment.name_any() == name && deployment.namespace().as_deref() == Some(namespace) {
+            return Ok(port);
+        }
+        used.insert(port);
+    }
+    free_health_port(&used)
+}
+
+pub fn make_deployment(
+    image: &str,
+    namespace: &str,
+    router: &str,
+    health_port: u16,
+) -> Result<Deployment, Error> {
     let ds: Deployment = serde_json::from_value(json!({
       "apiVersion": "apps/v1",
       "kind": "Deployment",
       "metadata": {
         "name": format!("metadata-{}-{}", namespace, router),
-        "labels": {}
+        "labels": {
+          METADATA_SERVICE_LABEL: "true"
+        },
+        "annotations": {
+          HEALTH_PORT_ANNOTATION: health_port.to_string()
+        }
       },
       "spec": {
         "selector": {
@@ -107,8 +184,18 @@ pub fn make_deployment(image: &str, namespace: &str, router: &str) -> Result<Dep
                   {
                     "name": "OTLP_ENDPOINT",
                     "value": "http://10.4.131.101:4317"
+                  },
+                  {
+                    "name": "HEALTH_PORT",
+                    "value": health_port.to_string()
                   }
                 ],
+                "livenessProbe": {
+                  "httpGet": { "path": "/healthz", "port": health_port }
+                },
+                "readinessProbe": {
+                  "httpGet": { "path": "/readyz", "port": health_port }
+                },
                 "securityContext": {
                   "privileged": true
                 },
@@ -178,8 +265,16 @@ pub async fn create_deployment(
     router: &str,
 ) -> Result<(), Error> {
     let deployments: Api<Deployment> = Api::namespaced(client.clone(), namespace);
-    let metadataservice_deploy =
-        make_deployment(&get_running_image(client.clone()).await?, namespace, router)?;
+    // Held until the deployment is written, so that routers reconciled at the same time do not
+    // get the same port
+    let _allocation = HEALTH_PORT_ALLOCATION.lock().await;
+    let health_port = allocate_health_port(client.clone(), namespace, router).await?;
+    let metadataservice_deploy = make_deployment(
+        &get_running_image(client.clone()).await?,
+        namespace,
+        router,
+        health_port,
+    )?;
     deployments
         .patch(
             metadataservice_deploy.metadata.name.as_ref().unwrap(),
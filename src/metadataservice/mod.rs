@@ -1,9 +1,14 @@
 use kube::Client;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 use tokio::sync::mpsc::channel;
 use tracing::{error, info};
 
+use crate::interfaces::ovn::lowlevel::Ovn;
 use crate::metadataservice::backend::MetadataBackend;
 use crate::metadataservice::proxy::MetadataProxy;
+use crate::utils::health::{HealthServer, METADATA_HEALTH_PORT};
+use crate::utils::supervisor::Supervisor;
 use crate::Error;
 
 mod backend;
@@ -30,25 +35,37 @@ pub async fn run(args: Vec<String>, client: Client) -> Result<(), Error> {
 
     let (request_sender, request_receiver) = channel(REQUEST_CHANNEL_BUFFER_SIZE);
 
-    let proxy_task = tokio::task::spawn(async move {
-        let proxy_thread =
-            std::thread::spawn(move || MetadataProxy::run(request_sender, &router_name));
-        panic!(
-            "proxy thread exited: {:?}",
-            tokio::task::spawn_blocking(|| { proxy_thread.join() }).await
-        );
-    });
-
-    let backend_task = tokio::task::spawn(async {
-        panic!(
-            "metadata backend task exited: {:?}",
-            MetadataBackend::run(request_receiver, client).await
-        );
-    });
+    let request_receiver = Arc::new(Mutex::new(request_receiver));
 
-    info!("supervisor: keeping a watch on the threads");
-    let _ = tokio::try_join!(proxy_task, backend_task);
+    let supervisor = Supervisor::default()
+        .with_task("proxy", move || {
+            let (request_sender, router_name) = (request_sender.clone(), router_name.clone());
+            async move {
+                let proxy_thread =
+                    std::thread::spawn(move || MetadataProxy::run(request_sender, &router_name));
+                match tokio::task::spawn_blocking(|| proxy_thread.join()).await {
+                    Ok(Ok(result)) => result,
+                    _ => Err(Error::UnexpectedExit(String::from(
+                        "metadata proxy thread panicked",
+                    ))),
+                }
+            }
+        })
+        .with_task("backend", {
+            let client = client.clone();
+            move || MetadataBackend::run(request_receiver.clone(), client.clone())
+        });
+
+    let health_server = HealthServer::default()
+        .with_supervisor(supervisor.health())
+        .with_readiness_check("ovn", move || {
+            let client = client.clone();
+            async move { Ovn::try_from_annotations(client).await.map(|_| ()) }
+        });
+    tokio::spawn(health_server.bind(METADATA_HEALTH_PORT)?);
 
-    error!("supervisor: ERROR: One of the threads died, killing the rest of the application");
-    std::process::exit(1);
+    info!("supervisor: keeping a watch on the threads");
+    let error = supervisor.run().await;
+    error!("supervisor: ERROR: {error}");
+    Err(error)
 }
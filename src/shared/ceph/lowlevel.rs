@@ -10,7 +10,7 @@ use librados_sys::{
 
 use librbd_sys::{
     rbd_clone, rbd_close, rbd_create, rbd_get_features, rbd_image_t, rbd_list, rbd_list_lockers,
-    rbd_open, rbd_open_read_only, rbd_remove,
+    rbd_open, rbd_open_read_only, rbd_remove, rbd_write,
 };
 use tracing::{instrument, warn};
 
@@ -218,6 +218,21 @@ pub fn clone_image(
     Ok(())
 }
 
+pub fn write_image(pool: rados_ioctx_t, name: &str, data: &[u8]) -> Result<(), Error> {
+    unsafe {
+        create_cstring!([(name_c, name)]);
+
+        let mut image: rbd_image_t = 0 as rbd_image_t;
+        call!("rbd_open", rbd_open(pool, name_c, &mut image, ptr::null()));
+
+        let written = rbd_write(image, 0, data.len(), data.as_ptr() as *const c_char);
+        call!("rbd_close", rbd_close(image));
+        drop_cstring!([name_c]);
+        call!("rbd_write", written as c_int);
+    }
+    Ok(())
+}
+
 pub fn remove_image(pool: rados_ioctx_t, name: &str) -> Result<(), Error> {
     unsafe {
         let name_c = CString::new(name)
@@ -2,9 +2,59 @@ use crate::Error;
 
 pub mod lowlevel;
 
+/// Whether the cluster can be connected to, for readiness checks
+pub fn check_connection() -> Result<(), Error> {
+    let cluster = lowlevel::connect()?;
+    lowlevel::get_pools(cluster)?;
+    lowlevel::disconnect(cluster);
+    Ok(())
+}
+
 pub fn has_locks(pool_name: &str, image_name: &str) -> Result<bool, Error> {
     let cluster = lowlevel::connect()?;
     let pool = lowlevel::get_pool(cluster, pool_name.into())?;
 
     lowlevel::has_locks(pool, image_name)
 }
+
+/// Create an image holding the given content unless it already exists. Existing images are left
+/// as they are, as they carry state.
+pub fn ensure_image_with_content(
+    pool_name: &str,
+    image_name: &str,
+    size: u64,
+    content: &[u8],
+) -> Result<(), Error> {
+    let cluster = lowlevel::connect()?;
+    let pool = lowlevel::get_pool(cluster, pool_name.into())?;
+
+    let exists = lowlevel::get_images(pool)?
+        .iter()
+        .any(|existing| existing == image_name);
+    if !exists {
+        lowlevel::create_image(pool, image_name, size)?;
+        if !content.is_empty() {
+            lowlevel::write_image(pool, image_name, content)?;
+        }
+    }
+
+    lowlevel::close_pool(pool);
+    lowlevel::disconnect(cluster);
+    Ok(())
+}
+
+pub fn remove_image_if_exists(pool_name: &str, image_name: &str) -> Result<(), Error> {
+    let cluster = lowlevel::connect()?;
+    let pool = lowlevel::get_pool(cluster, pool_name.into())?;
+
+    let exists = lowlevel::get_images(pool)?
+        .iter()
+        .any(|existing| existing == image_name);
+    if exists {
+        lowlevel::remove_image(pool, image_name)?;
+    }
+
+    lowlevel::close_pool(pool);
+    lowlevel::disconnect(cluster);
+    Ok(())
+}
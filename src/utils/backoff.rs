@@ -0,0 +1,139 @@
+use kube::ResourceExt;
+use kube::runtime::controller::Action;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::errors::Error;
+
+const DEFAULT_BASE: Duration = Duration::from_secs(5);
+const DEFAULT_MAX: Duration = Duration::from_secs(300);
+/// Permanent errors are fixed by changing the object, which triggers a reconcile anyway
+const DEFAULT_PERMANENT_RETRY: Duration = Duration::from_secs(600);
+/// Share of the delay randomly taken off, so that objects failing together spread out
+const JITTER: f64 = 0.2;
+/// Failures are forgotten once the object has not failed for this many maximum delays, e.g.
+/// because it was deleted while failing
+const EXPIRY_FACTOR: u32 = 2;
+
+/// Error policy requeueing failed objects with a per-object exponential backoff. Transient
+/// errors, like a lost OVN connection, are retried soon and then less and less often, while
+/// permanent errors, like an unparseable storage location, are only retried once in a while.
+#[derive(Debug, Clone)]
+pub struct ErrorBackoff {
+    base: Duration,
+    max: Duration,
+    permanent_retry: Duration,
+    /// Failures in a row and the time of the last one, by namespaced object name
+    failures: Arc<Mutex<HashMap<String, (u32, Instant)>>>,
+}
+
+impl Default for ErrorBackoff {
+    fn default() -> Self {
+        ErrorBackoff::new(DEFAULT_BASE, DEFAULT_MAX)
+    }
+}
+
+impl ErrorBackoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        ErrorBackoff {
+            base,
+            max,
+            permanent_retry: DEFAULT_PERMANENT_RETRY,
+            failures: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_permanent_retry(mut self, permanent_retry: Duration) -> Self {
+        self.permanent_retry = permanent_retry;
+        self
+    }
+
+    /// When to reconcile the object again after the error
+    pub fn on_error<K: ResourceExt>(&self, object: &K, error: &Error) -> Action {
+        if error.is_permanent() {
+            debug!(
+                "{} failed permanently, retrying in {:?}",
+                key(object),
+                self.permanent_retry
+            );
+            return Action::requeue(self.permanent_retry);
+        }
+
+        let failures = {
+            let mut failures = self.failures.lock().expect("backoff lock");
+            let now = Instant::now();
+            let expiry = self.max.saturating_mul(EXPIRY_FACTOR);
+            failures.retain(|_key, (_count, last)| now.duration_since(*last) <= expiry);
+            let (count, last) = failures.entry(key(object)).or_insert((0, now));
+            *count = count.saturating_add(1);
+            *last = now;
+            *count
+        };
+        let delay = self
+            .delay(failures)
+            .mul_f64(1.0 - rand::thread_rng().gen_range(0.0..JITTER));
+        debug!(
+            "{} failed {failures} times, retrying in {delay:?}",
+            key(object)
+        );
+        Action::requeue(delay)
+    }
+
+    /// Forget the failures of the object after it reconciled successfully
+    pub fn reset<K: ResourceExt>(&self, object: &K) {
+        self.failures
+            .lock()
+            .expect("backoff lock")
+            .remove(&key(object));
+    }
+
+    /// Delay before retrying after the given number of failures in a row, without the jitter
+    fn delay(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(16);
+        self.base.saturating_mul(2u32.pow(exponent)).min(self.max)
+    }
+}
+
+fn key<K: ResourceExt>(object: &K) -> String {
+    format!(
+        "{}/{}",
+        object.namespace().unwrap_or_default(),
+        object.name_any()
+    )
+}
+
+#[cfg(test)]
+#[test]
+fn test_delay() {
+    let backoff = ErrorBackoff::new(Duration::from_secs(5), Duration::from_secs(300));
+    assert_eq!(backoff.delay(1), Duration::from_secs(5));
+    assert_eq!(backoff.delay(3), Duration::from_secs(20));
+    assert_eq!(backoff.delay(7), Duration::from_secs(300));
+    assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(300));
+}
+
+#[cfg(test)]
+#[test]
+fn test_failures_expire() {
+    use k8s_openapi::api::core::v1::ConfigMap;
+
+    let backoff = ErrorBackoff::new(Duration::from_millis(1), Duration::from_millis(1));
+    let object = |name: &str| {
+        let mut object = ConfigMap::default();
+        object.metadata.name = Some(String::from(name));
+        object
+    };
+    let error = Error::Timeout(String::from("test"));
+
+    backoff.on_error(&object("deleted"), &error);
+    backoff.on_error(&object("failing"), &error);
+    std::thread::sleep(Duration::from_millis(5));
+    backoff.on_error(&object("failing"), &error);
+
+    let failures = backoff.failures.lock().unwrap();
+    assert!(!failures.contains_key("/deleted"));
+    assert_eq!(failures["/failing"].0, 1);
+}
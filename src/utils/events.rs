@@ -0,0 +1,148 @@
+use k8s_openapi::api::core::v1::ObjectReference;
+use kube::runtime::events::{Event, EventType, Recorder, Reporter};
+use kube::{Client, Resource};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+use crate::errors::Error;
+
+/// Name the events are reported by, the instance is the pod or node
+const REPORTING_CONTROLLER: &str = "cluster-controller";
+
+/// Publishes Kubernetes Events about resources, so that `kubectl describe` shows what happened
+/// to them. Events are informational, failing to publish one is only logged.
+#[derive(Clone)]
+pub struct EventRecorder {
+    client: Client,
+    reporter: Reporter,
+    /// Last reconcile error published for each failing object, by namespaced name
+    reconcile_errors: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl EventRecorder {
+    pub fn new(client: Client) -> Self {
+        let instance = env::var("POD_NAME").or_else(|_| env::var("NODE_NAME")).ok();
+        EventRecorder {
+            client,
+            reporter: Reporter {
+                controller: String::from(REPORTING_CONTROLLER),
+                instance,
+            },
+            reconcile_errors: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn publish<K>(
+        &self,
+        object: &K,
+        type_: EventType,
+        reason: &str,
+        action: &str,
+        note: String,
+    ) where
+        K: Resource,
+        K::DynamicType: Default,
+    {
+        self.send(object, type_, reason, action, note, None).await;
+    }
+
+    /// Publish an event about the object which also refers to another object involved, e.g.
+    /// the VM that preempted it
+    pub async fn publish_related<K, R>(
+        &self,
+        object: &K,
+        type_: EventType,
+        reason: &str,
+        action: &str,
+        note: String,
+        related: &R,
+    ) where
+        K: Resource,
+        K::DynamicType: Default,
+        R: Resource,
+        R::DynamicType: Default,
+    {
+        let secondary = related.object_ref(&R::DynamicType::default());
+        self.send(object, type_, reason, action, note, Some(secondary))
+            .await;
+    }
+
+    async fn send<K>(
+        &self,
+        object: &K,
+        type_: EventType,
+        reason: &str,
+        action: &str,
+        note: String,
+        secondary: Option<ObjectReference>,
+    ) where
+        K: Resource,
+        K::DynamicType: Default,
+    {
+        let reference = object.object_ref(&K::DynamicType::default());
+        let recorder = Recorder::new(self.client.clone(), self.reporter.clone(), reference);
+        let event = Event {
+            type_,
+            reason: reason.into(),
+            note: Some(note),
+            action: action.into(),
+            secondary,
+        };
+        if let Err(error) = recorder.publish(event).await {
+            warn!("Failed to publish a {reason} event: {error}");
+        }
+    }
+
+    pub async fn normal<K>(&self, object: &K, reason: &str, action: &str, note: String)
+    where
+        K: Resource,
+        K::DynamicType: Default,
+    {
+        self.publish(object, EventType::Normal, reason, action, note)
+            .await;
+    }
+
+    /// Record a failed reconcile of the object. Retries failing with the same error as the last
+    /// one are not published again, so that a stuck object does not flood its events.
+    pub async fn reconcile_failed<K>(&self, object: &K, error: &Error)
+    where
+        K: Resource,
+        K::DynamicType: Default,
+    {
+        let note = error.to_string();
+        let previous = self
+            .reconcile_errors
+            .lock()
+            .expect("reconcile errors lock")
+            .insert(key(object), note.clone());
+        if previous.as_ref() == Some(&note) {
+            return;
+        }
+        self.publish(
+            object,
+            EventType::Warning,
+            "ReconcileFailed",
+            "Reconcile",
+            note,
+        )
+        .await;
+    }
+
+    /// Forget the last reconcile error of the object after it reconciled successfully
+    pub fn reconcile_succeeded<K: Resource>(&self, object: &K) {
+        self.reconcile_errors
+            .lock()
+            .expect("reconcile errors lock")
+            .remove(&key(object));
+    }
+}
+
+fn key<K: Resource>(object: &K) -> String {
+    format!(
+        "{}/{}",
+        object.meta().namespace.as_deref().unwrap_or_default(),
+        object.meta().name.as_deref().unwrap_or_default()
+    )
+}
@@ -0,0 +1,148 @@
+use futures::FutureExt;
+use futures::future::{BoxFuture, join, join_all};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::env;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, sleep, timeout};
+use tracing::info;
+use warp::Filter;
+use warp::http::StatusCode;
+
+use crate::errors::Error;
+use crate::utils::metrics;
+use crate::utils::supervisor::SupervisorHealth;
+
+/// Default ports of the health endpoint by mode. They run on the host network, so the ports
+/// differ and can be overridden with $HEALTH_PORT.
+pub const CLUSTER_HEALTH_PORT: u16 = 9440;
+pub const HOST_HEALTH_PORT: u16 = 9441;
+pub const METADATA_HEALTH_PORT: u16 = 9442;
+
+/// The readiness checks connect to the backends, so they run in the background once in a while
+/// instead of on every probe
+const READINESS_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// A backend which does not answer in time is reported as not ready
+const READINESS_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+type ReadinessCheck = Box<dyn Fn() -> BoxFuture<'static, Result<(), Error>> + Send + Sync>;
+/// Result of the last run of each readiness check by name
+type ReadinessResults = Arc<Mutex<BTreeMap<String, Result<(), String>>>>;
+
+/// HTTP server for the probes and Prometheus:
+/// - `/healthz` fails when the supervisor has given up on a task, so that the pod is restarted
+/// - `/readyz` fails when the last run of one of the readiness checks, like reaching OVN, failed
+///   or a supervised task is waiting to be restarted
+/// - `/metrics` exposes the reconcile and task metrics
+#[derive(Default)]
+pub struct HealthServer {
+    supervisor: Option<SupervisorHealth>,
+    checks: Vec<(String, ReadinessCheck)>,
+}
+
+impl HealthServer {
+    pub fn with_supervisor(mut self, health: SupervisorHealth) -> Self {
+        self.supervisor = Some(health);
+        self
+    }
+
+    pub fn with_readiness_check<F, Fut>(mut self, name: &str, check: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        self.checks
+            .push((name.to_string(), Box::new(move || check().boxed())));
+        self
+    }
+
+    /// Bind to $HEALTH_PORT or the default port of the mode, returning the server to spawn
+    /// together with the background readiness checks. A port which is already taken is an
+    /// error, instead of a panic in the spawned task.
+    pub fn bind(self, default_port: u16) -> Result<impl Future<Output = ()>, Error> {
+        let port = env::var("HEALTH_PORT")
+            .ok()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(default_port);
+        let supervisor = self.supervisor;
+        let names: Arc<Vec<String>> =
+            Arc::new(self.checks.iter().map(|(name, _)| name.clone()).collect());
+        let results = ReadinessResults::default();
+        let checker = run_readiness_checks(self.checks, results.clone());
+
+        let liveness = supervisor.clone();
+        let healthz = warp::path("healthz")
+            .and(warp::path::end())
+            .map(move || match &liveness {
+                Some(health) if !health.is_alive() => {
+                    let tasks = warp::reply::json(&health.tasks());
+                    warp::reply::with_status(tasks, StatusCode::SERVICE_UNAVAILABLE)
+                }
+                _ => warp::reply::with_status(warp::reply::json(&"ok"), StatusCode::OK),
+            });
+        let readiness_supervisor = supervisor.clone();
+        let readyz = warp::path("readyz")
+            .and(warp::path::end())
+            .and_then(move || {
+                readiness(names.clone(), results.clone(), readiness_supervisor.clone())
+            });
+        let metrics = warp::path("metrics")
+            .and(warp::path::end())
+            .map(move || metrics::render(supervisor.as_ref()));
+        let routes = warp::get().and(healthz.or(readyz).or(metrics));
+
+        let (address, server) = warp::serve(routes)
+            .try_bind_ephemeral(([0, 0, 0, 0], port))
+            .map_err(|error| Error::HealthServerBind(port, error.to_string()))?;
+        info!("Serving health checks and metrics on {address}");
+        Ok(join(server, checker).map(|_| ()))
+    }
+}
+
+/// Run the readiness checks periodically, each with a timeout, and keep their last results
+async fn run_readiness_checks(checks: Vec<(String, ReadinessCheck)>, results: ReadinessResults) {
+    loop {
+        let outcomes = join_all(checks.iter().map(|(name, check)| async move {
+            let outcome = match timeout(READINESS_CHECK_TIMEOUT, check()).await {
+                Ok(result) => result.map_err(|error| error.to_string()),
+                Err(_elapsed) => Err(format!("timed out after {READINESS_CHECK_TIMEOUT:?}")),
+            };
+            (name.clone(), outcome)
+        }))
+        .await;
+        *results.lock().expect("readiness results lock") = outcomes.into_iter().collect();
+        sleep(READINESS_CHECK_INTERVAL).await;
+    }
+}
+
+async fn readiness(
+    names: Arc<Vec<String>>,
+    results: ReadinessResults,
+    supervisor: Option<SupervisorHealth>,
+) -> Result<impl warp::Reply, Infallible> {
+    let results = results.lock().expect("readiness results lock").clone();
+    let mut failures: Vec<String> = names
+        .iter()
+        .filter_map(|name| match results.get(name) {
+            Some(Ok(())) => None,
+            Some(Err(error)) => Some(format!("{name}: {error}")),
+            None => Some(format!("{name}: not checked yet")),
+        })
+        .collect();
+    let tasks = supervisor.map(|health| health.tasks()).unwrap_or_default();
+    failures.extend(
+        tasks
+            .iter()
+            .filter(|(_name, task)| !task.running)
+            .map(|(name, _task)| format!("{name}: not running")),
+    );
+    if failures.is_empty() {
+        Ok(warp::reply::with_status(String::from("ok"), StatusCode::OK))
+    } else {
+        Ok(warp::reply::with_status(
+            failures.join("\n"),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ))
+    }
+}
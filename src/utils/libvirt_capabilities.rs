@@ -0,0 +1,464 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use roxmltree::{Document, Node};
+
+use crate::crd::libvirtnode::{CpuAllocation, LibvirtNode};
+use crate::errors::Error;
+
+/// The parts of the libvirt host and domain capabilities relevant for placing VMs
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HostCapabilities {
+    pub cpu_vendor: Option<String>,
+    pub cpu_model: Option<String>,
+    pub cpu_features: BTreeSet<String>,
+    /// Machine types supported by the hypervisor, including aliases like "q35"
+    pub machine_types: BTreeSet<String>,
+
+    /// CPU models usable in custom mode, empty if domain capabilities are not known
+    pub usable_cpu_models: BTreeSet<String>,
+    /// Features of the host-model CPU which the host does not actually support
+    pub missing_cpu_features: BTreeSet<String>,
+
+    /// NUMA cells of the host with their CPUs, empty if the topology is not reported
+    pub numa_cells: Vec<NumaCell>,
+    /// Hugepages configured over all NUMA cells as number of pages by page size in KiB
+    pub hugepages: BTreeMap<u64, u64>,
+}
+
+/// A host NUMA cell, with its CPUs grouped by the physical core they are threads of
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NumaCell {
+    pub id: u32,
+    pub cores: Vec<Vec<u32>>,
+}
+
+/// The CPU requested for a VM, parsed from the <cpu> element of the VM or cluster
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CpuDefinition {
+    pub mode: String,
+    pub model: Option<String>,
+    pub vendor: Option<String>,
+    pub required_features: BTreeSet<String>,
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, tag: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| child.has_tag_name(tag))
+}
+
+fn child_text(node: Node, tag: &str) -> Option<String> {
+    child(node, tag)
+        .and_then(|child| child.text())
+        .map(|text| text.trim().to_string())
+}
+
+/// Parse the output of virConnectGetCapabilities
+pub fn parse_capabilities(xml: &str) -> Result<HostCapabilities, Error> {
+    let document = Document::parse(xml)?;
+    let root = document.root_element();
+    let mut capabilities = HostCapabilities::default();
+
+    if let Some(cpu) = child(root, "host").and_then(|host| child(host, "cpu")) {
+        capabilities.cpu_vendor = child_text(cpu, "vendor");
+        capabilities.cpu_model = child_text(cpu, "model");
+        capabilities.cpu_features = cpu
+            .children()
+            .filter(|node| node.has_tag_name("feature"))
+            .filter_map(|feature| feature.attribute("name"))
+            .map(String::from)
+            .collect();
+    }
+
+    let cells = child(root, "host")
+        .and_then(|host| child(host, "topology"))
+        .and_then(|topology| child(topology, "cells"))
+        .map(|cells| cells.children().filter(|node| node.has_tag_name("cell")))
+        .into_iter()
+        .flatten();
+    for cell in cells {
+        let Some(id) = cell.attribute("id").and_then(|id| id.parse().ok()) else {
+            continue;
+        };
+        // Threads of a core list each other as siblings, a CPU without siblings is its own core
+        let mut cores: Vec<(String, Vec<u32>)> = vec![];
+        let cpus = child(cell, "cpus")
+            .map(|cpus| cpus.children().filter(|node| node.has_tag_name("cpu")))
+            .into_iter()
+            .flatten();
+        for cpu in cpus {
+            let Some(cpu_id) = cpu.attribute("id").and_then(|id| id.parse().ok()) else {
+                continue;
+            };
+            let siblings = cpu.attribute("siblings").unwrap_or_default();
+            match cores
+                .iter_mut()
+                .find(|(key, _)| !siblings.is_empty() && key == siblings)
+            {
+                Some((_, threads)) => threads.push(cpu_id),
+                None => cores.push((siblings.to_string(), vec![cpu_id])),
+            }
+        }
+        // The smallest page size is the regular page size of the host, not a hugepage
+        let pages: Vec<(u64, u64)> = cell
+            .children()
+            .filter(|node| node.has_tag_name("pages"))
+            .filter_map(|pages| {
+                let size = pages.attribute("size")?.parse().ok()?;
+                let count = pages.text()?.trim().parse().ok()?;
+                Some((size, count))
+            })
+            .collect();
+        let base_size = pages.iter().map(|(size, _count)| *size).min();
+        for (size, count) in pages {
+            if Some(size) != base_size {
+                *capabilities.hugepages.entry(size).or_default() += count;
+            }
+        }
+
+        capabilities.numa_cells.push(NumaCell {
+            id,
+            cores: cores.into_iter().map(|(_, threads)| threads).collect(),
+        });
+    }
+
+    for machine in root
+        .children()
+        .filter(|node| node.has_tag_name("guest"))
+        .flat_map(|guest| guest.descendants())
+        .filter(|node| node.has_tag_name("machine"))
+    {
+        if let Some(name) = machine.text() {
+            capabilities.machine_types.insert(name.trim().to_string());
+        }
+        if let Some(canonical) = machine.attribute("canonical") {
+            capabilities.machine_types.insert(canonical.to_string());
+        }
+    }
+
+    Ok(capabilities)
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_capabilities() {
+    let xml = r#"
+        <capabilities>
+          <host>
+            <cpu>
+              <arch>x86_64</arch>
+              <model>Skylake-Client-IBRS</model>
+              <vendor>Intel</vendor>
+              <feature name='ss'/>
+              <feature name='vmx'/>
+            </cpu>
+            <topology>
+              <cells num='2'>
+                <cell id='0'>
+                  <pages unit='KiB' size='4'>1000</pages>
+                  <pages unit='KiB' size='2048'>512</pages>
+                  <pages unit='KiB' size='1048576'>0</pages>
+                  <cpus num='2'>
+                    <cpu id='0' socket_id='0' core_id='0' siblings='0,2'/>
+                    <cpu id='2' socket_id='0' core_id='0' siblings='0,2'/>
+                  </cpus>
+                </cell>
+                <cell id='1'>
+                  <pages unit='KiB' size='4'>1000</pages>
+                  <pages unit='KiB' size='2048'>256</pages>
+                  <cpus num='2'>
+                    <cpu id='1' socket_id='1' core_id='0' siblings='1'/>
+                    <cpu id='3' socket_id='1' core_id='1' siblings='3'/>
+                  </cpus>
+                </cell>
+              </cells>
+            </topology>
+          </host>
+          <guest>
+            <os_type>hvm</os_type>
+            <arch name='x86_64'>
+              <machine maxCpus='710'>pc-q35-rhel8.6.0</machine>
+              <machine canonical='pc-q35-rhel8.6.0' maxCpus='710'>q35</machine>
+            </arch>
+          </guest>
+        </capabilities>"#;
+    let capabilities = parse_capabilities(xml).unwrap();
+    assert_eq!(capabilities.cpu_vendor, Some(String::from("Intel")));
+    assert_eq!(
+        capabilities.cpu_model,
+        Some(String::from("Skylake-Client-IBRS"))
+    );
+    assert!(capabilities.cpu_features.contains("vmx"));
+    assert!(capabilities.machine_types.contains("q35"));
+    assert!(capabilities.machine_types.contains("pc-q35-rhel8.6.0"));
+    assert_eq!(
+        capabilities.numa_cells,
+        vec![
+            NumaCell {
+                id: 0,
+                cores: vec![vec![0, 2]]
+            },
+            NumaCell {
+                id: 1,
+                cores: vec![vec![1], vec![3]]
+            },
+        ]
+    );
+    assert_eq!(
+        capabilities.hugepages,
+        BTreeMap::from([(2048, 768), (1048576, 0)])
+    );
+}
+
+/// Parse the output of virConnectGetDomainCapabilities into existing host capabilities
+pub fn parse_domain_capabilities(
+    xml: &str,
+    capabilities: &mut HostCapabilities,
+) -> Result<(), Error> {
+    let document = Document::parse(xml)?;
+    let root = document.root_element();
+
+    let modes = child(root, "cpu")
+        .map(|cpu| cpu.children().filter(|node| node.has_tag_name("mode")))
+        .into_iter()
+        .flatten();
+
+    for mode in modes {
+        match mode.attribute("name") {
+            Some("custom") => {
+                capabilities.usable_cpu_models = mode
+                    .children()
+                    .filter(|node| node.has_tag_name("model"))
+                    .filter(|model| model.attribute("usable") == Some("yes"))
+                    .filter_map(|model| model.text())
+                    .map(|text| text.trim().to_string())
+                    .collect();
+            }
+            Some("host-model") => {
+                capabilities.missing_cpu_features = mode
+                    .children()
+                    .filter(|node| node.has_tag_name("feature"))
+                    .filter(|feature| feature.attribute("policy") == Some("disable"))
+                    .filter_map(|feature| feature.attribute("name"))
+                    .map(String::from)
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Parse a <cpu> element as used in the cluster and VM definitions
+pub fn parse_cpu_definition(xml: &str) -> Result<CpuDefinition, Error> {
+    let document = Document::parse(xml.trim())?;
+    let cpu = document.root_element();
+
+    Ok(CpuDefinition {
+        mode: cpu.attribute("mode").unwrap_or("custom").to_string(),
+        model: child_text(cpu, "model"),
+        vendor: child_text(cpu, "vendor"),
+        required_features: cpu
+            .children()
+            .filter(|node| node.has_tag_name("feature"))
+            .filter(|feature| matches!(feature.attribute("policy"), Some("require" | "force")))
+            .filter_map(|feature| feature.attribute("name"))
+            .map(String::from)
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_cpu_definition() {
+    let xml = r#"
+        <cpu mode='custom' match='exact' check='full'>
+            <model fallback='forbid'>IvyBridge-IBRS</model>
+            <vendor>Intel</vendor>
+            <feature policy='require' name='ss'/>
+            <feature policy='disable' name='hle'/>
+        </cpu>"#;
+    let cpu = parse_cpu_definition(xml).unwrap();
+    assert_eq!(cpu.mode, "custom");
+    assert_eq!(cpu.model, Some(String::from("IvyBridge-IBRS")));
+    assert_eq!(cpu.required_features, BTreeSet::from([String::from("ss")]));
+}
+
+/// Free CPUs of the given cells in allocation order: threads of completely free cores first, so
+/// that dedicated VMs share a core with each other as little as possible
+fn free_cpus<'a>(
+    cells: impl Iterator<Item = &'a NumaCell>,
+    unavailable: &BTreeSet<u32>,
+) -> Vec<u32> {
+    let mut whole_cores = vec![];
+    let mut partial_cores = vec![];
+    for core in cells.flat_map(|cell| cell.cores.iter()) {
+        let free: Vec<u32> = core
+            .iter()
+            .filter(|cpu| !unavailable.contains(cpu))
+            .copied()
+            .collect();
+        if free.len() == core.len() {
+            whole_cores.extend(free);
+        } else {
+            partial_cores.extend(free);
+        }
+    }
+    whole_cores.extend(partial_cores);
+    whole_cores
+}
+
+/// Name of a hugepage size given in KiB, as used in the LibvirtNode status
+pub fn hugepage_size_name(size_kib: u64) -> String {
+    match size_kib {
+        size if size % (1024 * 1024) == 0 => format!("{}G", size / (1024 * 1024)),
+        size if size % 1024 == 0 => format!("{}M", size / 1024),
+        size => format!("{size}K"),
+    }
+}
+
+impl HostCapabilities {
+    /// Parse the capabilities a libvirt host controller has reported for its node
+    pub fn from_libvirtnode(node: &LibvirtNode) -> Result<Option<HostCapabilities>, Error> {
+        let Some(status) = node.status.as_ref() else {
+            return Ok(None);
+        };
+        let mut capabilities = parse_capabilities(&status.capabilities)?;
+        if let Some(domain_capabilities) = status.domain_capabilities.as_ref() {
+            parse_domain_capabilities(domain_capabilities, &mut capabilities)?;
+        }
+        Ok(Some(capabilities))
+    }
+
+    pub fn supports_machine_type(&self, machine_type: &str) -> bool {
+        self.machine_types.is_empty() || self.machine_types.contains(machine_type)
+    }
+
+    /// Check that a VM with the given CPU definition can be started on this host. Returns the
+    /// reason if not.
+    pub fn check_cpu(&self, cpu: &CpuDefinition) -> Result<(), String> {
+        if cpu.mode != "custom" {
+            // host-model and host-passthrough adapt to whatever the host has
+            return Ok(());
+        }
+
+        match (&cpu.vendor, &self.cpu_vendor) {
+            (Some(wanted), Some(vendor)) if wanted != vendor => {
+                return Err(format!("CPU vendor {vendor} does not match {wanted}"));
+            }
+            _ => {}
+        }
+
+        let unusable_model = cpu.model.as_ref().filter(|model| {
+            !self.usable_cpu_models.is_empty() && !self.usable_cpu_models.contains(*model)
+        });
+        if let Some(model) = unusable_model {
+            return Err(format!("CPU model {model} is not usable"));
+        }
+
+        let missing: Vec<&String> = cpu
+            .required_features
+            .intersection(&self.missing_cpu_features)
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!("CPU features {missing:?} are not available"));
+        }
+
+        Ok(())
+    }
+
+    /// Check that a VM with the given CPU definition can be live migrated from the source host
+    /// to this host. Returns the reason if not.
+    pub fn check_migration_from(
+        &self,
+        source: &HostCapabilities,
+        cpu: &CpuDefinition,
+    ) -> Result<(), String> {
+        self.check_cpu(cpu)?;
+
+        // A passed through CPU exposes everything the source host had to the guest
+        if cpu.mode == "host-passthrough" {
+            if self.cpu_vendor != source.cpu_vendor || self.cpu_model != source.cpu_model {
+                return Err(format!(
+                    "host-passthrough CPU {:?} differs from source {:?}",
+                    self.cpu_model, source.cpu_model
+                ));
+            }
+            if !self.cpu_features.is_superset(&source.cpu_features) {
+                return Err(String::from(
+                    "host-passthrough CPU lacks features of the source host",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pick host CPUs for a VM with dedicated CPUs, leaving out the unavailable ones. With
+    /// single_cell all the CPUs come from the cell which fits the VM most tightly. Returns None if
+    /// the VM does not fit or the host has not reported its topology.
+    pub fn allocate_cpus(
+        &self,
+        unavailable: &BTreeSet<u32>,
+        count: usize,
+        single_cell: bool,
+    ) -> Option<CpuAllocation> {
+        if single_cell {
+            self.numa_cells
+                .iter()
+                .map(|cell| (cell.id, free_cpus(std::iter::once(cell), unavailable)))
+                .filter(|(_id, free)| free.len() >= count)
+                .min_by_key(|(_id, free)| free.len())
+                .map(|(id, free)| CpuAllocation {
+                    cpus: free[..count].to_vec(),
+                    numa_cell: Some(id),
+                })
+        } else {
+            let free = free_cpus(self.numa_cells.iter(), unavailable);
+            (!self.numa_cells.is_empty() && free.len() >= count).then(|| CpuAllocation {
+                cpus: free[..count].to_vec(),
+                numa_cell: None,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_allocate_cpus() {
+    let capabilities = HostCapabilities {
+        numa_cells: vec![
+            NumaCell {
+                id: 0,
+                cores: vec![vec![0, 4], vec![1, 5]],
+            },
+            NumaCell {
+                id: 1,
+                cores: vec![vec![2, 6], vec![3, 7]],
+            },
+        ],
+        ..Default::default()
+    };
+
+    // Whole cores are preferred, the partially used core of cell 0 is the last resort
+    let allocation = capabilities
+        .allocate_cpus(&BTreeSet::from([0]), 3, false)
+        .unwrap();
+    assert_eq!(allocation.cpus, vec![1, 5, 2]);
+
+    // Cell 0 is the tightest fit
+    let allocation = capabilities
+        .allocate_cpus(&BTreeSet::from([0]), 2, true)
+        .unwrap();
+    assert_eq!(allocation.cpus, vec![1, 5]);
+    assert_eq!(allocation.numa_cell, Some(0));
+
+    assert!(
+        capabilities
+            .allocate_cpus(&BTreeSet::new(), 5, true)
+            .is_none()
+    );
+    assert!(
+        HostCapabilities::default()
+            .allocate_cpus(&BTreeSet::new(), 1, false)
+            .is_none()
+    );
+}
@@ -0,0 +1,332 @@
+use roxmltree::{Document, Node};
+
+use crate::crd::cluster::Cluster;
+use crate::crd::virtualmachine::{DomainPatch, DomainPatchOperation, VirtualMachine};
+use crate::errors::Error;
+
+/// One step of a patch path, like `interface[@type='bridge'][2]`
+#[derive(Debug, PartialEq)]
+struct PathStep {
+    name: String,
+    attributes: Vec<(String, String)>,
+    index: Option<usize>,
+}
+
+fn invalid(message: String) -> Error {
+    Error::InvalidDomainOverrides(message)
+}
+
+/// Split a path on the slashes outside of predicates, which may contain paths as values
+fn split_path(path: &str) -> Result<Vec<&str>, Error> {
+    let Some(path) = path.strip_prefix('/') else {
+        return Err(invalid(format!("path {path} is not absolute")));
+    };
+    let mut steps = vec![];
+    let mut start = 0;
+    let mut quote: Option<char> = None;
+    let mut depth = 0;
+    for (position, c) in path.char_indices() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            (None, '/') if depth == 0 => {
+                steps.push(&path[start..position]);
+                start = position + 1;
+            }
+            _ => {}
+        }
+    }
+    steps.push(&path[start..]);
+    Ok(steps)
+}
+
+/// Position of the bracket closing a predicate, skipping brackets in quoted values
+fn closing_bracket(predicate: &str) -> Option<usize> {
+    let mut quote: Option<char> = None;
+    for (position, c) in predicate.char_indices() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, ']') => return Some(position),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_step(step: &str) -> Result<PathStep, Error> {
+    let error = || invalid(format!("can not parse path step {step}"));
+    let (name, mut predicates) = match step.find('[') {
+        Some(position) => (&step[..position], &step[position..]),
+        None => (step, ""),
+    };
+    if name.is_empty() {
+        return Err(error());
+    }
+
+    let mut parsed = PathStep {
+        name: name.to_string(),
+        attributes: vec![],
+        index: None,
+    };
+    while let Some(rest) = predicates.strip_prefix('[') {
+        let end = closing_bracket(rest).ok_or_else(error)?;
+        let predicate = rest[..end].trim();
+        match predicate.strip_prefix('@') {
+            Some(attribute) => {
+                let (name, value) = attribute.split_once('=').ok_or_else(error)?;
+                let value = value.trim();
+                let value = ['\'', '"']
+                    .iter()
+                    .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+                    .ok_or_else(error)?;
+                parsed
+                    .attributes
+                    .push((name.trim().to_string(), value.to_string()));
+            }
+            None => match predicate.parse::<usize>() {
+                Ok(index) if index > 0 => parsed.index = Some(index),
+                _ => return Err(error()),
+            },
+        }
+        predicates = &rest[end + 1..];
+    }
+    if !predicates.is_empty() {
+        return Err(error());
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_path() {
+    let steps: Vec<PathStep> = split_path("/domain/devices/disk[@type='file'][@device=\"cdrom\"]")
+        .unwrap()
+        .into_iter()
+        .map(|step| parse_step(step).unwrap())
+        .collect();
+    assert_eq!(steps.len(), 3);
+    assert_eq!(steps[1].name, "devices");
+    assert_eq!(
+        steps[2].attributes,
+        vec![
+            (String::from("type"), String::from("file")),
+            (String::from("device"), String::from("cdrom"))
+        ]
+    );
+
+    let steps = split_path("/domain/devices/disk/source[@file='/var/lib/a.iso'][1]").unwrap();
+    assert_eq!(steps.len(), 4);
+    let step = parse_step(steps[3]).unwrap();
+    assert_eq!(
+        step.attributes,
+        vec![(String::from("file"), String::from("/var/lib/a.iso"))]
+    );
+    assert_eq!(step.index, Some(1));
+
+    assert!(split_path("domain/devices").is_err());
+    assert!(parse_step("disk[0]").is_err());
+    assert!(parse_step("disk[@type=file]").is_err());
+    assert!(parse_step("disk[1").is_err());
+}
+
+fn matches(node: &Node, step: &PathStep) -> bool {
+    let name = step.name.rsplit(':').next().unwrap_or_default();
+    node.is_element()
+        && node.tag_name().name() == name
+        && step
+            .attributes
+            .iter()
+            .all(|(attribute, value)| node.attribute(attribute.as_str()) == Some(value.as_str()))
+}
+
+/// Find the single element the path points to
+fn select<'a, 'input>(
+    document: &'a Document<'input>,
+    path: &str,
+) -> Result<Node<'a, 'input>, Error> {
+    let steps = split_path(path)?
+        .into_iter()
+        .map(parse_step)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut candidates = vec![document.root()];
+    for step in &steps {
+        let found: Vec<Node> = candidates
+            .iter()
+            .flat_map(|node| node.children())
+            .filter(|node| matches(node, step))
+            .collect();
+        candidates = match step.index {
+            Some(index) => found.into_iter().skip(index - 1).take(1).collect(),
+            None => found,
+        };
+    }
+    match candidates.as_slice() {
+        [node] => Ok(*node),
+        [] => Err(invalid(format!("path {path} matches no element"))),
+        _ => Err(invalid(format!(
+            "path {path} matches more than one element"
+        ))),
+    }
+}
+
+/// Check that the fragment is well-formed XML, possibly with several top level elements
+fn checked_fragment(patch: &DomainPatch) -> Result<&str, Error> {
+    let fragment = patch
+        .xml
+        .as_deref()
+        .ok_or_else(|| invalid(format!("no XML given to patch {}", patch.path)))?;
+    Document::parse(&format!("<fragment>{fragment}</fragment>"))
+        .map_err(|error| invalid(format!("XML of patch {} is invalid: {error}", patch.path)))?;
+    Ok(fragment)
+}
+
+/// Apply a single patch to the domain XML. The XML is spliced as text so that everything
+/// outside the patched element stays as rendered.
+fn apply_patch(xml: &str, patch: &DomainPatch) -> Result<String, Error> {
+    let document = Document::parse(xml)?;
+    let node = select(&document, &patch.path)?;
+    let range = node.range();
+    let element = &xml[range.clone()];
+
+    let patched = match patch.operation {
+        DomainPatchOperation::Add => {
+            let fragment = checked_fragment(patch)?;
+            match element.strip_suffix("/>") {
+                // <devices/> becomes <devices>...</devices>
+                Some(open_tag) => {
+                    let tag_end = element[1..]
+                        .find(|c: char| c.is_whitespace() || c == '/' || c == '>')
+                        .map(|position| position + 1)
+                        .unwrap_or(element.len());
+                    let tag = &element[1..tag_end];
+                    format!(
+                        "{}{}>{fragment}</{tag}>{}",
+                        &xml[..range.start],
+                        open_tag.trim_end(),
+                        &xml[range.end..]
+                    )
+                }
+                None => {
+                    let closing = range.start + element.rfind("</").unwrap_or(element.len());
+                    format!("{}{fragment}{}", &xml[..closing], &xml[closing..])
+                }
+            }
+        }
+        DomainPatchOperation::Replace => {
+            let fragment = checked_fragment(patch)?;
+            format!("{}{fragment}{}", &xml[..range.start], &xml[range.end..])
+        }
+        DomainPatchOperation::Remove => {
+            if node.parent() == Some(document.root()) {
+                return Err(invalid(String::from(
+                    "the domain element can not be removed",
+                )));
+            }
+            format!("{}{}", &xml[..range.start], &xml[range.end..])
+        }
+    };
+    Document::parse(&patched).map_err(|error| {
+        invalid(format!(
+            "patch {} results in invalid XML: {error}",
+            patch.path
+        ))
+    })?;
+    Ok(patched)
+}
+
+/// Patches to the domain XML of the VM, the ones of the Cluster first
+pub fn domain_patches(vm: &VirtualMachine, cluster: &Cluster) -> Vec<DomainPatch> {
+    let cluster_patches = cluster.spec.domain_overrides.iter().flatten();
+    let vm_patches = vm.spec.domain_overrides.iter().flatten();
+    cluster_patches.chain(vm_patches).cloned().collect()
+}
+
+/// Apply the patches to the domain XML in order
+pub fn apply_domain_overrides(xml: String, patches: &[DomainPatch]) -> Result<String, Error> {
+    patches
+        .iter()
+        .try_fold(xml, |xml, patch| apply_patch(&xml, patch))
+}
+
+#[cfg(test)]
+#[test]
+fn test_apply_domain_overrides() {
+    let xml = String::from(
+        "<domain type='kvm'><name>vm</name><devices>\
+         <video><model type='vga' vram='16384'/></video>\
+         <interface type='bridge'><mac address='52:54:00:00:00:01'/></interface>\
+         <interface type='bridge'><mac address='52:54:00:00:00:02'/></interface>\
+         </devices><features/></domain>",
+    );
+    let patch = |path: &str, operation, xml: Option<&str>| DomainPatch {
+        path: path.to_string(),
+        operation,
+        xml: xml.map(String::from),
+    };
+
+    let patched = apply_domain_overrides(
+        xml.clone(),
+        &[
+            patch(
+                "/domain/devices",
+                DomainPatchOperation::Add,
+                Some("<watchdog model='i6300esb' action='reset'/>"),
+            ),
+            patch(
+                "/domain/devices/video/model",
+                DomainPatchOperation::Replace,
+                Some("<model type='virtio' heads='1'/>"),
+            ),
+            patch(
+                "/domain/devices/interface[2]",
+                DomainPatchOperation::Remove,
+                None,
+            ),
+            patch(
+                "/domain/features",
+                DomainPatchOperation::Add,
+                Some("<acpi/>"),
+            ),
+        ],
+    )
+    .unwrap();
+    assert_eq!(
+        patched,
+        "<domain type='kvm'><name>vm</name><devices>\
+         <video><model type='virtio' heads='1'/></video>\
+         <interface type='bridge'><mac address='52:54:00:00:00:01'/></interface>\
+         <watchdog model='i6300esb' action='reset'/></devices><features><acpi/></features></domain>"
+    );
+
+    // Ambiguous, missing and malformed patches are rejected
+    for invalid_patch in [
+        patch(
+            "/domain/devices/interface",
+            DomainPatchOperation::Remove,
+            None,
+        ),
+        patch(
+            "/domain/devices/watchdog",
+            DomainPatchOperation::Remove,
+            None,
+        ),
+        patch("/domain", DomainPatchOperation::Remove, None),
+        patch(
+            "/domain/devices",
+            DomainPatchOperation::Add,
+            Some("<watchdog>"),
+        ),
+        patch("/domain/devices", DomainPatchOperation::Add, None),
+    ] {
+        assert!(matches!(
+            apply_domain_overrides(xml.clone(), &[invalid_patch]),
+            Err(Error::InvalidDomainOverrides(_))
+        ));
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::BTreeSet;
+
+use roxmltree::{Document, Node};
+
+use crate::crd::libvirtnode::PciDevice;
+use crate::crd::virtualmachine::HostDeviceRequest;
+use crate::errors::Error;
+
+/// Host driver of the devices which may be passed through to VMs
+pub const PASSTHROUGH_DRIVER: &str = "vfio-pci";
+
+fn child<'a, 'input>(node: Node<'a, 'input>, tag: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| child.has_tag_name(tag))
+}
+
+fn child_text(node: Node, tag: &str) -> Option<String> {
+    child(node, tag)
+        .and_then(|child| child.text())
+        .map(|text| text.trim().to_string())
+}
+
+/// Normalize a hex ID like 0x8086 to 8086
+fn hex_id(id: &str) -> String {
+    id.trim_start_matches("0x").to_lowercase()
+}
+
+/// Parse a number given either in decimal or as 0x prefixed hex, as in node device XML
+fn parse_number(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn pci_address(node: Node) -> Option<String> {
+    let field = |name: &str| -> Option<u32> {
+        node.attribute(name)
+            .map(String::from)
+            .or_else(|| child_text(node, name))
+            .and_then(|value| parse_number(&value))
+    };
+    Some(format!(
+        "{:04x}:{:02x}:{:02x}.{:x}",
+        field("domain")?,
+        field("bus")?,
+        field("slot")?,
+        field("function")?
+    ))
+}
+
+/// Parse the XML description of a libvirt node device. Returns None for devices other than PCI
+/// devices.
+pub fn parse_pci_device(xml: &str) -> Result<Option<PciDevice>, Error> {
+    let document = Document::parse(xml)?;
+    let device = document.root_element();
+
+    let Some(capability) = device
+        .children()
+        .find(|node| node.has_tag_name("capability") && node.attribute("type") == Some("pci"))
+    else {
+        return Ok(None);
+    };
+    let Some(address) = pci_address(capability) else {
+        return Ok(None);
+    };
+    let vendor = child(capability, "vendor");
+    let product = child(capability, "product");
+
+    Ok(Some(PciDevice {
+        address,
+        vendor_id: vendor
+            .and_then(|vendor| vendor.attribute("id"))
+            .map(hex_id)
+            .unwrap_or_default(),
+        product_id: product
+            .and_then(|product| product.attribute("id"))
+            .map(hex_id)
+            .unwrap_or_default(),
+        vendor_name: vendor.and_then(|vendor| vendor.text()).map(String::from),
+        product_name: product.and_then(|product| product.text()).map(String::from),
+        driver: child(device, "driver").and_then(|driver| child_text(driver, "name")),
+        numa_node: child(capability, "numa")
+            .and_then(|numa| numa.attribute("node"))
+            .and_then(parse_number),
+        physical_function: capability
+            .children()
+            .find(|node| {
+                node.has_tag_name("capability") && node.attribute("type") == Some("phys_function")
+            })
+            .and_then(|function| child(function, "address"))
+            .and_then(pci_address),
+    }))
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_pci_device() {
+    let xml = r#"
+        <device>
+          <name>pci_0000_3b_01_1</name>
+          <parent>pci_0000_3a_00_0</parent>
+          <driver>
+            <name>vfio-pci</name>
+          </driver>
+          <capability type='pci'>
+            <class>0x020000</class>
+            <domain>0</domain>
+            <bus>59</bus>
+            <slot>1</slot>
+            <function>1</function>
+            <product id='0x1889'>Ethernet Adaptive Virtual Function</product>
+            <vendor id='0x8086'>Intel Corporation</vendor>
+            <capability type='phys_function'>
+              <address domain='0x0000' bus='0x3b' slot='0x00' function='0x0'/>
+            </capability>
+            <iommuGroup number='102'/>
+            <numa node='0'/>
+          </capability>
+        </device>"#;
+    let device = parse_pci_device(xml).unwrap().unwrap();
+    assert_eq!(device.address, "0000:3b:01.1");
+    assert_eq!(device.vendor_id, "8086");
+    assert_eq!(device.product_id, "1889");
+    assert_eq!(device.driver.as_deref(), Some(PASSTHROUGH_DRIVER));
+    assert_eq!(device.numa_node, Some(0));
+    assert_eq!(device.physical_function.as_deref(), Some("0000:3b:00.0"));
+
+    let xml = r#"
+        <device>
+          <name>computer</name>
+          <capability type='system'/>
+        </device>"#;
+    assert_eq!(parse_pci_device(xml).unwrap(), None);
+}
+
+/// Whether the device can be passed through to a VM asking for the given IDs
+fn matches(device: &PciDevice, request: &HostDeviceRequest) -> bool {
+    device.driver.as_deref() == Some(PASSTHROUGH_DRIVER)
+        && device.vendor_id == hex_id(&request.vendor_id)
+        && device.product_id == hex_id(&request.product_id)
+}
+
+/// Pick devices for all the requests of a VM, leaving out the unavailable ones. Returns the
+/// addresses of the picked devices, or None if the requests can not be satisfied.
+pub fn pick_pci_devices(
+    devices: &[PciDevice],
+    unavailable: &BTreeSet<String>,
+    requests: &[HostDeviceRequest],
+) -> Option<Vec<String>> {
+    let mut picked: Vec<String> = vec![];
+    for request in requests {
+        let count = request.count.unwrap_or(1);
+        let free: Vec<String> = devices
+            .iter()
+            .filter(|device| matches(device, request))
+            .filter(|device| !unavailable.contains(&device.address))
+            .filter(|device| !picked.contains(&device.address))
+            .take(count)
+            .map(|device| device.address.clone())
+            .collect();
+        if free.len() < count {
+            return None;
+        }
+        picked.extend(free);
+    }
+    Some(picked)
+}
+
+#[cfg(test)]
+#[test]
+fn test_pick_pci_devices() {
+    let device = |address: &str, driver: &str| PciDevice {
+        address: address.to_string(),
+        vendor_id: String::from("8086"),
+        product_id: String::from("1889"),
+        driver: Some(driver.to_string()),
+        ..Default::default()
+    };
+    let devices = vec![
+        device("0000:3b:01.0", "iavf"),
+        device("0000:3b:01.1", PASSTHROUGH_DRIVER),
+        device("0000:3b:01.2", PASSTHROUGH_DRIVER),
+    ];
+    let request = |count| HostDeviceRequest {
+        vendor_id: String::from("0x8086"),
+        product_id: String::from("1889"),
+        count: Some(count),
+    };
+    let unavailable = BTreeSet::from([String::from("0000:3b:01.1")]);
+
+    assert_eq!(
+        pick_pci_devices(&devices, &unavailable, &[request(1)]),
+        Some(vec![String::from("0000:3b:01.2")])
+    );
+    assert_eq!(
+        pick_pci_devices(&devices, &unavailable, &[request(2)]),
+        None
+    );
+    assert_eq!(
+        pick_pci_devices(&devices, &BTreeSet::new(), &[request(1), request(1)]),
+        Some(vec![
+            String::from("0000:3b:01.1"),
+            String::from("0000:3b:01.2")
+        ])
+    );
+}
@@ -0,0 +1,163 @@
+use kube::runtime::controller::Action;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::errors::Error;
+use crate::utils::supervisor::SupervisorHealth;
+
+/// Upper bounds of the reconcile duration histogram buckets, in seconds
+const DURATION_BUCKETS: [f64; 8] = [0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0];
+
+#[derive(Debug, Default, Clone)]
+struct ReconcileMetrics {
+    reconciles: u64,
+    errors: u64,
+    duration_sum: f64,
+    /// Cumulative counts of reconciles at most as long as the matching DURATION_BUCKETS bound
+    duration_buckets: [u64; DURATION_BUCKETS.len()],
+}
+
+static RECONCILES: Mutex<BTreeMap<String, ReconcileMetrics>> = Mutex::new(BTreeMap::new());
+static GAUGES: Mutex<BTreeMap<String, f64>> = Mutex::new(BTreeMap::new());
+
+/// Count a reconcile of a resource of the kind
+pub fn record_reconcile(kind: &str, seconds: f64, success: bool) {
+    let mut reconciles = RECONCILES.lock().expect("metrics lock");
+    let metrics = reconciles.entry(kind.to_string()).or_default();
+    metrics.reconciles += 1;
+    if !success {
+        metrics.errors += 1;
+    }
+    metrics.duration_sum += seconds;
+    for (bucket, bound) in metrics.duration_buckets.iter_mut().zip(DURATION_BUCKETS) {
+        if seconds <= bound {
+            *bucket += 1;
+        }
+    }
+}
+
+/// Run a reconcile, recording its duration and result
+pub async fn measure_reconcile<F>(kind: &str, reconcile: F) -> Result<Action, Error>
+where
+    F: Future<Output = Result<Action, Error>>,
+{
+    let start = Instant::now();
+    let result = reconcile.await;
+    record_reconcile(kind, start.elapsed().as_secs_f64(), result.is_ok());
+    result
+}
+
+/// Set a gauge without labels, e.g. whether this replica is the leader
+pub fn set_gauge(name: &str, value: f64) {
+    GAUGES
+        .lock()
+        .expect("metrics lock")
+        .insert(name.to_string(), value);
+}
+
+/// All the metrics in the Prometheus text format
+pub fn render(tasks: Option<&SupervisorHealth>) -> String {
+    let reconciles = RECONCILES.lock().expect("metrics lock").clone();
+    let gauges = GAUGES.lock().expect("metrics lock").clone();
+    let mut out = String::new();
+
+    // Writing to a String does not fail
+    let _ = writeln!(out, "# TYPE virt_controller_reconciles_total counter");
+    for (kind, metrics) in &reconciles {
+        let _ = writeln!(
+            out,
+            "virt_controller_reconciles_total{{kind=\"{kind}\"}} {}",
+            metrics.reconciles
+        );
+    }
+    let _ = writeln!(out, "# TYPE virt_controller_reconcile_errors_total counter");
+    for (kind, metrics) in &reconciles {
+        let _ = writeln!(
+            out,
+            "virt_controller_reconcile_errors_total{{kind=\"{kind}\"}} {}",
+            metrics.errors
+        );
+    }
+    let _ = writeln!(
+        out,
+        "# TYPE virt_controller_reconcile_duration_seconds histogram"
+    );
+    for (kind, metrics) in &reconciles {
+        for (count, bound) in metrics.duration_buckets.iter().zip(DURATION_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "virt_controller_reconcile_duration_seconds_bucket{{kind=\"{kind}\",le=\"{bound}\"}} {count}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "virt_controller_reconcile_duration_seconds_bucket{{kind=\"{kind}\",le=\"+Inf\"}} {}",
+            metrics.reconciles
+        );
+        let _ = writeln!(
+            out,
+            "virt_controller_reconcile_duration_seconds_sum{{kind=\"{kind}\"}} {}",
+            metrics.duration_sum
+        );
+        let _ = writeln!(
+            out,
+            "virt_controller_reconcile_duration_seconds_count{{kind=\"{kind}\"}} {}",
+            metrics.reconciles
+        );
+    }
+
+    if let Some(tasks) = tasks {
+        let tasks = tasks.tasks();
+        let _ = writeln!(out, "# TYPE virt_controller_task_running gauge");
+        for (task, health) in &tasks {
+            let _ = writeln!(
+                out,
+                "virt_controller_task_running{{task=\"{task}\"}} {}",
+                u8::from(health.running)
+            );
+        }
+        let _ = writeln!(out, "# TYPE virt_controller_task_restarts_total counter");
+        for (task, health) in &tasks {
+            let _ = writeln!(
+                out,
+                "virt_controller_task_restarts_total{{task=\"{task}\"}} {}",
+                health.restarts
+            );
+        }
+    }
+
+    for (name, value) in &gauges {
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "{name} {value}");
+    }
+    out
+}
+
+#[cfg(test)]
+#[test]
+fn test_render() {
+    record_reconcile("TestKind", 0.2, true);
+    record_reconcile("TestKind", 2.0, false);
+    set_gauge("virt_controller_test", 1.0);
+
+    let rendered = render(None);
+    let lines: Vec<&str> = rendered.lines().collect();
+    for expected in [
+        "virt_controller_reconciles_total{kind=\"TestKind\"} 2",
+        "virt_controller_reconcile_errors_total{kind=\"TestKind\"} 1",
+        "virt_controller_reconcile_duration_seconds_bucket{kind=\"TestKind\",le=\"0.1\"} 0",
+        "virt_controller_reconcile_duration_seconds_bucket{kind=\"TestKind\",le=\"0.5\"} 1",
+        "virt_controller_reconcile_duration_seconds_bucket{kind=\"TestKind\",le=\"5\"} 2",
+        "virt_controller_reconcile_duration_seconds_bucket{kind=\"TestKind\",le=\"+Inf\"} 2",
+        "virt_controller_reconcile_duration_seconds_sum{kind=\"TestKind\"} 2.2",
+        "virt_controller_test 1",
+    ] {
+        assert!(
+            lines.contains(&expected),
+            "{expected} missing from:\n{rendered}"
+        );
+    }
+}
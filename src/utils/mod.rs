@@ -8,10 +8,18 @@ use kube::core::WatchEvent;
 use kube::{Api, Client};
 use tracing::{debug, info, instrument};
 
+pub mod backoff;
+pub mod events;
+pub mod health;
+pub mod metrics;
 pub mod resource_controller;
 pub mod strings;
+pub mod supervisor;
 #[macro_use]
 pub mod shortcuts;
+pub mod libvirt_capabilities;
+pub mod libvirt_domain_overrides;
+pub mod libvirt_nodedev;
 pub mod libvirt_storage;
 pub mod traits;
 
@@ -6,18 +6,23 @@ use std::fmt::Debug;
 use std::future::Future;
 use std::hash::Hash;
 use std::sync::Arc;
-use tokio::time::Duration;
 use tracing::{error, info_span, Instrument};
 
 use crate::errors::Error;
+use crate::utils::backoff::ErrorBackoff;
+use crate::utils::events::EventRecorder;
+use crate::utils::metrics::measure_reconcile;
 
 type StoredErrorPolicyFn<ResourceType, State> =
     Box<dyn Fn(Arc<ResourceType>, &Error, Arc<State>) -> Action + Send + Sync>;
 type StoredReconcileFn<ResourceType, State, Fut> =
     Box<dyn Fn(Arc<ResourceType>, Arc<State>) -> Fut + Send + Sync>;
+type StoredWatchFn<ResourceType> =
+    Box<dyn FnOnce(Controller<ResourceType>) -> Controller<ResourceType> + Send>;
 
 pub struct DefaultState {
     pub client: Client,
+    pub recorder: EventRecorder,
 }
 
 pub struct ResourceControllerBuilder {
@@ -31,13 +36,16 @@ pub struct ResourceControllerBuilderWithStateAndErrorPolicy<ResourceType, State>
     client: Client,
     state: Arc<State>,
     error_policy: StoredErrorPolicyFn<ResourceType, State>,
+    backoff: ErrorBackoff,
 }
 pub struct ResourceController<ResourceType, State, UpdateFut, RemoveFut> {
     client: Client,
     state: Arc<State>,
     error_policy: StoredErrorPolicyFn<ResourceType, State>,
+    backoff: ErrorBackoff,
     update_fn: StoredReconcileFn<ResourceType, State, UpdateFut>,
     remove_fn: StoredReconcileFn<ResourceType, State, RemoveFut>,
+    owned: Vec<StoredWatchFn<ResourceType>>,
 }
 
 impl ResourceControllerBuilder {
@@ -47,26 +55,44 @@ impl ResourceControllerBuilder {
     pub fn with_default_state(self) -> ResourceControllerBuilderWithState<DefaultState> {
         let state = Arc::new(DefaultState {
             client: self.client.clone(),
+            recorder: EventRecorder::new(self.client.clone()),
         });
         ResourceControllerBuilderWithState {
             client: self.client,
             state,
         }
     }
+    pub fn with_state<State>(self, state: State) -> ResourceControllerBuilderWithState<State> {
+        ResourceControllerBuilderWithState {
+            client: self.client,
+            state: Arc::new(state),
+        }
+    }
 }
 
 impl<State> ResourceControllerBuilderWithState<State> {
-    pub fn with_default_error_policy<ResourceType>(
+    pub fn with_default_error_policy<ResourceType: kube::Resource>(
+        self,
+    ) -> ResourceControllerBuilderWithStateAndErrorPolicy<ResourceType, State> {
+        self.with_backoff_error_policy(ErrorBackoff::default())
+    }
+
+    /// Requeue failed objects with a per-object exponential backoff, which is reset once the
+    /// object reconciles successfully
+    pub fn with_backoff_error_policy<ResourceType: kube::Resource>(
         self,
+        backoff: ErrorBackoff,
     ) -> ResourceControllerBuilderWithStateAndErrorPolicy<ResourceType, State> {
-        let error_policy_fn = |_object: Arc<ResourceType>, _error: &Error, _ctx: Arc<State>| {
-            Action::requeue(Duration::from_secs(15))
+        let policy = backoff.clone();
+        let error_policy_fn = move |object: Arc<ResourceType>, error: &Error, _ctx: Arc<State>| {
+            policy.on_error(object.as_ref(), error)
         };
 
         ResourceControllerBuilderWithStateAndErrorPolicy {
             client: self.client,
             state: self.state,
             error_policy: Box::new(error_policy_fn),
+            backoff,
         }
     }
 }
@@ -85,8 +111,10 @@ impl<ResourceType, State> ResourceControllerBuilderWithStateAndErrorPolicy<Resou
             client: self.client,
             state: self.state,
             error_policy: self.error_policy,
+            backoff: self.backoff,
             update_fn: Box::new(update_fn),
             remove_fn: Box::new(remove_fn),
+            owned: vec![],
         }
     }
 }
@@ -107,28 +135,50 @@ where
     RemoveFut: Future<Output = Result<Action, Error>> + Send + 'static,
     State: Send + Sync + 'static,
 {
+    /// Also reconcile the resource when the child resources it owns change
+    pub fn owns<Child>(mut self) -> Self
+    where
+        Child: kube::Resource<DynamicType = ()> + Clone + Debug + DeserializeOwned + Send + 'static,
+    {
+        let api: Api<Child> = Api::all(self.client.clone());
+        self.owned
+            .push(Box::new(move |controller: Controller<ResourceType>| {
+                controller.owns(api, kube::runtime::watcher::Config::default())
+            }));
+        self
+    }
+
     pub async fn run(self) {
         let api: Api<ResourceType> = Api::all(self.client.clone());
         let remove_fn = Arc::new(self.remove_fn);
         let update_fn = Arc::new(self.update_fn);
+        let recorder = EventRecorder::new(self.client.clone());
+        let backoff = self.backoff;
 
-        Controller::new(api, kube::runtime::watcher::Config::default())
+        let controller = Controller::new(api, kube::runtime::watcher::Config::default());
+        self.owned
+            .into_iter()
+            .fold(controller, |controller, owns| owns(controller))
             .run(
                 move |object: Arc<ResourceType>, state: Arc<State>| {
                     let remove_fn = remove_fn.clone();
                     let update_fn = update_fn.clone();
+                    let recorder = recorder.clone();
+                    let backoff = backoff.clone();
+                    let reconciled = object.clone();
 
+                    let kind =
+                        ResourceType::kind(&ResourceType::DynamicType::default()).to_string();
                     let span = info_span!(
                         parent: None,
                         "reconcile resource",
-                        "kind" =
-                            ResourceType::kind(&ResourceType::DynamicType::default()).to_string(),
+                        "kind" = kind.clone(),
                         "ns" = object.meta().namespace.clone(),
                         "name" = object.meta().name.clone(),
                         "entity.name" = object.meta().name.clone(),
                     );
 
-                    async move {
+                    let reconcile = async move {
                         if object.meta().deletion_timestamp.is_some() {
                             remove_fn(object, state)
                                 .instrument(info_span!("remove_fn"))
@@ -138,6 +188,19 @@ where
                                 .instrument(info_span!("update_fn"))
                                 .await
                         }
+                    };
+                    async move {
+                        let result = measure_reconcile(&kind, reconcile).await;
+                        match &result {
+                            Ok(_) => {
+                                backoff.reset(reconciled.as_ref());
+                                recorder.reconcile_succeeded(reconciled.as_ref());
+                            }
+                            Err(error) => {
+                                recorder.reconcile_failed(reconciled.as_ref(), error).await
+                            }
+                        }
+                        result
                     }
                     .instrument(span)
                 },
@@ -1,3 +1,5 @@
+use crate::errors::Error;
+
 pub fn get_version_string() -> String {
     format!("{}-{}", env!("GIT_COUNT"), env!("GIT_HASH"))
 }
@@ -5,3 +7,105 @@ pub fn get_version_string() -> String {
 pub fn field_manager(controller: &str) -> String {
     format!("cluster-controller.{controller}")
 }
+
+/// Parse a memory size with a libvirt style unit (e.g. "512 MiB", "4G", "1 Gi", "2GB") to bytes.
+/// Like libvirt, bare and "i"/"iB" suffixed units are powers of 1024 and "B" suffixed powers of
+/// 1000, without a unit the size is in bytes. This is the one parser of VM memory sizes, so that
+/// the webhook, the scheduler and the hosts accept the same ones.
+pub fn parse_memory_bytes(input: &str) -> Result<u64, Error> {
+    let invalid = || Error::InvalidMemory(input.to_string());
+    let trimmed = input.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(trimmed.len());
+    let amount: u64 = trimmed[..split].parse().map_err(|_| invalid())?;
+    let unit = trimmed[split..].trim().to_lowercase();
+
+    if unit.is_empty() || unit == "b" || unit == "bytes" {
+        return Ok(amount);
+    }
+
+    let (prefix, suffix) = unit.split_at(1);
+    let exponent = "kmgtpe".find(prefix).ok_or_else(invalid)? as u32 + 1;
+    let base: u64 = match suffix {
+        "" | "i" | "ib" => 1024,
+        "b" => 1000,
+        _ => return Err(invalid()),
+    };
+    base.checked_pow(exponent)
+        .and_then(|multiplier| amount.checked_mul(multiplier))
+        .ok_or_else(invalid)
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_memory_bytes() {
+    let cases = [
+        ("1024", Some(1024)),
+        ("1024 b", Some(1024)),
+        ("1024 bytes", Some(1024)),
+        ("4k", Some(4 << 10)),
+        ("4 KiB", Some(4 << 10)),
+        ("4kb", Some(4_000)),
+        ("512M", Some(512 << 20)),
+        ("512 MiB", Some(512 << 20)),
+        ("512Mi", Some(512 << 20)),
+        ("512 MB", Some(512_000_000)),
+        ("4G", Some(4 << 30)),
+        ("1 Gi", Some(1 << 30)),
+        ("2GB", Some(2_000_000_000)),
+        ("2 TiB", Some(2 << 40)),
+        ("65746892Ki", Some(65746892 << 10)),
+        ("", None),
+        ("GiB", None),
+        ("1.5Gi", None),
+        ("1 parsec", None),
+        ("16 EiB", None),
+    ];
+    for (input, expected) in cases {
+        assert_eq!(parse_memory_bytes(input).ok(), expected, "{input}");
+    }
+}
+
+/// Parse a Kubernetes resource quantity of bytes (e.g. "65746892Ki", "16Gi", "500M"), as the
+/// kubelet reports the allocatable memory of nodes. Unlike VM memory sizes, "Ki"-"Ei" are
+/// powers of 1024 while "k"-"E" are powers of 1000.
+pub fn parse_quantity_bytes(quantity: &str) -> Option<u64> {
+    let quantity = quantity.trim();
+    let split = quantity
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(quantity.len());
+    let amount: u64 = quantity[..split].parse().ok()?;
+
+    let multiplier: u64 = match &quantity[split..] {
+        "" => 1,
+        "Ki" => 1 << 10,
+        "Mi" => 1 << 20,
+        "Gi" => 1 << 30,
+        "Ti" => 1 << 40,
+        "Pi" => 1 << 50,
+        "Ei" => 1 << 60,
+        "k" => 1_000,
+        "M" => 1_000_000,
+        "G" => 1_000_000_000,
+        "T" => 1_000_000_000_000,
+        "P" => 1_000_000_000_000_000,
+        "E" => 1_000_000_000_000_000_000,
+        // Decimal exponent, e.g. "129e6"
+        suffix => 10u64.checked_pow(suffix.strip_prefix('e')?.parse().ok()?)?,
+    };
+    amount.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_quantity_bytes() {
+    assert_eq!(parse_quantity_bytes("65746892Ki"), Some(65746892 * 1024));
+    assert_eq!(parse_quantity_bytes("16Gi"), Some(16 << 30));
+    assert_eq!(parse_quantity_bytes("500M"), Some(500_000_000));
+    assert_eq!(parse_quantity_bytes("64G"), Some(64_000_000_000));
+    assert_eq!(parse_quantity_bytes("129e6"), Some(129_000_000));
+    assert_eq!(parse_quantity_bytes("1024"), Some(1024));
+    assert_eq!(parse_quantity_bytes("1.5Gi"), None);
+    assert_eq!(parse_quantity_bytes("16 GiB"), None);
+}
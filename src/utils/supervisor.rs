@@ -0,0 +1,216 @@
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{error, info};
+
+use crate::errors::Error;
+
+/// Restarts allowed within RESTART_WINDOW before the supervisor gives up
+const DEFAULT_MAX_RESTARTS: usize = 5;
+const RESTART_WINDOW: Duration = Duration::from_secs(600);
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+type TaskFactory = Box<dyn Fn() -> BoxFuture<'static, Result<(), Error>> + Send + Sync>;
+
+struct Task {
+    name: String,
+    factory: TaskFactory,
+    max_restarts: usize,
+}
+
+/// State of one supervised task
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TaskHealth {
+    pub running: bool,
+    pub restarts: usize,
+    /// The supervisor gave up on the task or has exited, it is not restarted anymore
+    pub stopped: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Shared view of the health of the tasks of a supervisor
+#[derive(Debug, Clone, Default)]
+pub struct SupervisorHealth(Arc<Mutex<BTreeMap<String, TaskHealth>>>);
+
+impl SupervisorHealth {
+    /// Whether all the tasks are running, and none is waiting to be restarted
+    pub fn is_healthy(&self) -> bool {
+        self.tasks().values().all(|task| task.running)
+    }
+
+    /// Whether all the tasks are running or will be restarted
+    pub fn is_alive(&self) -> bool {
+        self.tasks().values().all(|task| !task.stopped)
+    }
+
+    pub fn tasks(&self) -> BTreeMap<String, TaskHealth> {
+        self.0.lock().expect("supervisor health lock").clone()
+    }
+
+    fn update(&self, name: &str, update: impl FnOnce(&mut TaskHealth)) {
+        let mut tasks = self.0.lock().expect("supervisor health lock");
+        update(tasks.entry(name.to_string()).or_default());
+    }
+
+    fn stop_all(&self) {
+        let mut tasks = self.0.lock().expect("supervisor health lock");
+        for task in tasks.values_mut() {
+            task.running = false;
+            task.stopped = true;
+        }
+    }
+}
+
+/// Runs long-lived tasks, restarting the ones which fail or panic with an exponential backoff.
+/// Gives up once a task fails more often than its restart budget allows.
+#[derive(Default)]
+pub struct Supervisor {
+    tasks: Vec<Task>,
+    health: SupervisorHealth,
+}
+
+/// Time to wait before restarting a task which failed the given number of times in a row
+fn backoff(failures: usize) -> Duration {
+    let exponent = failures.saturating_sub(1).min(16) as u32;
+    BACKOFF_BASE
+        .saturating_mul(2u32.pow(exponent))
+        .min(BACKOFF_MAX)
+}
+
+#[cfg(test)]
+#[test]
+fn test_backoff() {
+    assert_eq!(backoff(1), Duration::from_secs(1));
+    assert_eq!(backoff(3), Duration::from_secs(4));
+    assert_eq!(backoff(7), BACKOFF_MAX);
+    assert_eq!(backoff(100), BACKOFF_MAX);
+}
+
+impl Supervisor {
+    /// Report the health of the tasks to a shared handle, e.g. of the health endpoint
+    pub fn with_health(mut self, health: SupervisorHealth) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Add a task, started again from the factory whenever it ends
+    pub fn with_task<F, Fut>(mut self, name: &str, factory: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        self.tasks.push(Task {
+            name: name.to_string(),
+            factory: Box::new(move || factory().boxed()),
+            max_restarts: DEFAULT_MAX_RESTARTS,
+        });
+        self
+    }
+
+    /// Add a task which can't be restarted, the supervisor gives up as soon as it ends
+    pub fn with_task_once<Fut>(mut self, name: &str, future: Fut) -> Self
+    where
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        let future = Mutex::new(Some(future.boxed()));
+        let task_name = name.to_string();
+        self.tasks.push(Task {
+            name: name.to_string(),
+            factory: Box::new(move || {
+                let future = future.lock().expect("supervisor task lock").take();
+                let task_name = task_name.clone();
+                future.unwrap_or_else(|| {
+                    async move {
+                        Err(Error::UnexpectedExit(format!(
+                            "{task_name} can't be restarted"
+                        )))
+                    }
+                    .boxed()
+                })
+            }),
+            max_restarts: 0,
+        });
+        self
+    }
+
+    pub fn health(&self) -> SupervisorHealth {
+        self.health.clone()
+    }
+
+    /// Run the tasks until one of them runs out of restarts, returning why. The other tasks are
+    /// stopped before returning, and also when the future is dropped.
+    pub async fn run(self) -> Error {
+        let mut supervised = JoinSet::new();
+        for task in self.tasks {
+            supervised.spawn(supervise(task, self.health.clone()));
+        }
+        let error = match supervised.join_next().await {
+            Some(Ok(error)) => error,
+            Some(Err(join_error)) => {
+                Error::UnexpectedExit(format!("supervisor task failed: {join_error}"))
+            }
+            None => Error::UnexpectedExit(String::from("supervisor has no tasks")),
+        };
+        supervised.shutdown().await;
+        self.health.stop_all();
+        error
+    }
+}
+
+/// Aborts the spawned task when dropped, so that an aborted supervisor does not leave the task
+/// it supervises running
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+async fn supervise(task: Task, health: SupervisorHealth) -> Error {
+    let mut failures: VecDeque<Instant> = VecDeque::new();
+    loop {
+        health.update(&task.name, |state| state.running = true);
+        let mut child = AbortOnDrop(tokio::spawn((task.factory)()));
+        let reason = match (&mut child.0).await {
+            Ok(Ok(())) => String::from("exited"),
+            Ok(Err(error)) => format!("failed: {error}"),
+            Err(join_error) => format!("panicked: {join_error}"),
+        };
+        error!("supervisor: {} {reason}", task.name);
+
+        let now = Instant::now();
+        failures.push_back(now);
+        while failures
+            .front()
+            .is_some_and(|failure| now.duration_since(*failure) > RESTART_WINDOW)
+        {
+            failures.pop_front();
+        }
+        health.update(&task.name, |state| {
+            state.running = false;
+            state.last_error = Some(reason.clone());
+        });
+
+        if failures.len() > task.max_restarts {
+            health.update(&task.name, |state| state.stopped = true);
+            return Error::UnexpectedExit(format!(
+                "{} {reason}, restarted {} times within {}s",
+                task.name,
+                failures.len() - 1,
+                RESTART_WINDOW.as_secs()
+            ));
+        }
+        let delay = backoff(failures.len());
+        info!("supervisor: restarting {} in {delay:?}", task.name);
+        tokio::time::sleep(delay).await;
+        health.update(&task.name, |state| state.restarts += 1);
+    }
+}
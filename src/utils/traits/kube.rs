@@ -68,15 +68,17 @@ where
             Api::all(client)
         };
 
-        api.replace(
-            &self.name_unchecked(),
-            &PostParams {
-                dry_run: false,
-                field_manager: Some(String::from(field_manager)),
-            },
-            self,
-        )
-        .await?;
+        // Keep the local copy current, so that it can be committed again
+        *self = api
+            .replace(
+                &self.name_unchecked(),
+                &PostParams {
+                    dry_run: false,
+                    field_manager: Some(String::from(field_manager)),
+                },
+                self,
+            )
+            .await?;
         Ok(())
     }
 
@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::info;
+
+use crate::crd::versioning::convert_object;
+use crate::errors::Error;
+
+/// ConversionReview of apiextensions.k8s.io/v1, sent by the API server to convert objects of
+/// CRDs with several versions
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionReview {
+    pub api_version: String,
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request: Option<ConversionRequest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<ConversionResponse>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionRequest {
+    pub uid: String,
+    #[serde(rename = "desiredAPIVersion")]
+    pub desired_api_version: String,
+    pub objects: Vec<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionResponse {
+    pub uid: String,
+    pub converted_objects: Vec<Value>,
+    pub result: ConversionResult,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConversionResult {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// Convert all the objects of the review, failing the whole review if any of them fails
+pub fn review_conversion(review: ConversionReview) -> ConversionReview {
+    let (uid, result) = match review.request {
+        Some(request) => {
+            let converted: Result<Vec<Value>, Error> = request
+                .objects
+                .into_iter()
+                .map(|object| convert_object(object, &request.desired_api_version))
+                .collect();
+            (request.uid, converted)
+        }
+        None => (
+            String::new(),
+            Err(Error::CrdConversion(String::from(
+                "review without a request",
+            ))),
+        ),
+    };
+
+    let response = match result {
+        Ok(converted_objects) => ConversionResponse {
+            uid,
+            converted_objects,
+            result: ConversionResult {
+                status: String::from("Success"),
+                message: None,
+            },
+        },
+        Err(error) => {
+            info!("Conversion failed: {error}");
+            ConversionResponse {
+                uid,
+                converted_objects: vec![],
+                result: ConversionResult {
+                    status: String::from("Failure"),
+                    message: Some(error.to_string()),
+                },
+            }
+        }
+    };
+    ConversionReview {
+        api_version: review.api_version,
+        kind: review.kind,
+        request: None,
+        response: Some(response),
+    }
+}
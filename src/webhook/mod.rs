@@ -0,0 +1,154 @@
+use kube::Client;
+use kube::core::DynamicObject;
+use kube::core::admission::{AdmissionRequest, AdmissionResponse, AdmissionReview, Operation};
+use std::convert::Infallible;
+use std::path::Path;
+use tracing::{info, warn};
+use warp::Filter;
+
+use crate::errors::Error;
+
+mod conversion;
+mod mutation;
+mod validation;
+
+pub const WEBHOOK_PORT: u16 = 8443;
+/// The API server only calls webhooks over HTTPS, the certificate is mounted here from a TLS
+/// secret
+pub const WEBHOOK_TLS_PATH: &str = "/etc/webhook";
+
+/// Check the object of an admission request, denying it with all the problems found
+fn review_validation(request: &AdmissionRequest<DynamicObject>) -> AdmissionResponse {
+    let response = AdmissionResponse::from(request);
+    // Deletions carry no object to validate
+    let Some(object) = &request.object else {
+        return response;
+    };
+    if !validation::needs_validation(object, request.old_object.as_ref()) {
+        return response;
+    }
+    let name = format!(
+        "{} {}/{}",
+        request.kind.kind,
+        request.namespace.as_deref().unwrap_or_default(),
+        request.name
+    );
+    match validation::validate(&request.kind, object) {
+        Ok(problems) if problems.is_empty() => response,
+        Ok(problems) => {
+            info!("Denied {name}: {}", problems.join("; "));
+            response.deny(problems.join("; "))
+        }
+        Err(error) => {
+            info!("Denied {name}: {error}");
+            response.deny(error.to_string())
+        }
+    }
+}
+
+async fn handle_validate(
+    review: AdmissionReview<DynamicObject>,
+) -> Result<warp::reply::Json, Infallible> {
+    let response = match AdmissionRequest::try_from(review) {
+        Ok(request) => review_validation(&request),
+        Err(error) => {
+            warn!("Invalid admission review: {error}");
+            AdmissionResponse::invalid(error.to_string())
+        }
+    };
+    Ok(warp::reply::json(&response.into_review()))
+}
+
+/// Fill in the defaults of a VM being created or updated, denying it if they can't be found
+async fn review_mutation(
+    request: &AdmissionRequest<DynamicObject>,
+    client: Client,
+) -> AdmissionResponse {
+    let response = AdmissionResponse::from(request);
+    let Some(object) = &request.object else {
+        return response;
+    };
+    if request.kind.kind != "VirtualMachine"
+        || !matches!(request.operation, Operation::Create | Operation::Update)
+        || object.metadata.deletion_timestamp.is_some()
+    {
+        return response;
+    }
+    let namespace = request.namespace.as_deref().unwrap_or_default();
+    let patch = mutation::default_vm(
+        namespace,
+        &request.name,
+        object,
+        request.old_object.as_ref(),
+        client,
+    )
+    .await;
+    let result = match patch {
+        Ok(patch) if patch.0.is_empty() => return response,
+        Ok(patch) => response
+            .with_patch(patch)
+            .map_err(|error| error.to_string()),
+        Err(error) => Err(error.to_string()),
+    };
+    result.unwrap_or_else(|error| {
+        warn!(
+            "Could not fill in the defaults of VirtualMachine {namespace}/{}: {error}",
+            request.name
+        );
+        AdmissionResponse::from(request).deny(error)
+    })
+}
+
+async fn handle_mutate(
+    review: AdmissionReview<DynamicObject>,
+    client: Client,
+) -> Result<warp::reply::Json, Infallible> {
+    let response = match AdmissionRequest::try_from(review) {
+        Ok(request) => review_mutation(&request, client).await,
+        Err(error) => {
+            warn!("Invalid admission review: {error}");
+            AdmissionResponse::invalid(error.to_string())
+        }
+    };
+    Ok(warp::reply::json(&response.into_review()))
+}
+
+async fn handle_convert(
+    review: conversion::ConversionReview,
+) -> Result<warp::reply::Json, Infallible> {
+    Ok(warp::reply::json(&conversion::review_conversion(review)))
+}
+
+/// Serve the admission webhooks of the CRDs. Objects are validated at `/validate`, the defaults
+/// of VMs are filled in at `/mutate` and objects are converted between the versions of their CRD
+/// at `/convert`.
+pub async fn run(client: Client) -> Result<(), Error> {
+    let validate = warp::path("validate")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and_then(handle_validate);
+    let mutate = warp::path("mutate")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(warp::any().map(move || client.clone()))
+        .and_then(handle_mutate);
+    let convert = warp::path("convert")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and_then(handle_convert);
+    let routes = validate.or(mutate).or(convert).with(warp::log("webhook"));
+
+    info!("Serving admission webhooks on port {WEBHOOK_PORT}");
+    warp::serve(routes)
+        .tls()
+        .cert_path(Path::new(WEBHOOK_TLS_PATH).join("tls.crt"))
+        .key_path(Path::new(WEBHOOK_TLS_PATH).join("tls.key"))
+        .run(([0, 0, 0, 0], WEBHOOK_PORT))
+        .await;
+    Err(Error::UnexpectedExit(String::from(
+        "admission webhook HTTP API (warp) died",
+    )))
+}
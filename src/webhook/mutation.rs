@@ -0,0 +1,222 @@
+use kube::core::DynamicObject;
+use kube::{Api, Client};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::crd::network::{Network, NetworkType};
+use crate::crd::virtualmachine::{NetworkAttachment, VirtualMachine};
+use crate::errors::Error;
+
+const PREFIX: &str = "52:54:00";
+
+/// Takes a VM name and a network interface specification and generates
+/// a MAC address based on a hash of the information. Interfaces with neither a network nor a
+/// bridge get none, they are rejected by the validating webhook.
+fn generate_mac_address(vm_name: &str, nic: &NetworkAttachment, index: usize) -> Option<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(vm_name);
+    hasher.update(nic.name.as_ref().or(nic.bridge.as_ref())?);
+    hasher.update(vec![index as u8]);
+    let hash = hasher.finalize();
+    Some(format!(
+        "{}:{:02x}:{:02x}:{:02x}",
+        PREFIX, hash[29], hash[30], hash[31]
+    ))
+}
+
+fn new_uuid() -> String {
+    Uuid::new_v4()
+        .hyphenated()
+        .encode_lower(&mut Uuid::encode_buffer())
+        .into()
+}
+
+/// The values to add to the spec of a VM, as JSON pointers and values. Values the spec already
+/// has are kept, the ones missing are taken from the previous version of an updated VM, which
+/// for VMs created before the webhook may only have them in its status, or generated.
+fn vm_defaults(
+    vm: &VirtualMachine,
+    old: Option<&VirtualMachine>,
+    vm_name: &str,
+    ovn_nics: &[bool],
+) -> Vec<(String, String)> {
+    let mut defaults = vec![];
+
+    let uuid = match &vm.spec.uuid {
+        Some(uuid) => uuid.clone(),
+        None => {
+            let uuid = old
+                .and_then(|old| old.spec.uuid.clone())
+                .unwrap_or_else(new_uuid);
+            defaults.push((String::from("/spec/uuid"), uuid.clone()));
+            uuid
+        }
+    };
+    // VMs created with generateName have no name yet
+    let vm_name = if vm_name.is_empty() { &uuid } else { vm_name };
+
+    let old_nics: Vec<&NetworkAttachment> = old
+        .iter()
+        .flat_map(|old| {
+            let status_nics = old.status.iter().flat_map(|status| status.networks.iter());
+            old.spec.networks.iter().chain(status_nics)
+        })
+        .collect();
+
+    for (index, nic) in vm.spec.networks.iter().enumerate() {
+        let mut previous = old_nics
+            .iter()
+            .filter(|old| old.name == nic.name && old.bridge == nic.bridge);
+
+        if nic.mac_address.is_none() {
+            let mac = previous
+                .clone()
+                .find_map(|old| old.mac_address.clone())
+                .or_else(|| generate_mac_address(vm_name, nic, index));
+            if let Some(mac) = mac {
+                defaults.push((format!("/spec/networks/{index}/mac_address"), mac));
+            }
+        }
+        if nic.ovn_id.is_none() && ovn_nics[index] {
+            let ovn_id = previous
+                .find_map(|old| old.ovn_id.clone())
+                .unwrap_or_else(new_uuid);
+            defaults.push((format!("/spec/networks/{index}/ovn_id"), ovn_id));
+        }
+    }
+    defaults
+}
+
+#[cfg(test)]
+#[test]
+fn test_vm_defaults() {
+    use crate::crd::virtualmachine::VirtualMachineStatus;
+
+    let nic = |name: &str| NetworkAttachment {
+        name: Some(String::from(name)),
+        ..Default::default()
+    };
+    let mut vm = VirtualMachine::new("vm", Default::default());
+    vm.spec.networks = vec![nic("lan"), nic("storage")];
+
+    let defaults = vm_defaults(&vm, None, "test-vm", &[true, false]);
+    let pointers: Vec<&str> = defaults
+        .iter()
+        .map(|(pointer, _)| pointer.as_str())
+        .collect();
+    assert_eq!(
+        pointers,
+        vec![
+            "/spec/uuid",
+            "/spec/networks/0/mac_address",
+            "/spec/networks/0/ovn_id",
+            "/spec/networks/1/mac_address"
+        ]
+    );
+    assert_eq!(defaults[1].1.len(), 17);
+    assert_eq!(
+        Some(defaults[1].1.clone()),
+        generate_mac_address("test-vm", &vm.spec.networks[0], 0)
+    );
+
+    // An interface with neither a network nor a bridge is left for the validation to reject
+    let mut unnamed = vm.clone();
+    unnamed.spec.uuid = Some(String::from("8a9e8bbc-6b1f-4f4e-9d0c-1f6e3c5a7d21"));
+    unnamed.spec.networks = vec![NetworkAttachment::default()];
+    assert!(vm_defaults(&unnamed, None, "test-vm", &[false]).is_empty());
+
+    // An updated VM keeps its values, also the ones only stored in the status
+    let mut old = vm.clone();
+    old.spec.uuid = Some(String::from("8a9e8bbc-6b1f-4f4e-9d0c-1f6e3c5a7d21"));
+    old.status = Some(VirtualMachineStatus {
+        scheduled: true,
+        running: true,
+        migration_pending: false,
+        node: None,
+        domain_name: String::new(),
+        ip_addresses: None,
+        ip_addresses_string: None,
+        networks: vec![NetworkAttachment {
+            mac_address: Some(String::from("52:54:00:0a:0b:0c")),
+            ovn_id: Some(String::from("port")),
+            ..nic("lan")
+        }],
+        conditions: vec![],
+        ovn_ip_addresses: vec![],
+        guest: None,
+        applied_credentials: None,
+        template: None,
+    });
+    vm.spec.networks[1].mac_address = Some(String::from("52:54:00:01:02:03"));
+    assert_eq!(
+        vm_defaults(&vm, Some(&old), "test-vm", &[true, false]),
+        vec![
+            (
+                String::from("/spec/uuid"),
+                String::from("8a9e8bbc-6b1f-4f4e-9d0c-1f6e3c5a7d21")
+            ),
+            (
+                String::from("/spec/networks/0/mac_address"),
+                String::from("52:54:00:0a:0b:0c")
+            ),
+            (
+                String::from("/spec/networks/0/ovn_id"),
+                String::from("port")
+            ),
+        ]
+    );
+}
+
+/// Whether each interface of the VM is in an OVN network and needs a port ID. Networks which
+/// do not exist yet default to OVN.
+async fn ovn_interfaces(
+    vm: &VirtualMachine,
+    namespace: &str,
+    client: Client,
+) -> Result<Vec<bool>, Error> {
+    let networks: Api<Network> = Api::namespaced(client, namespace);
+    let mut ovn_nics = vec![];
+    for nic in &vm.spec.networks {
+        let is_ovn = match &nic.name {
+            Some(name) => {
+                networks
+                    .get_opt(name)
+                    .await?
+                    .and_then(|network| network.spec.network_type)
+                    .unwrap_or_default()
+                    == NetworkType::Ovn
+            }
+            None => false,
+        };
+        ovn_nics.push(is_ovn);
+    }
+    Ok(ovn_nics)
+}
+
+/// Fill in the UUID of a VM and the MAC addresses and OVN port IDs of its interfaces, returning
+/// them as a JSON patch
+pub async fn default_vm(
+    namespace: &str,
+    name: &str,
+    object: &DynamicObject,
+    old_object: Option<&DynamicObject>,
+    client: Client,
+) -> Result<json_patch::Patch, Error> {
+    let vm: VirtualMachine = serde_json::from_value(serde_json::to_value(object)?)?;
+    let old: Option<VirtualMachine> = match old_object {
+        Some(old) => Some(serde_json::from_value(serde_json::to_value(old)?)?),
+        None => None,
+    };
+
+    let ovn_nics = ovn_interfaces(&vm, namespace, client).await?;
+    let vm_name = match name {
+        "" => String::new(),
+        name => format!("{namespace}-{name}"),
+    };
+    let operations: Vec<Value> = vm_defaults(&vm, old.as_ref(), &vm_name, &ovn_nics)
+        .into_iter()
+        .map(|(path, value)| json!({ "op": "add", "path": path, "value": value }))
+        .collect();
+    Ok(serde_json::from_value(Value::Array(operations))?)
+}
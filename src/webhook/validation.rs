@@ -0,0 +1,389 @@
+use humanize_rs::bytes::Bytes;
+use ipnet::IpNet;
+use kube::ResourceExt;
+use kube::core::{DynamicObject, GroupVersionKind};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::net::IpAddr;
+use uuid::Uuid;
+
+use crate::crd::ceph::{Image, Volume};
+use crate::crd::network::{Network, NetworkType};
+use crate::crd::router::Router;
+use crate::crd::versioning::{VersionedCrd, convert};
+use crate::crd::virtualmachine::v1beta3::VirtualMachineSpec;
+use crate::crd::virtualmachine::{NetworkAttachment, VirtualMachine};
+use crate::errors::Error;
+use crate::utils::libvirt_storage::{StorageType, parse_storage_location};
+use crate::utils::strings::parse_memory_bytes;
+
+const VLAN_RANGE: std::ops::RangeInclusive<u16> = 1..=4094;
+
+/// Whether the address is a unicast MAC address like 52:54:00:12:34:56. Octets without a leading
+/// zero are accepted, older versions generated addresses like 52:54:00:5:a:ff.
+fn is_unicast_mac(address: &str) -> bool {
+    let octets: Vec<&str> = address.split(':').collect();
+    let well_formed = octets.len() == 6
+        && octets.iter().all(|octet| {
+            (1..=2).contains(&octet.len()) && octet.chars().all(|c| c.is_ascii_hexdigit())
+        });
+    well_formed && u8::from_str_radix(octets[0], 16).is_ok_and(|first| first & 1 == 0)
+}
+
+#[cfg(test)]
+#[test]
+fn test_is_unicast_mac() {
+    assert!(is_unicast_mac("52:54:00:ab:CD:01"));
+    assert!(is_unicast_mac("52:54:00:5:a:ff"));
+    assert!(!is_unicast_mac("01:00:5e:00:00:01"));
+    assert!(!is_unicast_mac("52:54:00:ab:cd"));
+    assert!(!is_unicast_mac("52-54-00-ab-cd-01"));
+    assert!(!is_unicast_mac("52:54:00:ab:cd:0g"));
+    assert!(!is_unicast_mac("52:54:00:ab:cd:+1"));
+    assert!(!is_unicast_mac("52:54:00:ab:cd:"));
+    assert!(!is_unicast_mac("52:54:00:ab:cd:012"));
+}
+
+fn check_vlan(problems: &mut Vec<String>, field: &str, vlan: u16) {
+    if !VLAN_RANGE.contains(&vlan) {
+        problems.push(format!("{field}: VLAN {vlan} is outside of 1-4094"));
+    }
+}
+
+fn validate_nic(problems: &mut Vec<String>, field: &str, nic: &NetworkAttachment) {
+    match (&nic.name, &nic.bridge) {
+        (Some(_), Some(_)) => {
+            problems.push(format!("{field}: set either name or bridge, not both"))
+        }
+        (None, None) => problems.push(format!("{field}: either name or bridge is required")),
+        _ => {}
+    }
+    if let Some(mac) = &nic.mac_address {
+        if !is_unicast_mac(mac) {
+            problems.push(format!(
+                "{field}.mac_address: {mac} is not a unicast MAC address like 52:54:00:12:34:56"
+            ));
+        }
+    }
+    if nic.queues == Some(0) {
+        problems.push(format!("{field}.queues: must be at least 1"));
+    }
+    if let Some(vlan) = nic.untagged_vlan {
+        check_vlan(problems, &format!("{field}.untagged_vlan"), vlan);
+    }
+    for vlan in nic.tagged_vlans.iter().flatten() {
+        check_vlan(problems, &format!("{field}.tagged_vlans"), *vlan);
+    }
+}
+
+fn validate_vm(spec: &VirtualMachineSpec) -> Vec<String> {
+    let mut problems = vec![];
+    let templated = spec.template.is_some() || spec.instance_type.is_some();
+
+    if spec.cpus == 0 && !templated {
+        problems.push(String::from(
+            "spec.cpus: must be at least 1 unless given by a template or instance type",
+        ));
+    }
+    if spec.memory.is_empty() {
+        if !templated {
+            problems.push(String::from(
+                "spec.memory: required unless given by a template or instance type",
+            ));
+        }
+    } else if let Err(error) = parse_memory_bytes(&spec.memory) {
+        problems.push(format!("spec.memory: {error}"));
+    }
+    if let Some(uuid) = &spec.uuid {
+        if Uuid::parse_str(uuid).is_err() {
+            problems.push(format!("spec.uuid: {uuid} is not a UUID"));
+        }
+    }
+
+    for (index, volume) in spec.volumes.iter().enumerate() {
+        match parse_storage_location(&volume.name) {
+            Ok((StorageType::Filesystem, _)) if volume.delete_with_vm == Some(true) => problems
+                .push(format!(
+                    "spec.volumes[{index}].delete_with_vm: only Volumes can be deleted with the VM"
+                )),
+            Ok(_) => {}
+            Err(error) => problems.push(format!("spec.volumes[{index}].name: {error}")),
+        }
+    }
+    for (index, nic) in spec.networks.iter().enumerate() {
+        validate_nic(&mut problems, &format!("spec.networks[{index}]"), nic);
+    }
+    problems
+}
+
+#[cfg(test)]
+#[test]
+fn test_validate_vm() {
+    use crate::crd::virtualmachine::VolumeAttachment;
+
+    let valid = VirtualMachineSpec {
+        cpus: 2,
+        memory: String::from("4 GiB"),
+        volumes: vec![VolumeAttachment {
+            name: String::from("ceph:root"),
+            delete_with_vm: Some(true),
+        }],
+        networks: vec![NetworkAttachment {
+            name: Some(String::from("lan")),
+            mac_address: Some(String::from("52:54:00:12:34:56")),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    assert!(validate_vm(&valid).is_empty());
+
+    let templated = VirtualMachineSpec {
+        template: Some(String::from("small")),
+        ..Default::default()
+    };
+    assert!(validate_vm(&templated).is_empty());
+
+    let invalid = VirtualMachineSpec {
+        memory: String::from("lots"),
+        uuid: Some(String::from("not-a-uuid")),
+        volumes: vec![
+            VolumeAttachment {
+                name: String::from("nfs:/export"),
+                delete_with_vm: None,
+            },
+            VolumeAttachment {
+                name: String::from("fs:/var/lib/images/a.qcow2"),
+                delete_with_vm: Some(true),
+            },
+        ],
+        networks: vec![
+            NetworkAttachment::default(),
+            NetworkAttachment {
+                bridge: Some(String::from("br0")),
+                mac_address: Some(String::from("01:00:5e:00:00:01")),
+                untagged_vlan: Some(4095),
+                tagged_vlans: Some(vec![10, 0]),
+                ..Default::default()
+            },
+        ],
+        ..Default::default()
+    };
+    let problems = validate_vm(&invalid);
+    assert_eq!(problems.len(), 9, "{problems:?}");
+    assert!(problems[0].starts_with("spec.cpus"));
+    assert!(problems[1].starts_with("spec.memory"));
+    assert!(problems.contains(&String::from(
+        "spec.networks[1].untagged_vlan: VLAN 4095 is outside of 1-4094"
+    )));
+}
+
+fn check_address(problems: &mut Vec<String>, field: &str, address: &str) {
+    if address.parse::<IpAddr>().is_err() {
+        problems.push(format!("{field}: {address} is not an IP address"));
+    }
+}
+
+fn check_cidr(problems: &mut Vec<String>, field: &str, cidr: &str) {
+    if cidr.parse::<IpNet>().is_err() {
+        problems.push(format!(
+            "{field}: {cidr} is not in CIDR notation like 10.0.0.0/24"
+        ));
+    }
+}
+
+fn validate_network(network: &Network) -> Vec<String> {
+    let mut problems = vec![];
+    let spec = &network.spec;
+
+    if let Some(dhcp) = &spec.dhcp {
+        check_cidr(&mut problems, "spec.dhcp.cidr", &dhcp.cidr);
+        // OVN takes several servers as {a, b}
+        let dns_servers = dhcp.dns_server.iter().flat_map(|servers| {
+            servers
+                .trim_matches(|c| c == '{' || c == '}')
+                .split(',')
+                .map(str::trim)
+        });
+        for server in dns_servers {
+            check_address(&mut problems, "spec.dhcp.dns_server", server);
+        }
+        if let Some(router) = &dhcp.router {
+            match (router.parse::<IpAddr>(), dhcp.cidr.parse::<IpNet>()) {
+                (Ok(address), Ok(cidr)) if !cidr.contains(&address) => problems.push(format!(
+                    "spec.dhcp.router: {router} is not in {}",
+                    dhcp.cidr
+                )),
+                (Err(_), _) => check_address(&mut problems, "spec.dhcp.router", router),
+                _ => {}
+            }
+        }
+        if dhcp.lease_time == Some(0) {
+            problems.push(String::from("spec.dhcp.lease_time: must be at least 1"));
+        }
+    }
+
+    for (index, attachment) in spec.routers.iter().flatten().enumerate() {
+        let field = format!("spec.routers[{index}]");
+        if let Err(error) = attachment.router(&network.namespace().unwrap_or_default()) {
+            problems.push(format!("{field}.name: {error}"));
+        }
+        check_cidr(
+            &mut problems,
+            &format!("{field}.address"),
+            &attachment.address,
+        );
+    }
+
+    if spec.network_type == Some(NetworkType::Evpn) {
+        if spec.bridge.is_none() {
+            problems.push(String::from("spec.bridge: required for EVPN networks"));
+        }
+        // The ID is used as the VLAN of the network on the bridge
+        match spec.network_id.map(u16::try_from) {
+            Some(Ok(vlan)) => check_vlan(&mut problems, "spec.network_id", vlan),
+            Some(Err(_)) => {
+                problems.push(String::from("spec.network_id: VLAN is outside of 1-4094"))
+            }
+            None => problems.push(String::from("spec.network_id: required for EVPN networks")),
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+#[test]
+fn test_validate_network() {
+    use crate::crd::network::v1beta1::NetworkSpec;
+    use crate::crd::network::{DhcpOptions, RouterAttachment};
+
+    let mut network = Network::new(
+        "lan",
+        NetworkSpec {
+            dhcp: Some(DhcpOptions {
+                cidr: String::from("10.0.0.0/24"),
+                dns_server: Some(String::from("{10.0.0.1, 1.1.1.1}")),
+                router: Some(String::from("10.0.0.1")),
+                ..Default::default()
+            }),
+            routers: Some(vec![RouterAttachment {
+                name: String::from("infra/edge"),
+                address: String::from("10.0.0.1/24"),
+            }]),
+            ..Default::default()
+        },
+    );
+    network.metadata.namespace = Some(String::from("test"));
+    assert!(validate_network(&network).is_empty());
+
+    network.spec.dhcp.as_mut().unwrap().router = Some(String::from("10.0.1.1"));
+    network.spec.routers.as_mut().unwrap()[0] = RouterAttachment {
+        name: String::from("a/b/c"),
+        address: String::from("10.0.0.1"),
+    };
+    network.spec.network_type = Some(NetworkType::Evpn);
+    network.spec.network_id = Some(5000);
+    assert_eq!(validate_network(&network).len(), 5);
+}
+
+fn validate_router(router: &Router) -> Vec<String> {
+    let mut problems = vec![];
+    for (index, route) in router.spec.routes.iter().flatten().enumerate() {
+        check_cidr(
+            &mut problems,
+            &format!("spec.routes[{index}].cidr"),
+            &route.cidr,
+        );
+        check_address(
+            &mut problems,
+            &format!("spec.routes[{index}].nexthop"),
+            &route.nexthop,
+        );
+    }
+    problems
+}
+
+fn validate_volume(volume: &Volume) -> Vec<String> {
+    let mut problems = vec![];
+    match volume.spec.size.parse::<Bytes<u64>>() {
+        Ok(size) if size.size() == 0 => {
+            problems.push(String::from("spec.size: must be larger than zero"))
+        }
+        Ok(_) => {}
+        Err(error) => problems.push(format!("spec.size: {}: {error}", volume.spec.size)),
+    }
+    if volume.spec.template.as_deref() == Some("") {
+        problems.push(String::from("spec.template: must not be empty when set"));
+    }
+    problems
+}
+
+fn validate_image(image: &Image) -> Vec<String> {
+    if image.spec.source.is_empty() {
+        vec![String::from("spec.source: required")]
+    } else {
+        vec![]
+    }
+}
+
+/// Whether an object has to pass validation. Updates which leave the spec alone, like the
+/// controllers adding finalizers, and objects being deleted are let through, so that objects
+/// created before a rule was added can still be managed and deleted.
+pub fn needs_validation(object: &DynamicObject, old_object: Option<&DynamicObject>) -> bool {
+    if object.metadata.deletion_timestamp.is_some() {
+        return false;
+    }
+    old_object.is_none_or(|old| old.data.get("spec") != object.data.get("spec"))
+}
+
+#[cfg(test)]
+#[test]
+fn test_needs_validation() {
+    let object = |value: Value| -> DynamicObject { serde_json::from_value(value).unwrap() };
+    let old = object(serde_json::json!({
+        "metadata": { "name": "vm" },
+        "spec": { "cpus": 0 }
+    }));
+    let finalized = object(serde_json::json!({
+        "metadata": { "name": "vm", "finalizers": ["ovn"] },
+        "spec": { "cpus": 0 }
+    }));
+    let deleting = object(serde_json::json!({
+        "metadata": { "name": "vm", "deletionTimestamp": "2024-01-01T00:00:00Z" },
+        "spec": { "cpus": 1 }
+    }));
+    let changed = object(serde_json::json!({
+        "metadata": { "name": "vm" },
+        "spec": { "cpus": 1 }
+    }));
+
+    assert!(needs_validation(&old, None));
+    assert!(!needs_validation(&finalized, Some(&old)));
+    assert!(!needs_validation(&deleting, Some(&old)));
+    assert!(needs_validation(&changed, Some(&old)));
+}
+
+/// Deserialize an object of the given version as the latest version of its CRD
+fn as_latest<T: VersionedCrd + DeserializeOwned>(
+    mut value: Value,
+    version: &str,
+) -> Result<T, Error> {
+    value["apiVersion"] = Value::String(format!("{}/{version}", T::group(&())));
+    let latest = convert::<T>(value, &T::api_version(&()))?;
+    Ok(serde_json::from_value(latest)?)
+}
+
+/// Validate an object of one of the CRDs, returning the problems found. Only checks the object
+/// itself, the resources it references may be created after it.
+pub fn validate(kind: &GroupVersionKind, object: &DynamicObject) -> Result<Vec<String>, Error> {
+    let value = serde_json::to_value(object)?;
+    let version = kind.version.as_str();
+    let problems = match kind.kind.as_str() {
+        "VirtualMachine" => validate_vm(&as_latest::<VirtualMachine>(value, version)?.spec),
+        "Network" => validate_network(&as_latest(value, version)?),
+        "Router" => validate_router(&as_latest(value, version)?),
+        "Volume" => validate_volume(&as_latest(value, version)?),
+        "Image" => validate_image(&as_latest(value, version)?),
+        _ => vec![],
+    };
+    Ok(problems)
+}